@@ -0,0 +1,173 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use chrono::NaiveDateTime;
+use sqlx::PgPool;
+
+// What is needed of a photo to group it and rank it against the rest of its group
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct Shot {
+    pub photo_id: i32,
+    pub file_path: String,
+    pub camera_make: Option<String>,
+    pub camera_model: Option<String>,
+    pub captured_at: Option<NaiveDateTime>,
+    pub quality: Option<f32>,
+    pub width: Option<i32>,
+    pub height: Option<i32>,
+}
+
+// Near-identical photos, the best one first
+#[derive(Debug)]
+pub struct ShotGroup {
+    pub best: Shot,
+    pub others: Vec<Shot>,
+}
+
+// Burst gap when none is given: frames of a burst or bracket are well under a second apart, a
+// second attempt at the same shot a few seconds later
+pub const DEFAULT_BURST_GAP: Duration = Duration::from_secs(2);
+
+// Groups of photos that are variants of one shot, with the best of each picked by quality. A
+// group is a capture burst (same camera, each frame at most `gap` after the previous one) or a
+// set of linked photos (edits, crops, RAW+JPEG pairs), merged where they overlap. Kept copies of
+// the same content are left out; they are the same image.
+pub async fn best_shots(pool: &PgPool, gap: Duration) -> Result<Vec<ShotGroup>, sqlx::Error> {
+    let query = "SELECT photo_id, file_path, camera_make, camera_model, captured_at, quality, width, height
+        FROM photos WHERE duplicate_of IS NULL ORDER BY photo_id";
+    let shots: Vec<Shot> = sqlx::query_as(query).fetch_all(pool).await?;
+    let links: Vec<(i32, i32)> = sqlx::query_as("SELECT from_photo_id, to_photo_id FROM photo_links")
+        .fetch_all(pool)
+        .await?;
+    Ok(group_shots(shots, &links, gap))
+}
+
+// The grouping behind `best_shots`, groups of a single photo are dropped. Groups come in the order
+// of their best photo's id.
+pub fn group_shots(shots: Vec<Shot>, links: &[(i32, i32)], gap: Duration) -> Vec<ShotGroup> {
+    let index: HashMap<i32, usize> = shots.iter().enumerate().map(|(i, shot)| (shot.photo_id, i)).collect();
+    let mut groups = DisjointSets::new(shots.len());
+
+    for &(from, to) in links {
+        if let (Some(&from), Some(&to)) = (index.get(&from), index.get(&to)) {
+            groups.union(from, to);
+        }
+    }
+
+    // bursts: consecutive frames of the same camera, no camera means no burst
+    let mut timed: Vec<usize> = (0..shots.len())
+        .filter(|&i| shots[i].captured_at.is_some() && (shots[i].camera_make.is_some() || shots[i].camera_model.is_some()))
+        .collect();
+    timed.sort_by(|&a, &b| camera_and_time(&shots[a]).cmp(&camera_and_time(&shots[b])));
+    let gap = chrono::Duration::from_std(gap).unwrap_or(chrono::Duration::max_value());
+    for pair in timed.windows(2) {
+        let (previous, next) = (&shots[pair[0]], &shots[pair[1]]);
+        let same_camera = (&previous.camera_make, &previous.camera_model) == (&next.camera_make, &next.camera_model);
+        if same_camera && next.captured_at.unwrap() - previous.captured_at.unwrap() <= gap {
+            groups.union(pair[0], pair[1]);
+        }
+    }
+
+    let mut members: HashMap<usize, Vec<Shot>> = HashMap::new();
+    for (i, shot) in shots.into_iter().enumerate() {
+        members.entry(groups.find(i)).or_default().push(shot);
+    }
+    let mut shot_groups: Vec<ShotGroup> = members
+        .into_values()
+        .filter(|group| group.len() > 1)
+        .map(|mut group| {
+            group.sort_by(|a, b| rank(b).partial_cmp(&rank(a)).unwrap().then(a.photo_id.cmp(&b.photo_id)));
+            let best = group.remove(0);
+            ShotGroup { best, others: group }
+        })
+        .collect();
+    shot_groups.sort_by_key(|group| group.best.photo_id);
+    shot_groups
+}
+
+fn camera_and_time(shot: &Shot) -> (&Option<String>, &Option<String>, Option<NaiveDateTime>) {
+    (&shot.camera_make, &shot.camera_model, shot.captured_at)
+}
+
+// Higher is better: quality first, unscored photos last, then the larger image
+fn rank(shot: &Shot) -> (f32, i64) {
+    let pixels = i64::from(shot.width.unwrap_or(0)) * i64::from(shot.height.unwrap_or(0));
+    (shot.quality.unwrap_or(-1.0), pixels)
+}
+
+// Union-find over indexes into the shot list
+struct DisjointSets {
+    parents: Vec<usize>,
+}
+
+impl DisjointSets {
+    fn new(len: usize) -> Self {
+        DisjointSets { parents: (0..len).collect() }
+    }
+
+    fn find(&mut self, i: usize) -> usize {
+        let mut root = i;
+        while self.parents[root] != root {
+            root = self.parents[root];
+        }
+        // point everything on the way straight at the root
+        let mut i = i;
+        while self.parents[i] != root {
+            let next = self.parents[i];
+            self.parents[i] = root;
+            i = next;
+        }
+        root
+    }
+
+    fn union(&mut self, a: usize, b: usize) {
+        let (a, b) = (self.find(a), self.find(b));
+        self.parents[a.max(b)] = a.min(b);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn shot(photo_id: i32, camera: Option<&str>, second: Option<u32>, quality: Option<f32>) -> Shot {
+        Shot {
+            photo_id,
+            file_path: format!("{}.jpg", photo_id),
+            camera_make: camera.map(str::to_string),
+            camera_model: None,
+            captured_at: second.map(|second| {
+                chrono::NaiveDate::from_ymd_opt(2024, 6, 1).unwrap().and_hms_opt(12, 0, second).unwrap()
+            }),
+            quality,
+            width: Some(100),
+            height: Some(100),
+        }
+    }
+
+    fn ids(group: &ShotGroup) -> (i32, Vec<i32>) {
+        (group.best.photo_id, group.others.iter().map(|shot| shot.photo_id).collect())
+    }
+
+    #[test]
+    fn groups_bursts_and_links_and_picks_the_best() {
+        let shots = vec![
+            // a burst, 1s apart, chained past the gap end to end
+            shot(1, Some("Canon"), Some(0), Some(0.4)),
+            shot(2, Some("Canon"), Some(1), Some(0.9)),
+            shot(3, Some("Canon"), Some(3), Some(0.6)),
+            // same time, other camera
+            shot(4, Some("Nikon"), Some(1), Some(0.5)),
+            // too late for the burst
+            shot(5, Some("Canon"), Some(10), Some(0.8)),
+            // no capture time, linked to 5 as its crop; unscored ranks last
+            shot(6, None, None, None),
+            shot(7, None, None, Some(0.1)),
+        ];
+
+        let groups = group_shots(shots, &[(6, 5), (8, 7)], Duration::from_secs(2));
+
+        let groups: Vec<_> = groups.iter().map(ids).collect();
+        assert_eq!(groups, vec![(2, vec![3, 1]), (5, vec![6])]);
+    }
+}
@@ -0,0 +1,238 @@
+use std::error::Error;
+use std::future::Future;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+
+use crate::limiter::QueueTimeout;
+use crate::tagger::Tagger;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum State {
+    Closed { failures: u32 },
+    // calls fail fast until `until`, then one probe is let through
+    Open { until: Instant },
+    HalfOpen,
+}
+
+// Wraps a backend so that once it has failed `threshold` times in a row, calls fail immediately
+// instead of each waiting for its own timeout. After `cooldown` a single probe call is let through:
+// success closes the circuit again, failure keeps it open for another cooldown.
+pub struct CircuitBreaker {
+    inner: Box<dyn Tagger>,
+    threshold: u32,
+    cooldown: Duration,
+    state: Mutex<State>,
+}
+
+impl CircuitBreaker {
+    pub fn new(inner: Box<dyn Tagger>, threshold: u32, cooldown: Duration) -> Self {
+        CircuitBreaker {
+            inner,
+            threshold: threshold.max(1),
+            cooldown,
+            state: Mutex::new(State::Closed { failures: 0 }),
+        }
+    }
+
+    fn before_call(&self) -> Result<(), String> {
+        let mut state = self.state.lock().unwrap();
+        match *state {
+            State::Closed { .. } => Ok(()),
+            State::Open { until } if Instant::now() >= until => {
+                *state = State::HalfOpen;
+                Ok(())
+            }
+            State::Open { until } => Err(format!(
+                "AI backend is unavailable, not retrying for another {}s",
+                until.saturating_duration_since(Instant::now()).as_secs() + 1
+            )),
+            State::HalfOpen => Err("AI backend is unavailable, waiting for a probe request".to_string()),
+        }
+    }
+
+    fn after_call(&self, result: Result<(), &(dyn Error + 'static)>) {
+        let mut state = self.state.lock().unwrap();
+        // a call stuck behind a concurrency limit never reached the backend
+        if result.is_err_and(|e| e.is::<QueueTimeout>()) {
+            if *state == State::HalfOpen {
+                // let the next call probe instead
+                *state = State::Open { until: Instant::now() };
+            }
+            return;
+        }
+        let succeeded = result.is_ok();
+        *state = match (*state, succeeded) {
+            (_, true) => State::Closed { failures: 0 },
+            (State::Closed { failures }, false) if failures + 1 < self.threshold => {
+                State::Closed { failures: failures + 1 }
+            }
+            (State::Open { until }, false) => State::Open { until },
+            (_, false) => {
+                println!(
+                    "AI backend failed {} times in a row, pausing calls for {}s",
+                    self.threshold,
+                    self.cooldown.as_secs()
+                );
+                State::Open { until: Instant::now() + self.cooldown }
+            }
+        };
+    }
+
+    async fn guard<T>(&self, call: impl Future<Output = Result<T, Box<dyn Error>>>) -> Result<T, Box<dyn Error>> {
+        self.before_call()?;
+        let result = call.await;
+        self.after_call(result.as_ref().map(|_| ()).map_err(|e| e.as_ref()));
+        result
+    }
+}
+
+#[async_trait]
+impl Tagger for CircuitBreaker {
+    fn vision_model(&self) -> &str {
+        self.inner.vision_model()
+    }
+
+    fn text_model(&self) -> &str {
+        self.inner.text_model()
+    }
+
+    async fn tag_image(&self, base64_image: &str) -> Result<Vec<String>, Box<dyn Error>> {
+        self.guard(self.inner.tag_image(base64_image)).await
+    }
+
+    async fn tag_image_with_response(&self, base64_image: &str) -> Result<(Vec<String>, String), Box<dyn Error>> {
+        self.guard(self.inner.tag_image_with_response(base64_image)).await
+    }
+
+    async fn tags_for_query(&self, query: &str) -> Result<Vec<String>, Box<dyn Error>> {
+        self.guard(self.inner.tags_for_query(query)).await
+    }
+
+    async fn related_tags(&self, tag: &str) -> Result<Vec<String>, Box<dyn Error>> {
+        self.guard(self.inner.related_tags(tag)).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    // Counts calls and fails while `down` is set
+    struct FakeTagger {
+        calls: Arc<AtomicUsize>,
+        down: Arc<AtomicBool>,
+    }
+
+    #[async_trait]
+    impl Tagger for FakeTagger {
+        fn vision_model(&self) -> &str {
+            "fake"
+        }
+
+        fn text_model(&self) -> &str {
+            "fake"
+        }
+
+        async fn tag_image(&self, _base64_image: &str) -> Result<Vec<String>, Box<dyn Error>> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            if self.down.load(Ordering::SeqCst) {
+                return Err("connection refused".into());
+            }
+            Ok(vec!["beach".to_string()])
+        }
+
+        async fn tags_for_query(&self, query: &str) -> Result<Vec<String>, Box<dyn Error>> {
+            self.tag_image(query).await
+        }
+
+        async fn related_tags(&self, tag: &str) -> Result<Vec<String>, Box<dyn Error>> {
+            self.tag_image(tag).await
+        }
+    }
+
+    #[tokio::test]
+    async fn opens_after_consecutive_failures_and_recovers_after_cooldown() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let down = Arc::new(AtomicBool::new(true));
+        let inner = FakeTagger { calls: calls.clone(), down: down.clone() };
+        let breaker = CircuitBreaker::new(Box::new(inner), 2, Duration::from_millis(50));
+
+        assert!(breaker.tag_image("").await.is_err());
+        assert!(breaker.tag_image("").await.is_err());
+        // open: fails without reaching the backend
+        let error = breaker.tag_image("").await.unwrap_err();
+        assert!(error.to_string().contains("unavailable"), "{}", error);
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+        // the probe after the cooldown fails, so the circuit stays open
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        assert!(breaker.tag_image("").await.is_err());
+        assert!(breaker.tag_image("").await.is_err());
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+
+        // a successful probe closes it again
+        down.store(false, Ordering::SeqCst);
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        assert_eq!(breaker.tag_image("").await.unwrap(), vec!["beach"]);
+        assert!(breaker.tags_for_query("").await.is_ok());
+        assert_eq!(calls.load(Ordering::SeqCst), 5);
+    }
+
+    // Always saturated: every call times out waiting for a slot
+    struct SaturatedTagger;
+
+    #[async_trait]
+    impl Tagger for SaturatedTagger {
+        fn vision_model(&self) -> &str {
+            "saturated"
+        }
+
+        fn text_model(&self) -> &str {
+            "saturated"
+        }
+
+        async fn tag_image(&self, _base64_image: &str) -> Result<Vec<String>, Box<dyn Error>> {
+            Err(Box::new(QueueTimeout { waited: Duration::from_secs(1) }))
+        }
+
+        async fn tags_for_query(&self, query: &str) -> Result<Vec<String>, Box<dyn Error>> {
+            self.tag_image(query).await
+        }
+
+        async fn related_tags(&self, tag: &str) -> Result<Vec<String>, Box<dyn Error>> {
+            self.tag_image(tag).await
+        }
+    }
+
+    #[tokio::test]
+    async fn queue_timeouts_leave_the_circuit_closed() {
+        let breaker = CircuitBreaker::new(Box::new(SaturatedTagger), 1, Duration::from_secs(60));
+
+        for _ in 0..3 {
+            let error = breaker.tag_image("").await.unwrap_err();
+            assert!(error.to_string().contains("waiting"), "{}", error);
+        }
+        assert_eq!(*breaker.state.lock().unwrap(), State::Closed { failures: 0 });
+    }
+
+    #[tokio::test]
+    async fn success_resets_the_failure_count() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let down = Arc::new(AtomicBool::new(true));
+        let inner = FakeTagger { calls: calls.clone(), down: down.clone() };
+        let breaker = CircuitBreaker::new(Box::new(inner), 2, Duration::from_secs(60));
+
+        assert!(breaker.tag_image("").await.is_err());
+        down.store(false, Ordering::SeqCst);
+        assert!(breaker.tag_image("").await.is_ok());
+        down.store(true, Ordering::SeqCst);
+        assert!(breaker.tag_image("").await.is_err());
+        // only one failure since the success, still closed
+        assert!(!breaker.tag_image("").await.unwrap_err().to_string().contains("unavailable"));
+        assert_eq!(calls.load(Ordering::SeqCst), 4);
+    }
+}
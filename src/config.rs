@@ -0,0 +1,479 @@
+use std::env;
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use chrono_tz::Tz;
+
+use crate::hooks::{CommandHook, PostIngestHook, WebhookHook};
+use crate::ingest::{DuplicatePolicy, IngestOptions, Normalization, NormalizedFormat, NormalizedLayout};
+use crate::tagger::Timeouts;
+use crate::thumbnails::Thumbnails;
+use crate::trash::Trash;
+use crate::watch::{Storage, StoreMode, WatchOptions};
+
+// Every setting of the binary, read from the environment (a .env file included) once at startup.
+// All variables are checked before any command runs and every problem is reported at once, so a
+// typo never surfaces halfway through a long upload. The variables are listed in .env.example.
+#[derive(Debug)]
+pub struct Config {
+    pub database_url: String,
+    pub ai: AiConfig,
+    pub ingest: IngestOptions,
+    pub watch: WatchOptions,
+}
+
+// The AI backend and the limits wrapped around it, see `tagger::from_config`
+#[derive(Debug)]
+pub struct AiConfig {
+    pub provider: Provider,
+    // AI_MAX_VISION_REQUESTS and AI_MAX_TEXT_REQUESTS, unset means unlimited
+    pub max_vision_requests: Option<usize>,
+    pub max_text_requests: Option<usize>,
+    // AI_QUEUE_TIMEOUT_SECS, unset waits for a slot indefinitely
+    pub queue_timeout: Option<Duration>,
+    // AI_BREAKER_FAILURES, consecutive failures before the circuit opens (default 5, 0 disables)
+    pub breaker_failures: u32,
+    // AI_BREAKER_COOLDOWN_SECS (default 30)
+    pub breaker_cooldown: Duration,
+}
+
+// AI_PROVIDER: `ollama` (default) or `openai` for any OpenAI-compatible server
+#[derive(Debug)]
+pub enum Provider {
+    Ollama(OllamaConfig),
+    OpenAi(OpenAiConfig),
+}
+
+// OLLAMA_URL, OLLAMA_VISION_MODEL, OLLAMA_TEXT_MODEL, OLLAMA_TIMEOUT_SECS, OLLAMA_TAG_TIMEOUT_SECS
+// and OLLAMA_TEXT_TIMEOUT_SECS
+#[derive(Debug)]
+pub struct OllamaConfig {
+    pub url: Option<String>,
+    pub vision_model: Option<String>,
+    pub text_model: Option<String>,
+    pub timeout: Option<Duration>,
+    pub timeouts: Timeouts,
+}
+
+// OPENAI_BASE_URL, OPENAI_API_KEY, OPENAI_VISION_MODEL, OPENAI_TEXT_MODEL, OPENAI_TIMEOUT_SECS,
+// OPENAI_TAG_TIMEOUT_SECS and OPENAI_TEXT_TIMEOUT_SECS
+pub struct OpenAiConfig {
+    pub base_url: Option<String>,
+    pub api_key: Option<String>,
+    pub vision_model: Option<String>,
+    pub text_model: Option<String>,
+    pub timeout: Option<Duration>,
+    pub timeouts: Timeouts,
+}
+
+// Errors end up on the terminal, the key must not
+impl fmt::Debug for OpenAiConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("OpenAiConfig")
+            .field("base_url", &self.base_url)
+            .field("api_key", &self.api_key.as_ref().map(|_| "<redacted>"))
+            .field("vision_model", &self.vision_model)
+            .field("text_model", &self.text_model)
+            .field("timeout", &self.timeout)
+            .field("timeouts", &self.timeouts)
+            .finish()
+    }
+}
+
+// Every variable that could not be used, one per line
+pub struct ConfigError {
+    pub problems: Vec<String>,
+}
+
+// An error returned from main is printed with Debug, show the list rather than the struct
+impl fmt::Debug for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid configuration:")?;
+        for problem in &self.problems {
+            write!(f, "\n  {}", problem)?;
+        }
+        Ok(())
+    }
+}
+
+impl Error for ConfigError {}
+
+impl Config {
+    pub fn from_env() -> Result<Config, ConfigError> {
+        Config::from_vars(|name| env::var(name).ok())
+    }
+
+    // Like `from_env` with the variables looked up through `var`, for tests
+    pub fn from_vars(var: impl Fn(&str) -> Option<String>) -> Result<Config, ConfigError> {
+        let mut vars = Vars { var: &var, problems: Vec::new() };
+
+        let database_url = vars.string("DATABASE_URL");
+        if database_url.is_none() {
+            vars.problems.push("DATABASE_URL is not set".to_string());
+        }
+        let ai = ai_config(&mut vars);
+        let ingest = ingest_options(&mut vars);
+        let watch = watch_options(&mut vars);
+
+        match (database_url, vars.problems.is_empty()) {
+            (Some(database_url), true) => Ok(Config { database_url, ai, ingest, watch }),
+            _ => Err(ConfigError { problems: vars.problems }),
+        }
+    }
+}
+
+// Reads variables and collects what is wrong with them instead of stopping at the first problem;
+// a variable that fails to parse reads as unset
+struct Vars<'a> {
+    var: &'a dyn Fn(&str) -> Option<String>,
+    problems: Vec<String>,
+}
+
+impl Vars<'_> {
+    // Set and not blank
+    fn string(&self, name: &str) -> Option<String> {
+        (self.var)(name).filter(|value| !value.trim().is_empty())
+    }
+
+    fn parse_with<T>(&mut self, name: &str, parse: impl FnOnce(&str) -> Result<T, String>) -> Option<T> {
+        let value = self.string(name)?;
+        match parse(value.trim()) {
+            Ok(parsed) => Some(parsed),
+            Err(expected) => {
+                self.problems.push(format!("{}: invalid value '{}', expected {}", name, value, expected));
+                None
+            }
+        }
+    }
+
+    fn parse<T: FromStr>(&mut self, name: &str, expected: &str) -> Option<T> {
+        self.parse_with(name, |value| value.parse().map_err(|_| expected.to_string()))
+    }
+
+    fn seconds(&mut self, name: &str) -> Option<Duration> {
+        self.parse(name, "a number of seconds").map(Duration::from_secs)
+    }
+
+    // The directory as the canonical path it will have, without creating it: commands that never
+    // write there (search, check, ...) must not leave directories behind. It is created where it
+    // is first written to.
+    fn dir(&mut self, name: &str, dir: &str) -> Option<PathBuf> {
+        match resolve_dir(Path::new(dir)) {
+            Ok(dir) => Some(dir),
+            Err(e) => {
+                self.problems.push(format!("{}: cannot resolve '{}': {}", name, dir, e));
+                None
+            }
+        }
+    }
+}
+
+// `dir` made absolute with the symlinks in its existing part resolved, which is what
+// `canonicalize` returns once the rest has been created
+fn resolve_dir(dir: &Path) -> io::Result<PathBuf> {
+    let mut resolved = PathBuf::new();
+    for component in std::path::absolute(dir)?.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                resolved.pop();
+            }
+            component => {
+                resolved.push(component);
+                if let Ok(canonical) = resolved.canonicalize() {
+                    resolved = canonical;
+                }
+            }
+        }
+    }
+    Ok(resolved)
+}
+
+// `<prefix>_TAG_TIMEOUT_SECS` and `<prefix>_TEXT_TIMEOUT_SECS`, e.g. OLLAMA_TAG_TIMEOUT_SECS
+fn timeouts(vars: &mut Vars, prefix: &str) -> Timeouts {
+    Timeouts {
+        tag: vars.seconds(&format!("{}_TAG_TIMEOUT_SECS", prefix)),
+        text: vars.seconds(&format!("{}_TEXT_TIMEOUT_SECS", prefix)),
+    }
+}
+
+fn ai_config(vars: &mut Vars) -> AiConfig {
+    let provider = vars.parse_with("AI_PROVIDER", |provider| match provider.to_lowercase().as_str() {
+        "ollama" => Ok(false),
+        "openai" => Ok(true),
+        _ => Err("ollama or openai".to_string()),
+    });
+    let provider = if provider == Some(true) {
+        Provider::OpenAi(OpenAiConfig {
+            base_url: vars.string("OPENAI_BASE_URL"),
+            api_key: vars.string("OPENAI_API_KEY"),
+            vision_model: vars.string("OPENAI_VISION_MODEL"),
+            text_model: vars.string("OPENAI_TEXT_MODEL"),
+            timeout: vars.seconds("OPENAI_TIMEOUT_SECS"),
+            timeouts: timeouts(vars, "OPENAI"),
+        })
+    } else {
+        Provider::Ollama(OllamaConfig {
+            url: vars.string("OLLAMA_URL"),
+            vision_model: vars.string("OLLAMA_VISION_MODEL"),
+            text_model: vars.string("OLLAMA_TEXT_MODEL"),
+            timeout: vars.seconds("OLLAMA_TIMEOUT_SECS"),
+            timeouts: timeouts(vars, "OLLAMA"),
+        })
+    };
+    AiConfig {
+        provider,
+        max_vision_requests: vars.parse("AI_MAX_VISION_REQUESTS", "a number of requests"),
+        max_text_requests: vars.parse("AI_MAX_TEXT_REQUESTS", "a number of requests"),
+        queue_timeout: vars.seconds("AI_QUEUE_TIMEOUT_SECS"),
+        breaker_failures: vars.parse("AI_BREAKER_FAILURES", "a number of failures").unwrap_or(5),
+        breaker_cooldown: vars.seconds("AI_BREAKER_COOLDOWN_SECS").unwrap_or(Duration::from_secs(30)),
+    }
+}
+
+fn ingest_options(vars: &mut Vars) -> IngestOptions {
+    let mut hooks: Vec<Box<dyn PostIngestHook>> = Vec::new();
+    if let Some(command) = vars.string("INGEST_HOOK_COMMAND") {
+        hooks.push(Box::new(CommandHook { command }));
+    }
+    // the webhooks themselves are in the database, registered with `webhook add`
+    let mut webhooks = WebhookHook::new();
+    if let Some(attempts) = vars.parse("WEBHOOK_ATTEMPTS", "a number of attempts") {
+        webhooks.attempts = attempts;
+    }
+    hooks.push(Box::new(webhooks));
+
+    IngestOptions {
+        normalization: normalization(vars),
+        thumbnails: thumbnails(vars),
+        concurrency: vars.parse("INGEST_CONCURRENCY", "a number of images").unwrap_or(1),
+        hooks,
+        home_timezone: vars.parse::<Tz>("HOME_TIMEZONE", "a time zone such as Europe/Berlin"),
+        duplicates: vars
+            .parse_with("INGEST_DUPLICATES", |policy| {
+                policy.parse::<DuplicatePolicy>().map_err(|_| "skip, reject, replace or keep-both".to_string())
+            })
+            .unwrap_or_default(),
+        min_free_bytes: min_free_bytes(vars),
+        trash: trash(vars),
+    }
+}
+
+fn trash(vars: &mut Vars) -> Option<Trash> {
+    // checked even without TRASH_DIR, like the normalization settings
+    let retention_days = vars.parse("TRASH_RETENTION_DAYS", "a number of days").unwrap_or(30);
+    let dir = vars.string("TRASH_DIR")?;
+    Some(Trash { dir: vars.dir("TRASH_DIR", &dir)?, retention_days })
+}
+
+fn min_free_bytes(vars: &mut Vars) -> Option<u64> {
+    let megabytes = vars.parse::<u64>("INGEST_MIN_FREE_MB", "a number of megabytes")?;
+    let bytes = megabytes.checked_mul(1024 * 1024);
+    if bytes.is_none() {
+        vars.problems.push(format!("INGEST_MIN_FREE_MB: {} MB is more than any disk holds", megabytes));
+    }
+    bytes
+}
+
+fn normalization(vars: &mut Vars) -> Option<Normalization> {
+    let format = vars.parse_with("NORMALIZE_FORMAT", |format| match format.to_lowercase().as_str() {
+        "jpeg" | "jpg" => Ok(None),
+        "png" => Ok(Some(NormalizedFormat::Png)),
+        _ => Err("jpeg or png".to_string()),
+    });
+    // checked even while normalization is off, a typo shows up before it is turned on
+    let quality = vars.parse_with("NORMALIZE_QUALITY", |quality| match quality.parse::<u8>() {
+        Ok(quality) if (1..=100).contains(&quality) => Ok(quality),
+        _ => Err("a JPEG quality from 1 to 100".to_string()),
+    });
+    let keep_originals = vars.parse("KEEP_ORIGINALS", "true or false").unwrap_or(true);
+    let layout = vars
+        .parse_with("NORMALIZED_LAYOUT", |layout| match layout {
+            "named" => Ok(NormalizedLayout::Named),
+            "hashed" => Ok(NormalizedLayout::Hashed),
+            _ => Err("named or hashed".to_string()),
+        })
+        .unwrap_or_default();
+    let format = format?.unwrap_or(NormalizedFormat::Jpeg { quality: quality.unwrap_or(85) });
+    let output_dir = vars.string("NORMALIZED_DIR").unwrap_or_else(|| "./normalized".to_string());
+    Some(Normalization {
+        format,
+        // stored paths are canonical, so paths built from the directory compare with them
+        output_dir: vars.dir("NORMALIZED_DIR", &output_dir)?,
+        keep_originals,
+        layout,
+    })
+}
+
+fn thumbnails(vars: &mut Vars) -> Option<Thumbnails> {
+    let sizes = vars.parse_with("THUMBNAIL_SIZES", parse_sizes)?;
+    let output_dir = vars.string("THUMBNAIL_DIR").unwrap_or_else(|| "./thumbs".to_string());
+    Some(Thumbnails { sizes, output_dir: vars.dir("THUMBNAIL_DIR", &output_dir)? })
+}
+
+// Comma-separated thumbnail sizes, without repeats
+fn parse_sizes(sizes: &str) -> Result<Vec<u32>, String> {
+    let expected = || "comma-separated sizes in pixels such as 256,1024".to_string();
+    let mut parsed = Vec::new();
+    for size in sizes.split(',').map(str::trim).filter(|size| !size.is_empty()) {
+        match size.parse::<u32>() {
+            Ok(size) if size > 0 => {
+                if !parsed.contains(&size) {
+                    parsed.push(size);
+                }
+            }
+            _ => return Err(expected()),
+        }
+    }
+    if parsed.is_empty() {
+        return Err(expected());
+    }
+    Ok(parsed)
+}
+
+fn watch_options(vars: &mut Vars) -> WatchOptions {
+    let dirs = vars
+        .string("WATCH_DIRS")
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .collect();
+    let settle = vars.seconds("WATCH_SETTLE_SECS").unwrap_or(Duration::from_secs(2));
+    let mode = vars.parse_with("WATCH_STORE", |mode| mode.parse::<StoreMode>().map_err(|_| "move or link".to_string()));
+    let storage = match vars.string("WATCH_STORAGE_DIR") {
+        Some(dir) => vars.dir("WATCH_STORAGE_DIR", &dir).map(|dir| Storage {
+            // canonical like the photo paths stored from it
+            dir,
+            mode: mode.unwrap_or(StoreMode::Move),
+        }),
+        None => {
+            if mode.is_some() {
+                vars.problems.push("WATCH_STORE is set but WATCH_STORAGE_DIR is not".to_string());
+            }
+            None
+        }
+    };
+    let duplicates = vars.parse_with("WATCH_DUPLICATES", |policy| {
+        policy.parse::<DuplicatePolicy>().map_err(|_| "skip, reject, replace or keep-both".to_string())
+    });
+    WatchOptions { dirs, settle, storage, duplicates }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn config(vars: &[(&str, &str)]) -> Result<Config, ConfigError> {
+        let vars: HashMap<String, String> =
+            vars.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect();
+        Config::from_vars(|name| vars.get(name).cloned())
+    }
+
+    #[test]
+    fn defaults_need_only_the_database() {
+        let config = config(&[("DATABASE_URL", "postgres://localhost/photos")]).unwrap();
+
+        assert!(matches!(
+            config.ai.provider,
+            Provider::Ollama(OllamaConfig { url: None, vision_model: None, text_model: None, .. })
+        ));
+        assert_eq!(config.ai.breaker_failures, 5);
+        assert_eq!(config.ingest.concurrency, 1);
+        assert!(config.ingest.normalization.is_none() && config.ingest.thumbnails.is_none());
+        // only the webhook hook, which posts to the registered webhooks
+        assert_eq!(config.ingest.hooks.len(), 1);
+        assert_eq!(config.watch.settle, Duration::from_secs(2));
+    }
+
+    #[test]
+    fn every_problem_is_reported_at_once() {
+        let error = config(&[
+            ("AI_PROVIDER", "claude"),
+            ("INGEST_CONCURRENCY", "many"),
+            ("HOME_TIMEZONE", "Mars/Olympus"),
+            ("THUMBNAIL_SIZES", "256,0"),
+            ("WATCH_STORE", "copy"),
+        ])
+        .unwrap_err();
+
+        assert_eq!(
+            error.problems,
+            vec![
+                "DATABASE_URL is not set",
+                "AI_PROVIDER: invalid value 'claude', expected ollama or openai",
+                "THUMBNAIL_SIZES: invalid value '256,0', expected comma-separated sizes in pixels such as 256,1024",
+                "INGEST_CONCURRENCY: invalid value 'many', expected a number of images",
+                "HOME_TIMEZONE: invalid value 'Mars/Olympus', expected a time zone such as Europe/Berlin",
+                "WATCH_STORE: invalid value 'copy', expected move or link",
+            ]
+        );
+    }
+
+    #[test]
+    fn settings_of_disabled_features_are_checked() {
+        let error = config(&[
+            ("DATABASE_URL", "postgres://localhost/photos"),
+            ("NORMALIZE_QUALITY", "150"),
+            ("KEEP_ORIGINALS", "no"),
+            ("NORMALIZED_LAYOUT", "flat"),
+            ("TRASH_RETENTION_DAYS", "a month"),
+        ])
+        .unwrap_err();
+
+        assert_eq!(
+            error.problems,
+            vec![
+                "NORMALIZE_QUALITY: invalid value '150', expected a JPEG quality from 1 to 100",
+                "KEEP_ORIGINALS: invalid value 'no', expected true or false",
+                "NORMALIZED_LAYOUT: invalid value 'flat', expected named or hashed",
+                "TRASH_RETENTION_DAYS: invalid value 'a month', expected a number of days",
+            ]
+        );
+        let zero = config(&[("DATABASE_URL", "postgres://localhost/photos"), ("NORMALIZE_FORMAT", "jpeg"), ("NORMALIZE_QUALITY", "0")]);
+        assert!(zero.is_err());
+    }
+
+    #[test]
+    fn oversized_free_space_threshold_is_a_problem() {
+        let error = config(&[("DATABASE_URL", "postgres://localhost/photos"), ("INGEST_MIN_FREE_MB", "18446744073709551615")])
+            .unwrap_err();
+
+        assert_eq!(error.problems, vec!["INGEST_MIN_FREE_MB: 18446744073709551615 MB is more than any disk holds"]);
+    }
+
+    #[test]
+    fn directories_are_resolved_but_not_created() {
+        let root = std::env::temp_dir().canonicalize().unwrap();
+        let dir = root.join(format!("image-index-ai-config-{}", std::process::id()));
+        let setting = format!("{}/trash/../thumbs/./", dir.display());
+
+        let config = config(&[
+            ("DATABASE_URL", "postgres://localhost/photos"),
+            ("THUMBNAIL_SIZES", "256"),
+            ("THUMBNAIL_DIR", &setting),
+        ])
+        .unwrap();
+
+        assert_eq!(config.ingest.thumbnails.unwrap().output_dir, dir.join("thumbs"));
+        assert!(!dir.exists());
+    }
+
+    #[test]
+    fn thumbnail_sizes_drop_repeats() {
+        assert_eq!(parse_sizes(" 256, 1024,256 ,"), Ok(vec![256, 1024]));
+        assert!(parse_sizes(",").is_err());
+    }
+}
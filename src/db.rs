@@ -0,0 +1,200 @@
+use sqlx::PgPool;
+
+use crate::tagging;
+
+// Creates the photos table and brings an existing one up to the current schema
+pub async fn create_photos_table(pool: &PgPool) -> Result<(), sqlx::Error> {
+    let query = r#"
+        CREATE TABLE IF NOT EXISTS photos (
+            photo_id SERIAL PRIMARY KEY,
+            file_name TEXT NOT NULL,
+            file_path TEXT NOT NULL,
+            tags TEXT[],
+            created_at TIMESTAMP DEFAULT (NOW() AT TIME ZONE 'UTC')
+        )
+    "#;
+
+    sqlx::query(query)
+        .execute(pool)
+        .await?;
+
+    // Columns added after the initial schema, applied to existing tables as well
+    let migrations = [
+        "ALTER TABLE photos ADD COLUMN IF NOT EXISTS width INTEGER",
+        "ALTER TABLE photos ADD COLUMN IF NOT EXISTS height INTEGER",
+        "ALTER TABLE photos ADD COLUMN IF NOT EXISTS byte_size BIGINT",
+        "ALTER TABLE photos ADD COLUMN IF NOT EXISTS thumbnail_paths TEXT[]",
+        "ALTER TABLE photos ADD COLUMN IF NOT EXISTS content_hash TEXT",
+        "ALTER TABLE photos ADD COLUMN IF NOT EXISTS captured_at TIMESTAMP",
+        "ALTER TABLE photos ADD COLUMN IF NOT EXISTS camera_make TEXT",
+        "ALTER TABLE photos ADD COLUMN IF NOT EXISTS camera_model TEXT",
+        "ALTER TABLE photos ADD COLUMN IF NOT EXISTS exif_orientation SMALLINT",
+        "ALTER TABLE photos ADD COLUMN IF NOT EXISTS gps_latitude DOUBLE PRECISION",
+        "ALTER TABLE photos ADD COLUMN IF NOT EXISTS gps_longitude DOUBLE PRECISION",
+        "CREATE INDEX IF NOT EXISTS photos_captured_at_idx ON photos (captured_at)",
+        "ALTER TABLE photos ADD COLUMN IF NOT EXISTS original_file_name TEXT",
+        "ALTER TABLE photos ADD COLUMN IF NOT EXISTS tag_keys TEXT[]",
+        "CREATE INDEX IF NOT EXISTS photos_tag_keys_idx ON photos USING GIN (tag_keys)",
+        "ALTER TABLE photos ADD COLUMN IF NOT EXISTS captured_at_utc TIMESTAMP",
+        "CREATE INDEX IF NOT EXISTS photos_captured_at_utc_idx ON photos (captured_at_utc)",
+        // similarity() for the fuzzy search fallback
+        "CREATE EXTENSION IF NOT EXISTS pg_trgm",
+        // Copies kept under INGEST_DUPLICATES=keep-both point at the photo they duplicate, only the
+        // first photo with a given content hash has to be unique. Replaces the earlier unique index
+        // photos_content_hash_idx over all photos.
+        "ALTER TABLE photos ADD COLUMN IF NOT EXISTS duplicate_of INTEGER",
+        "CREATE UNIQUE INDEX IF NOT EXISTS photos_unique_content_hash_idx ON photos (content_hash) WHERE duplicate_of IS NULL",
+        "DROP INDEX IF EXISTS photos_content_hash_idx",
+        "CREATE INDEX IF NOT EXISTS photos_content_hash_lookup_idx ON photos (content_hash)",
+        "ALTER TABLE photos ADD COLUMN IF NOT EXISTS quality REAL",
+        // where the image was found, file_path is the transcoded copy when it was normalized
+        "ALTER TABLE photos ADD COLUMN IF NOT EXISTS original_path TEXT",
+        "CREATE INDEX IF NOT EXISTS photos_original_path_idx ON photos (original_path)",
+        // finalized photos no automated process (reindex, relocate, replacing duplicates) may change
+        "ALTER TABLE photos ADD COLUMN IF NOT EXISTS locked BOOLEAN NOT NULL DEFAULT FALSE",
+    ];
+    for migration in migrations {
+        sqlx::query(migration)
+            .execute(pool)
+            .await?;
+    }
+
+    // Tag keys are computed in Rust, fill them in for photos tagged before they existed
+    let missing_keys: Vec<(i32, Vec<String>)> =
+        sqlx::query_as("SELECT photo_id, tags FROM photos WHERE tag_keys IS NULL AND tags IS NOT NULL")
+            .fetch_all(pool)
+            .await?;
+    for (photo_id, tags) in missing_keys {
+        sqlx::query("UPDATE photos SET tag_keys = $1 WHERE photo_id = $2")
+            .bind(tagging::tag_keys(&tags))
+            .bind(photo_id)
+            .execute(pool)
+            .await?;
+    }
+
+    // Processing timeline of each photo
+    let query = r#"
+        CREATE TABLE IF NOT EXISTS photo_events (
+            event_id SERIAL PRIMARY KEY,
+            photo_id INTEGER NOT NULL REFERENCES photos (photo_id) ON DELETE CASCADE,
+            event TEXT NOT NULL,
+            detail TEXT,
+            created_at TIMESTAMP DEFAULT (NOW() AT TIME ZONE 'UTC')
+        )
+    "#;
+    sqlx::query(query)
+        .execute(pool)
+        .await?;
+    sqlx::query("CREATE INDEX IF NOT EXISTS photo_events_photo_id_idx ON photo_events (photo_id)")
+        .execute(pool)
+        .await?;
+
+    // Related tags from the text model, cached per tag and model for query expansion
+    let query = r#"
+        CREATE TABLE IF NOT EXISTS tag_expansions (
+            tag_key TEXT NOT NULL,
+            model TEXT NOT NULL,
+            related TEXT[] NOT NULL,
+            created_at TIMESTAMP DEFAULT (NOW() AT TIME ZONE 'UTC'),
+            PRIMARY KEY (tag_key, model)
+        )
+    "#;
+    sqlx::query(query)
+        .execute(pool)
+        .await?;
+
+    // Hash-chained record of every tagging operation, see provenance::TagProvenance. No foreign
+    // key, the record outlives the photo.
+    let query = r#"
+        CREATE TABLE IF NOT EXISTS tag_provenance (
+            provenance_id SERIAL PRIMARY KEY,
+            photo_id INTEGER NOT NULL,
+            model TEXT NOT NULL,
+            prompt_hash TEXT NOT NULL,
+            response_hash TEXT NOT NULL,
+            tags TEXT[] NOT NULL,
+            created_at TIMESTAMP NOT NULL,
+            previous_hash TEXT,
+            record_hash TEXT NOT NULL
+        )
+    "#;
+    sqlx::query(query)
+        .execute(pool)
+        .await?;
+    sqlx::query("CREATE INDEX IF NOT EXISTS tag_provenance_photo_id_idx ON tag_provenance (photo_id)")
+        .execute(pool)
+        .await?;
+
+    // Receivers of photo lifecycle events, see hooks::Webhook
+    let query = r#"
+        CREATE TABLE IF NOT EXISTS webhooks (
+            webhook_id SERIAL PRIMARY KEY,
+            url TEXT NOT NULL,
+            secret TEXT,
+            events TEXT[] NOT NULL,
+            enabled BOOLEAN NOT NULL DEFAULT TRUE,
+            created_at TIMESTAMP DEFAULT (NOW() AT TIME ZONE 'UTC')
+        )
+    "#;
+    sqlx::query(query)
+        .execute(pool)
+        .await?;
+
+    // Every POST to a webhook, see hooks::WebhookDelivery. No foreign key, the log outlives the
+    // photo so failed `photo.deleted` deliveries can still be looked up.
+    let query = r#"
+        CREATE TABLE IF NOT EXISTS webhook_deliveries (
+            delivery_id SERIAL PRIMARY KEY,
+            photo_id INTEGER NOT NULL,
+            event TEXT NOT NULL,
+            url TEXT NOT NULL,
+            attempt INTEGER NOT NULL,
+            status SMALLINT,
+            error TEXT,
+            created_at TIMESTAMP NOT NULL
+        )
+    "#;
+    sqlx::query(query)
+        .execute(pool)
+        .await?;
+    sqlx::query("CREATE INDEX IF NOT EXISTS webhook_deliveries_photo_id_idx ON webhook_deliveries (photo_id)")
+        .execute(pool)
+        .await?;
+
+    // Edits, crops and RAW+JPEG pairs, see link::PhotoLink
+    let query = r#"
+        CREATE TABLE IF NOT EXISTS photo_links (
+            link_id SERIAL PRIMARY KEY,
+            from_photo_id INTEGER NOT NULL REFERENCES photos (photo_id) ON DELETE CASCADE,
+            to_photo_id INTEGER NOT NULL REFERENCES photos (photo_id) ON DELETE CASCADE,
+            kind TEXT NOT NULL,
+            created_at TIMESTAMP DEFAULT (NOW() AT TIME ZONE 'UTC'),
+            UNIQUE (from_photo_id, to_photo_id, kind),
+            CHECK (from_photo_id <> to_photo_id)
+        )
+    "#;
+    sqlx::query(query)
+        .execute(pool)
+        .await?;
+    sqlx::query("CREATE INDEX IF NOT EXISTS photo_links_to_photo_id_idx ON photo_links (to_photo_id)")
+        .execute(pool)
+        .await?;
+
+    // Every timestamp is UTC, like the ones written from Rust (provenance, deliveries), so all of
+    // them compare. Tables created before defaulted to the session's local time; rows already
+    // written keep it.
+    let defaults = [
+        "ALTER TABLE photos ALTER COLUMN created_at SET DEFAULT (NOW() AT TIME ZONE 'UTC')",
+        "ALTER TABLE photo_events ALTER COLUMN created_at SET DEFAULT (NOW() AT TIME ZONE 'UTC')",
+        "ALTER TABLE tag_expansions ALTER COLUMN created_at SET DEFAULT (NOW() AT TIME ZONE 'UTC')",
+        "ALTER TABLE webhooks ALTER COLUMN created_at SET DEFAULT (NOW() AT TIME ZONE 'UTC')",
+        "ALTER TABLE photo_links ALTER COLUMN created_at SET DEFAULT (NOW() AT TIME ZONE 'UTC')",
+    ];
+    for default in defaults {
+        sqlx::query(default)
+            .execute(pool)
+            .await?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,165 @@
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::Once;
+
+use sha2::{Digest, Sha256};
+
+// Bytes available to this user on the file system holding `path`
+#[cfg(unix)]
+pub fn available_bytes(path: &Path) -> io::Result<u64> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    let mut stats: libc::statvfs = unsafe { std::mem::zeroed() };
+    // SAFETY: `path` is NUL-terminated and `stats` is a valid statvfs to write into
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stats) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    #[allow(clippy::unnecessary_cast)] // the field types differ between platforms
+    Ok(stats.f_bavail as u64 * stats.f_frsize as u64)
+}
+
+// Only implemented with statvfs so far
+#[cfg(not(unix))]
+pub fn available_bytes(_path: &Path) -> io::Result<u64> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "free space is only checked on Unix"))
+}
+
+// Error when less than `min_free_bytes` are left on any of `paths`. A directory that is not
+// created yet is measured on its nearest existing parent. Where free space cannot be measured on
+// this platform the check is skipped with a warning, once per run.
+pub fn check_free_space<'a>(paths: impl IntoIterator<Item = &'a Path>, min_free_bytes: u64) -> Result<(), String> {
+    static UNSUPPORTED: Once = Once::new();
+    for path in paths {
+        let existing = path.ancestors().find(|ancestor| ancestor.exists()).unwrap_or(path);
+        let available = match available_bytes(existing) {
+            Ok(available) => available,
+            Err(e) if e.kind() == io::ErrorKind::Unsupported => {
+                UNSUPPORTED.call_once(|| println!("Warning: INGEST_MIN_FREE_MB is ignored, {}", e));
+                return Ok(());
+            }
+            Err(e) => return Err(format!("cannot check free space on {}: {}", path.display(), e)),
+        };
+        if available < min_free_bytes {
+            return Err(format!(
+                "only {} MB free on {}, below INGEST_MIN_FREE_MB",
+                available / 1024 / 1024,
+                path.display()
+            ));
+        }
+    }
+    Ok(())
+}
+
+// Write `contents` to `path` through a temporary file in the same directory that is synced and
+// renamed into place, so a crash mid-write leaves either the old file or the complete new one,
+// never a truncated image
+pub fn write_atomically(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let dir = path.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("{} has no file name", path.display())))?;
+    // hidden and without an image extension, so a leftover from a crash is never ingested
+    let temp_path = dir.join(format!(".{}.{}.tmp", file_name.to_string_lossy(), std::process::id()));
+
+    let written = File::create(&temp_path).and_then(|mut file| {
+        file.write_all(contents)?;
+        file.sync_all()
+    });
+    if let Err(e) = written.and_then(|_| fs::rename(&temp_path, path)) {
+        let _ = fs::remove_file(&temp_path);
+        return Err(e);
+    }
+    // persist the rename itself
+    File::open(dir)?.sync_all()
+}
+
+// A file to find copies of: other files are compared by size first and only hashed when the size
+// matches, streamed rather than read into memory. The file's own hash is computed once, when the
+// first candidate of the same size turns up.
+pub struct Fingerprint<'a> {
+    path: &'a Path,
+    len: u64,
+    hash: Option<Vec<u8>>,
+}
+
+impl<'a> Fingerprint<'a> {
+    pub fn of(path: &'a Path) -> io::Result<Self> {
+        Ok(Fingerprint { path, len: fs::metadata(path)?.len(), hash: None })
+    }
+
+    // Whether `other` holds the same bytes
+    pub fn matches(&mut self, other: &Path) -> io::Result<bool> {
+        if fs::metadata(other)?.len() != self.len {
+            return Ok(false);
+        }
+        let hash = match &self.hash {
+            Some(hash) => hash,
+            None => self.hash.insert(hash_file(self.path)?),
+        };
+        Ok(hash_file(other)? == *hash)
+    }
+}
+
+fn hash_file(path: &Path) -> io::Result<Vec<u8>> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut File::open(path)?, &mut hasher)?;
+    Ok(hasher.finalize().to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[cfg(unix)]
+    fn reports_free_space_and_errors() {
+        let here = Path::new(env!("CARGO_MANIFEST_DIR"));
+        assert!(available_bytes(here).unwrap() > 0);
+        assert!(check_free_space([here], 0).is_ok());
+        assert!(check_free_space([here.join("not/created/yet").as_path()], 0).is_ok());
+        assert!(check_free_space([here], u64::MAX).unwrap_err().contains("below INGEST_MIN_FREE_MB"));
+        assert!(available_bytes(Path::new("/does/not/exist")).is_err());
+    }
+
+    #[test]
+    #[cfg(not(unix))]
+    fn skips_the_free_space_check() {
+        let here = Path::new(env!("CARGO_MANIFEST_DIR"));
+        assert!(check_free_space([here], u64::MAX).is_ok());
+    }
+
+    #[test]
+    fn atomic_writes_replace_the_file_and_leave_no_temp_files() {
+        let dir = std::env::temp_dir().join(format!("image-index-ai-disk-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("a.jpg");
+
+        write_atomically(&path, b"first").unwrap();
+        write_atomically(&path, b"second").unwrap();
+
+        assert_eq!(fs::read(&path).unwrap(), b"second");
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        assert!(write_atomically(&dir.join("missing/a.jpg"), b"x").is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn fingerprints_match_files_with_the_same_bytes() {
+        let dir = std::env::temp_dir().join(format!("image-index-ai-fingerprint-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        for (name, contents) in [("a", "beach"), ("same", "beach"), ("longer", "beaches"), ("other", "coast")] {
+            fs::write(dir.join(name), contents).unwrap();
+        }
+
+        let a = dir.join("a");
+        let mut fingerprint = Fingerprint::of(&a).unwrap();
+        assert!(fingerprint.matches(&dir.join("same")).unwrap());
+        assert!(!fingerprint.matches(&dir.join("longer")).unwrap());
+        assert!(!fingerprint.matches(&dir.join("other")).unwrap());
+        assert!(fingerprint.matches(&dir.join("missing")).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,59 @@
+use chrono::NaiveDateTime;
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+
+// Processing steps recorded in a photo's timeline
+pub const UPLOADED: &str = "uploaded";
+pub const TAGGED: &str = "tagged";
+pub const THUMBNAILED: &str = "thumbnailed";
+pub const RETAGGED: &str = "retagged";
+pub const HOOK_FAILED: &str = "hook_failed";
+pub const IMPORTED: &str = "imported";
+
+// One timestamped entry in a photo's processing history
+#[derive(Debug, Serialize, Deserialize, sqlx::FromRow)]
+pub struct PhotoEvent {
+    pub event_id: i32,
+    pub photo_id: i32,
+    pub event: String,
+    pub detail: Option<String>,
+    pub created_at: NaiveDateTime,
+}
+
+impl PhotoEvent {
+    // Function to append an event to a photo's history
+    pub async fn record(pool: &PgPool, photo_id: i32, event: &str, detail: Option<&str>) -> Result<(), sqlx::Error> {
+        let query = "INSERT INTO photo_events (photo_id, event, detail) VALUES ($1, $2, $3)";
+        sqlx::query(query)
+            .bind(photo_id)
+            .bind(event)
+            .bind(detail)
+            .execute(pool)
+            .await?;
+
+        Ok(())
+    }
+
+    // Function to add an event from an export to a photo's history, at the time it happened
+    pub async fn import(pool: &PgPool, photo_id: i32, event: &PhotoEvent) -> Result<(), sqlx::Error> {
+        let query = "INSERT INTO photo_events (photo_id, event, detail, created_at) VALUES ($1, $2, $3, $4)";
+        sqlx::query(query)
+            .bind(photo_id)
+            .bind(&event.event)
+            .bind(&event.detail)
+            .bind(event.created_at)
+            .execute(pool)
+            .await?;
+
+        Ok(())
+    }
+
+    // Function to get the history of a photo, oldest first
+    pub async fn list_for_photo(pool: &PgPool, photo_id: i32) -> Result<Vec<PhotoEvent>, sqlx::Error> {
+        let query = "SELECT event_id, photo_id, event, detail, created_at FROM photo_events WHERE photo_id = $1 ORDER BY created_at, event_id";
+        sqlx::query_as::<_, PhotoEvent>(query)
+            .bind(photo_id)
+            .fetch_all(pool)
+            .await
+    }
+}
@@ -0,0 +1,46 @@
+use std::error::Error;
+
+use sqlx::PgPool;
+
+use crate::tagger::Tagger;
+use crate::tagging;
+
+// Broaden search tags with synonyms and related tags from the text model, so a search for "car"
+// also finds photos tagged "automobile". Each tag is asked about once per model; answers are kept in
+// the tag_expansions table.
+pub async fn expand_tags(pool: &PgPool, tagger: &dyn Tagger, tags: &[String]) -> Result<Vec<String>, Box<dyn Error>> {
+    let mut expanded = tags.to_vec();
+    for tag in tags {
+        for related in related_tags(pool, tagger, tag).await? {
+            if !expanded.contains(&related) {
+                expanded.push(related);
+            }
+        }
+    }
+    Ok(expanded)
+}
+
+async fn related_tags(pool: &PgPool, tagger: &dyn Tagger, tag: &str) -> Result<Vec<String>, Box<dyn Error>> {
+    let tag_key = tagging::tag_key(tag);
+    let query = "SELECT related FROM tag_expansions WHERE tag_key = $1 AND model = $2";
+    let cached: Option<Vec<String>> = sqlx::query_scalar(query)
+        .bind(&tag_key)
+        .bind(tagger.text_model())
+        .fetch_optional(pool)
+        .await?;
+    if let Some(related) = cached {
+        return Ok(related);
+    }
+
+    let related = tagger.related_tags(tag).await?;
+    let query = "INSERT INTO tag_expansions (tag_key, model, related) VALUES ($1, $2, $3)
+        ON CONFLICT (tag_key, model) DO UPDATE SET related = EXCLUDED.related, created_at = NOW() AT TIME ZONE 'UTC'";
+    sqlx::query(query)
+        .bind(&tag_key)
+        .bind(tagger.text_model())
+        .bind(&related)
+        .execute(pool)
+        .await?;
+
+    Ok(related)
+}
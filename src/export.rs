@@ -0,0 +1,414 @@
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Seek, Write};
+use std::path::{Path, PathBuf};
+
+use futures::TryStreamExt;
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+
+use crate::disk::Fingerprint;
+use crate::events::{self, PhotoEvent};
+use crate::ingest::{self, IngestOptions};
+use crate::link::{LinkKind, PhotoLink};
+use crate::photo::Photo;
+use crate::provenance::TagProvenance;
+
+// A photo link as exported, on a line of its own as `{"link": {...}}` after all photos. Both ends
+// carry their content hash, ids change on import.
+#[derive(Debug, Serialize, Deserialize, sqlx::FromRow)]
+struct ExportedLink {
+    from_photo_id: i32,
+    from_content_hash: Option<String>,
+    to_photo_id: i32,
+    to_content_hash: Option<String>,
+    kind: String,
+}
+
+// The lines following the photos, each an object with a single key
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Line {
+    Link(ExportedLink),
+    Event(PhotoEvent),
+    Provenance(TagProvenance),
+}
+
+// The index inside an archive, written after the image files
+const INDEX_ENTRY: &str = "index.ndjson";
+
+// Writes photos, then their links, events and provenance records, as NDJSON while they are read,
+// so huge libraries are never held in memory
+pub async fn export_photos(pool: &PgPool, writer: impl Write) -> Result<(), Box<dyn Error>> {
+    write_index(pool, writer, |_| Ok(())).await
+}
+
+// Writes a tar archive of the library for another server: every indexed image file under
+// `files/`, then the NDJSON of `export_photos` as `index.ndjson`, its paths pointing into the
+// archive. The index goes last so both ends can stream; it is staged in an anonymous temporary
+// file meanwhile, which is gone once the export ends, even when it fails. Thumbnails are left out,
+// the importing side makes its own.
+pub async fn export_archive(pool: &PgPool, writer: impl Write) -> Result<(), Box<dyn Error>> {
+    let mut archive = tar::Builder::new(writer);
+    let mut index = tempfile::tempfile()?;
+
+    // kept copies may share a file, it is archived once
+    let mut entries: HashMap<String, String> = HashMap::new();
+    write_index(pool, &mut index, |photo| {
+        let entry = match entries.get(&photo.file_path) {
+            Some(entry) => entry.clone(),
+            None => {
+                // a file missing here is reported as missing on import
+                if !Path::new(&photo.file_path).is_file() {
+                    return Ok(());
+                }
+                // named like the file itself, the stored name may not be a plain file name
+                let name = Path::new(&photo.file_path).file_name().unwrap_or_default().to_string_lossy();
+                let entry = format!("files/{}/{}", photo.photo_id, name);
+                archive.append_path_with_name(&photo.file_path, &entry)?;
+                entries.insert(photo.file_path.clone(), entry.clone());
+                entry
+            }
+        };
+        if photo.original_path.as_deref() == Some(photo.file_path.as_str()) {
+            photo.original_path = Some(entry.clone());
+        }
+        photo.file_path = entry;
+        Ok(())
+    })
+    .await?;
+    index.rewind()?;
+    archive.append_file(INDEX_ENTRY, &mut index)?;
+    archive.finish()?;
+    Ok(())
+}
+
+async fn write_index(
+    pool: &PgPool,
+    writer: impl Write,
+    mut exported: impl FnMut(&mut Photo) -> io::Result<()>,
+) -> Result<(), Box<dyn Error>> {
+    let mut writer = BufWriter::new(writer);
+
+    let mut photos = Photo::stream_all(pool);
+    while let Some(mut photo) = photos.try_next().await? {
+        exported(&mut photo)?;
+        serde_json::to_writer(&mut writer, &photo)?;
+        writer.write_all(b"\n")?;
+    }
+
+    let query = "SELECT l.from_photo_id, f.content_hash AS from_content_hash, l.to_photo_id,
+            t.content_hash AS to_content_hash, l.kind
+        FROM photo_links l
+        JOIN photos f ON f.photo_id = l.from_photo_id
+        JOIN photos t ON t.photo_id = l.to_photo_id
+        ORDER BY l.link_id";
+    let mut links = sqlx::query_as::<_, ExportedLink>(query).fetch(pool);
+    while let Some(link) = links.try_next().await? {
+        serde_json::to_writer(&mut writer, &Line::Link(link))?;
+        writer.write_all(b"\n")?;
+    }
+
+    let query = "SELECT event_id, photo_id, event, detail, created_at FROM photo_events ORDER BY created_at, event_id";
+    let mut events = sqlx::query_as::<_, PhotoEvent>(query).fetch(pool);
+    while let Some(event) = events.try_next().await? {
+        serde_json::to_writer(&mut writer, &Line::Event(event))?;
+        writer.write_all(b"\n")?;
+    }
+
+    // records of deleted photos stay behind, they have nothing to be imported for
+    let query = "SELECT provenance_id, r.photo_id, model, prompt_hash, response_hash, r.tags, r.created_at, previous_hash, record_hash
+        FROM tag_provenance r JOIN photos p ON p.photo_id = r.photo_id ORDER BY provenance_id";
+    let mut records = sqlx::query_as::<_, TagProvenance>(query).fetch(pool);
+    while let Some(record) = records.try_next().await? {
+        serde_json::to_writer(&mut writer, &Line::Provenance(record))?;
+        writer.write_all(b"\n")?;
+    }
+    writer.flush()?;
+
+    Ok(())
+}
+
+// Where the image files of an imported library live now, e.g. `--rebase /srv/photos:/data/photos`
+// when the folders were copied to another root
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rebase {
+    pub from: String,
+    pub to: String,
+}
+
+impl Rebase {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value.split_once(':') {
+            Some((from, to)) if !from.is_empty() && !to.is_empty() => Ok(Rebase {
+                from: from.trim_end_matches('/').to_string(),
+                to: to.trim_end_matches('/').to_string(),
+            }),
+            _ => Err(format!("invalid rebase '{}', expected <old root>:<new root>", value)),
+        }
+    }
+
+    fn apply(&self, path: &str) -> String {
+        match path.strip_prefix(&self.from) {
+            Some(rest) if rest.is_empty() || rest.starts_with('/') => format!("{}{}", self.to, rest),
+            _ => path.to_string(),
+        }
+    }
+}
+
+// Outcome of an import run
+#[derive(Debug, Default)]
+pub struct ImportReport {
+    pub imported: usize,
+    // already on this database, matched by content hash and path
+    pub skipped: usize,
+    // imported, but the image file isn't where the row says
+    pub missing_files: Vec<String>,
+    pub links_imported: usize,
+    // already linked on this database
+    pub links_skipped: usize,
+}
+
+// The id a photo of the export has on this database: the one it was imported or matched as, or
+// else the indexed photo with the same content
+async fn imported_id(
+    pool: &PgPool,
+    new_ids: &HashMap<i32, i32>,
+    photo_id: i32,
+    content_hash: Option<&str>,
+) -> Result<Option<i32>, sqlx::Error> {
+    if let Some(&new_id) = new_ids.get(&photo_id) {
+        return Ok(Some(new_id));
+    }
+    match content_hash {
+        Some(content_hash) => Ok(Photo::find_by_content_hash(pool, content_hash).await?.map(|photo| photo.photo_id)),
+        None => Ok(None),
+    }
+}
+
+// Restore photos, links, events and provenance written by `export_photos`, keeping tags and
+// metadata so nothing has to be re-tagged. The image files are copied separately. Running it again
+// skips photos that are already there, and the history of those photos with them.
+pub async fn import_photos(
+    pool: &PgPool,
+    options: &IngestOptions,
+    reader: impl BufRead,
+    rebase: Option<&Rebase>,
+) -> Result<ImportReport, Box<dyn Error>> {
+    import_index(pool, options, reader, |path| match rebase {
+        Some(rebase) => rebase.apply(path),
+        None => path.to_string(),
+    })
+    .await
+}
+
+// Restore an archive written by `export_archive`, writing its image files to `restore_dir` under
+// their own names (with a counter when a different file has the name). Files restored by an
+// earlier run are reused, so an interrupted import can be run again.
+pub async fn import_archive(
+    pool: &PgPool,
+    options: &IngestOptions,
+    reader: impl Read,
+    restore_dir: &Path,
+) -> Result<ImportReport, Box<dyn Error>> {
+    fs::create_dir_all(restore_dir)?;
+    // stored paths are canonical
+    let restore_dir = restore_dir.canonicalize()?;
+    let mut archive = tar::Archive::new(reader);
+    let mut restored: HashMap<String, String> = HashMap::new();
+
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let name = entry.path()?.to_string_lossy().into_owned();
+        if name == INDEX_ENTRY {
+            // last in the archive, every file is restored by now
+            return import_index(pool, options, BufReader::new(entry), |path| {
+                restored.get(path).cloned().unwrap_or_else(|| path.to_string())
+            })
+            .await;
+        }
+        if !name.starts_with("files/") || !entry.header().entry_type().is_file() {
+            continue;
+        }
+        let path = restore_file(&restore_dir, Path::new(&name), &mut entry)?;
+        let path = path.to_str().ok_or_else(|| format!("{} is not valid UTF-8", path.display()))?;
+        restored.insert(name, path.to_string());
+    }
+    Err(format!("not an archive written by `export --archive`, {} is missing", INDEX_ENTRY).into())
+}
+
+// Write an archived file to `dir`, named like in the archive but never outside `dir`; returns the
+// file it is in now
+fn restore_file(dir: &Path, name: &Path, contents: &mut impl Read) -> io::Result<PathBuf> {
+    let stem = name.file_stem().unwrap_or_default().to_string_lossy();
+    let stem = ingest::sanitize_file_stem(&stem);
+    let extension: String = name
+        .extension()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .chars()
+        .filter(char::is_ascii_alphanumeric)
+        .collect();
+    let file_name = |counter: usize| match (counter, extension.is_empty()) {
+        (0, true) => stem.clone(),
+        (0, false) => format!("{}.{}", stem, extension),
+        (_, true) => format!("{}-{}", stem, counter),
+        (_, false) => format!("{}-{}.{}", stem, counter, extension),
+    };
+
+    let temp = dir.join(format!(".{}.{}.tmp", file_name(0), std::process::id()));
+    let copied = File::create(&temp).and_then(|mut file| io::copy(contents, &mut file));
+    if let Err(e) = copied {
+        let _ = fs::remove_file(&temp);
+        return Err(e);
+    }
+    let mut restored = Fingerprint::of(&temp)?;
+    let mut counter = 0;
+    loop {
+        let target = dir.join(file_name(counter));
+        if !target.exists() {
+            fs::rename(&temp, &target)?;
+            return target.canonicalize();
+        }
+        if restored.matches(&target)? {
+            fs::remove_file(&temp)?;
+            return target.canonicalize();
+        }
+        counter += 1;
+    }
+}
+
+async fn import_index(
+    pool: &PgPool,
+    options: &IngestOptions,
+    reader: impl BufRead,
+    relocate: impl Fn(&str) -> String,
+) -> Result<ImportReport, Box<dyn Error>> {
+    let mut report = ImportReport::default();
+    // ids change on import, kept copies have to point at the new id of their original
+    let mut new_ids: HashMap<i32, i32> = HashMap::new();
+    // photos inserted by this run, only their history is restored
+    let mut inserted: HashSet<i32> = HashSet::new();
+
+    for (number, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let value: serde_json::Value =
+            serde_json::from_str(&line).map_err(|e| format!("line {}: invalid JSON: {}", number + 1, e))?;
+        let is_photo = value.get("photo_id").is_some();
+        if !is_photo {
+            let line: Line =
+                serde_json::from_value(value).map_err(|e| format!("line {}: invalid line: {}", number + 1, e))?;
+            match line {
+                Line::Link(link) => {
+                    let kind: LinkKind = link.kind.parse().map_err(|e| format!("line {}: {}", number + 1, e))?;
+                    let from = imported_id(pool, &new_ids, link.from_photo_id, link.from_content_hash.as_deref()).await?;
+                    let to = imported_id(pool, &new_ids, link.to_photo_id, link.to_content_hash.as_deref()).await?;
+                    let (Some(from), Some(to)) = (from, to) else {
+                        return Err(format!(
+                            "line {}: link between photos #{} and #{} which are not in the export",
+                            number + 1,
+                            link.from_photo_id,
+                            link.to_photo_id
+                        )
+                        .into());
+                    };
+                    // both ends may have matched the same photo here
+                    if from == to {
+                        report.links_skipped += 1;
+                        continue;
+                    }
+                    match PhotoLink::create(pool, from, kind, to).await? {
+                        Some(_) => report.links_imported += 1,
+                        None => report.links_skipped += 1,
+                    }
+                }
+                Line::Event(event) => {
+                    if inserted.contains(&event.photo_id) {
+                        PhotoEvent::import(pool, new_ids[&event.photo_id], &event).await?;
+                    }
+                }
+                Line::Provenance(record) => {
+                    if inserted.contains(&record.photo_id) {
+                        TagProvenance::import(pool, new_ids[&record.photo_id], &record).await?;
+                    }
+                }
+            }
+            continue;
+        }
+        let mut photo: Photo =
+            serde_json::from_value(value).map_err(|e| format!("line {}: invalid photo: {}", number + 1, e))?;
+        photo.file_path = relocate(&photo.file_path);
+        photo.original_path = photo.original_path.map(|path| relocate(&path));
+
+        let existing = Photo::find_by_file_path(pool, &photo.file_path).await?;
+        if let Some(existing) = existing.iter().find(|existing| existing.content_hash == photo.content_hash) {
+            new_ids.insert(photo.photo_id, existing.photo_id);
+            report.skipped += 1;
+            continue;
+        }
+
+        let duplicate_of = match photo.duplicate_of {
+            Some(duplicate_of) => Some(
+                *new_ids
+                    .get(&duplicate_of)
+                    .ok_or_else(|| format!("line {}: copy of photo #{} which comes later", number + 1, duplicate_of))?,
+            ),
+            None => None,
+        };
+        let photo_id = match Photo::import(pool, &photo, duplicate_of).await? {
+            Some(photo_id) => photo_id,
+            // the same image is indexed here under another path
+            None => {
+                let content_hash = photo.content_hash.as_deref().unwrap_or_default();
+                if let Some(existing) = Photo::find_by_content_hash(pool, content_hash).await? {
+                    new_ids.insert(photo.photo_id, existing.photo_id);
+                }
+                report.skipped += 1;
+                continue;
+            }
+        };
+        new_ids.insert(photo.photo_id, photo_id);
+        inserted.insert(photo.photo_id);
+
+        let imported = format!("as photo #{} from an export", photo.photo_id);
+        PhotoEvent::record(pool, photo_id, events::IMPORTED, Some(&imported)).await?;
+        if !Path::new(&photo.file_path).is_file() {
+            report.missing_files.push(photo.file_path);
+        } else if let Some(thumbnails) = &options.thumbnails {
+            // thumbnails are named after the photo id, the exported ones belong to other photos here
+            let thumbnailed = image::open(&photo.file_path).map_err(Box::<dyn Error>::from).and_then(|image| {
+                thumbnails.generate(photo_id, &image)
+            });
+            match thumbnailed {
+                Ok(thumbnail_paths) => {
+                    Photo::set_thumbnail_paths(pool, photo_id, &thumbnail_paths).await?;
+                    let thumbnailed = format!("sizes {:?}", thumbnails.sizes);
+                    PhotoEvent::record(pool, photo_id, events::THUMBNAILED, Some(&thumbnailed)).await?;
+                }
+                Err(e) => println!("Failed to create thumbnails for photo #{}: {}", photo_id, e),
+            }
+        }
+        report.imported += 1;
+    }
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rebase_replaces_whole_leading_components() {
+        let rebase = Rebase::parse("/srv/photos/:/data").unwrap();
+
+        assert_eq!(rebase.apply("/srv/photos/2023/a.jpg"), "/data/2023/a.jpg");
+        assert_eq!(rebase.apply("/srv/photos-old/a.jpg"), "/srv/photos-old/a.jpg");
+        assert_eq!(rebase.apply("/elsewhere/a.jpg"), "/elsewhere/a.jpg");
+        assert!(Rebase::parse("/srv/photos").is_err());
+    }
+}
@@ -0,0 +1,519 @@
+use std::error::Error;
+use std::fmt::{self, Debug};
+use std::process::Stdio;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use chrono::{NaiveDateTime, Utc};
+use data_encoding::HEXLOWER;
+use futures::FutureExt;
+use hmac::{Hmac, Mac};
+use serde::Serialize;
+use serde_json::json;
+use sha2::Sha256;
+use sqlx::PgPool;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+
+use crate::events::{self, PhotoEvent};
+use crate::photo::Photo;
+
+// Custom behaviour run after a photo has been ingested (copy it to a NAS, notify a chat, ...).
+// Hooks see the stored record including tags and thumbnails. A failing hook is recorded in the
+// photo's history but does not undo the ingest.
+#[async_trait]
+pub trait PostIngestHook: Debug + Send + Sync {
+    async fn after_ingest(&self, pool: &PgPool, photo: &Photo) -> Result<(), Box<dyn Error>>;
+
+    // Called with the updated record after `reindex` replaced a photo's tags
+    async fn after_tag(&self, _pool: &PgPool, _photo: &Photo) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+
+    // Called with the removed record after a photo is deleted from the index
+    async fn after_delete(&self, _pool: &PgPool, _photo: &Photo) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+
+    // Called once the command is done, to wait for work the hook left running in the background
+    async fn finish(&self) {}
+}
+
+// Runs a shell command for every ingested photo, configured through INGEST_HOOK_COMMAND. The photo
+// is passed as JSON on stdin and as PHOTO_ID and PHOTO_PATH in the environment, e.g.
+// `INGEST_HOOK_COMMAND='cp "$PHOTO_PATH" /mnt/nas/photos/'`.
+#[derive(Debug)]
+pub struct CommandHook {
+    pub command: String,
+}
+
+#[async_trait]
+impl PostIngestHook for CommandHook {
+    async fn after_ingest(&self, _pool: &PgPool, photo: &Photo) -> Result<(), Box<dyn Error>> {
+        let mut child = Command::new("sh")
+            .arg("-c")
+            .arg(&self.command)
+            .env("PHOTO_ID", photo.photo_id.to_string())
+            .env("PHOTO_PATH", &photo.file_path)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::inherit())
+            .stderr(Stdio::piped())
+            .spawn()?;
+
+        let json = serde_json::to_vec(photo)?;
+        if let Some(mut stdin) = child.stdin.take() {
+            // a command that doesn't read its input closes the pipe early, that is not an error
+            match stdin.write_all(&json).await {
+                Err(e) if e.kind() != std::io::ErrorKind::BrokenPipe => return Err(e.into()),
+                _ => {}
+            }
+        }
+
+        let output = child.wait_with_output().await?;
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            let mut message = format!("hook command exited with {}", output.status);
+            if !stderr.trim().is_empty() {
+                message = format!("{}: {}", message, stderr.trim());
+            }
+            return Err(message.into());
+        }
+        Ok(())
+    }
+}
+
+// Lifecycle events a webhook can subscribe to
+pub const PHOTO_CREATED: &str = "photo.created";
+pub const PHOTO_TAGGED: &str = "photo.tagged";
+pub const PHOTO_DELETED: &str = "photo.deleted";
+pub const EVENTS: [&str; 3] = [PHOTO_CREATED, PHOTO_TAGGED, PHOTO_DELETED];
+
+// Deliveries running at once, so a burst of ingested photos doesn't open a connection each
+const MAX_CONCURRENT_DELIVERIES: usize = 8;
+
+// A registered receiver for lifecycle events, managed with `webhook add|list|remove|enable|disable`
+#[derive(Clone, sqlx::FromRow)]
+pub struct Webhook {
+    pub webhook_id: i32,
+    pub url: String,
+    pub secret: Option<String>,
+    pub events: Vec<String>,
+    pub enabled: bool,
+    pub created_at: NaiveDateTime,
+}
+
+// Failures are printed and recorded, the secret must not be
+impl Debug for Webhook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Webhook")
+            .field("webhook_id", &self.webhook_id)
+            .field("url", &self.url)
+            .field("secret", &self.secret.as_ref().map(|_| "<redacted>"))
+            .field("events", &self.events)
+            .field("enabled", &self.enabled)
+            .finish()
+    }
+}
+
+// An event name as given on the command line
+pub fn parse_event(value: &str) -> Result<String, String> {
+    match EVENTS.contains(&value) {
+        true => Ok(value.to_string()),
+        false => Err(format!("unknown event '{}', expected {}", value, EVENTS.join(", "))),
+    }
+}
+
+impl Webhook {
+    // Function to register a webhook for `events`, enabled
+    pub async fn create(pool: &PgPool, url: &str, secret: Option<&str>, events: &[String]) -> Result<Webhook, sqlx::Error> {
+        let query = "INSERT INTO webhooks (url, secret, events) VALUES ($1, $2, $3)
+            RETURNING webhook_id, url, secret, events, enabled, created_at";
+        sqlx::query_as::<_, Webhook>(query)
+            .bind(url)
+            .bind(secret)
+            .bind(events)
+            .fetch_one(pool)
+            .await
+    }
+
+    // Function to list every webhook, oldest first
+    pub async fn list(pool: &PgPool) -> Result<Vec<Webhook>, sqlx::Error> {
+        let query = "SELECT webhook_id, url, secret, events, enabled, created_at FROM webhooks ORDER BY webhook_id";
+        sqlx::query_as::<_, Webhook>(query).fetch_all(pool).await
+    }
+
+    // Function to get the enabled webhooks subscribed to `event`
+    pub async fn subscribed(pool: &PgPool, event: &str) -> Result<Vec<Webhook>, sqlx::Error> {
+        let query = "SELECT webhook_id, url, secret, events, enabled, created_at FROM webhooks
+            WHERE enabled AND $1 = ANY (events) ORDER BY webhook_id";
+        sqlx::query_as::<_, Webhook>(query).bind(event).fetch_all(pool).await
+    }
+
+    // Function to turn a webhook on or off, false when there is none with that id
+    pub async fn set_enabled(pool: &PgPool, webhook_id: i32, enabled: bool) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query("UPDATE webhooks SET enabled = $2 WHERE webhook_id = $1")
+            .bind(webhook_id)
+            .bind(enabled)
+            .execute(pool)
+            .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    // Function to remove a webhook, its delivery log stays; false when there is none with that id
+    pub async fn delete(pool: &PgPool, webhook_id: i32) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query("DELETE FROM webhooks WHERE webhook_id = $1")
+            .bind(webhook_id)
+            .execute(pool)
+            .await?;
+        Ok(result.rows_affected() > 0)
+    }
+}
+
+// POSTs `{"event": "photo.created" | "photo.tagged" | "photo.deleted", "photo": {...}}` to every
+// enabled webhook subscribed to the event when a photo is ingested, re-tagged or deleted, e.g. for
+// a home-automation server. A webhook with a secret gets the body signed with HMAC-SHA256 in an
+// `X-Signature-256: sha256=<hex>` header. Deliveries run in the background so a slow or dead
+// receiver never holds up an ingest; failed ones are retried up to WEBHOOK_ATTEMPTS times in total
+// (default 3), waiting longer after each, and every attempt is logged as a `WebhookDelivery`.
+// `finish` waits for the deliveries still running.
+#[derive(Debug)]
+pub struct WebhookHook {
+    client: reqwest::Client,
+    pub attempts: u32,
+    // wait before the first retry, doubled for every further one
+    pub retry_delay: Duration,
+    deliveries: Arc<Semaphore>,
+    tasks: Mutex<JoinSet<()>>,
+}
+
+// Outcome of one POST to the webhook
+#[derive(Debug)]
+pub struct DeliveryAttempt {
+    pub attempted_at: NaiveDateTime,
+    // HTTP status, unset when no response came back
+    pub status: Option<u16>,
+    pub error: Option<String>,
+}
+
+// What a delivery running in the background needs from the hook
+#[derive(Clone)]
+struct Sender {
+    client: reqwest::Client,
+    attempts: u32,
+    retry_delay: Duration,
+}
+
+impl Default for WebhookHook {
+    fn default() -> Self {
+        WebhookHook::new()
+    }
+}
+
+impl WebhookHook {
+    pub fn new() -> Self {
+        WebhookHook {
+            client: reqwest::Client::builder()
+                .timeout(Duration::from_secs(10))
+                .build()
+                .unwrap_or_default(),
+            attempts: 3,
+            retry_delay: Duration::from_secs(1),
+            deliveries: Arc::new(Semaphore::new(MAX_CONCURRENT_DELIVERIES)),
+            tasks: Mutex::new(JoinSet::new()),
+        }
+    }
+
+    fn sender(&self) -> Sender {
+        Sender { client: self.client.clone(), attempts: self.attempts, retry_delay: self.retry_delay }
+    }
+
+    // POST the event to one webhook until it is accepted or the attempts run out, returning every
+    // attempt; the last one failed when it has an error
+    pub async fn send(&self, webhook: &Webhook, event: &str, photo: &Photo) -> Result<Vec<DeliveryAttempt>, Box<dyn Error>> {
+        self.sender().send(webhook, event, photo).await
+    }
+
+    // Start a delivery to every webhook subscribed to the event, without waiting for them
+    async fn dispatch(&self, pool: &PgPool, event: &'static str, photo: &Photo) -> Result<(), Box<dyn Error>> {
+        let webhooks = Webhook::subscribed(pool, event).await?;
+        let mut tasks = self.tasks.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        // a long `watch` run would otherwise keep every finished delivery around
+        while let Some(Some(_)) = tasks.join_next().now_or_never() {}
+        for webhook in webhooks {
+            let (pool, photo, sender, deliveries) = (pool.clone(), photo.clone(), self.sender(), self.deliveries.clone());
+            tasks.spawn(async move {
+                let Ok(_permit) = deliveries.acquire_owned().await else { return };
+                let delivered = sender.deliver(&pool, &webhook, event, &photo).await.map_err(|e| e.to_string());
+                if let Err(e) = delivered {
+                    println!("Webhook #{} failed for photo #{}: {}", webhook.webhook_id, photo.photo_id, e);
+                    // the history of a deleted photo is gone, the delivery log has the failure
+                    if event != PHOTO_DELETED {
+                        let failed = format!("webhook #{} {}: {}", webhook.webhook_id, webhook.url, e);
+                        let _ = PhotoEvent::record(&pool, photo.photo_id, events::HOOK_FAILED, Some(&failed)).await;
+                    }
+                }
+            });
+        }
+        Ok(())
+    }
+}
+
+impl Sender {
+    async fn send(&self, webhook: &Webhook, event: &str, photo: &Photo) -> Result<Vec<DeliveryAttempt>, Box<dyn Error>> {
+        let body = serde_json::to_vec(&json!({ "event": event, "photo": photo }))?;
+        let signature = match &webhook.secret {
+            Some(secret) => {
+                let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes())?;
+                mac.update(&body);
+                Some(format!("sha256={}", HEXLOWER.encode(&mac.finalize().into_bytes())))
+            }
+            None => None,
+        };
+
+        let mut delay = self.retry_delay;
+        let mut attempts = Vec::new();
+        loop {
+            let mut request = self
+                .client
+                .post(&webhook.url)
+                .header(reqwest::header::CONTENT_TYPE, "application/json")
+                .body(body.clone());
+            if let Some(signature) = &signature {
+                request = request.header("X-Signature-256", signature);
+            }
+            let attempted_at = Utc::now().naive_utc();
+            let attempt = match request.send().await {
+                Ok(response) if response.status().is_success() => {
+                    DeliveryAttempt { attempted_at, status: Some(response.status().as_u16()), error: None }
+                }
+                Ok(response) => DeliveryAttempt {
+                    attempted_at,
+                    status: Some(response.status().as_u16()),
+                    error: Some(format!("webhook returned {}", response.status())),
+                },
+                Err(e) => DeliveryAttempt { attempted_at, status: None, error: Some(format!("webhook request failed: {}", e)) },
+            };
+            let failed = attempt.error.is_some();
+            attempts.push(attempt);
+            if !failed || attempts.len() >= self.attempts.max(1) as usize {
+                return Ok(attempts);
+            }
+            tokio::time::sleep(delay).await;
+            delay *= 2;
+        }
+    }
+
+    async fn deliver(&self, pool: &PgPool, webhook: &Webhook, event: &str, photo: &Photo) -> Result<(), Box<dyn Error>> {
+        let attempts = self.send(webhook, event, photo).await?;
+        for (i, attempt) in attempts.iter().enumerate() {
+            WebhookDelivery::record(pool, photo.photo_id, event, &webhook.url, i as i32 + 1, attempt).await?;
+        }
+        match attempts.last().and_then(|attempt| attempt.error.as_ref()) {
+            Some(error) => Err(format!("{} (after {} attempts)", error, attempts.len()).into()),
+            None => Ok(()),
+        }
+    }
+}
+
+#[async_trait]
+impl PostIngestHook for WebhookHook {
+    async fn after_ingest(&self, pool: &PgPool, photo: &Photo) -> Result<(), Box<dyn Error>> {
+        self.dispatch(pool, PHOTO_CREATED, photo).await
+    }
+
+    async fn after_tag(&self, pool: &PgPool, photo: &Photo) -> Result<(), Box<dyn Error>> {
+        self.dispatch(pool, PHOTO_TAGGED, photo).await
+    }
+
+    async fn after_delete(&self, pool: &PgPool, photo: &Photo) -> Result<(), Box<dyn Error>> {
+        self.dispatch(pool, PHOTO_DELETED, photo).await
+    }
+
+    async fn finish(&self) {
+        let mut tasks = std::mem::take(&mut *self.tasks.lock().unwrap_or_else(|poisoned| poisoned.into_inner()));
+        while tasks.join_next().await.is_some() {}
+    }
+}
+
+// One logged POST to a webhook. No foreign key, deliveries for deleted photos stay visible.
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct WebhookDelivery {
+    pub delivery_id: i32,
+    pub photo_id: i32,
+    pub event: String,
+    pub url: String,
+    // 1 for the first try of a delivery, counting up through its retries
+    pub attempt: i32,
+    pub status: Option<i16>,
+    pub error: Option<String>,
+    pub created_at: NaiveDateTime,
+}
+
+impl WebhookDelivery {
+    // Function to log one attempt
+    pub async fn record(
+        pool: &PgPool,
+        photo_id: i32,
+        event: &str,
+        url: &str,
+        attempt: i32,
+        outcome: &DeliveryAttempt,
+    ) -> Result<(), sqlx::Error> {
+        let query = "INSERT INTO webhook_deliveries (photo_id, event, url, attempt, status, error, created_at)
+            VALUES ($1, $2, $3, $4, $5, $6, $7)";
+        sqlx::query(query)
+            .bind(photo_id)
+            .bind(event)
+            .bind(url)
+            .bind(attempt)
+            .bind(outcome.status.map(|status| status as i16))
+            .bind(&outcome.error)
+            .bind(outcome.attempted_at)
+            .execute(pool)
+            .await?;
+
+        Ok(())
+    }
+
+    // Function to get the logged deliveries for a photo, oldest first
+    pub async fn list_for_photo(pool: &PgPool, photo_id: i32) -> Result<Vec<WebhookDelivery>, sqlx::Error> {
+        let query = "SELECT delivery_id, photo_id, event, url, attempt, status, error, created_at
+            FROM webhook_deliveries WHERE photo_id = $1 ORDER BY delivery_id";
+        sqlx::query_as::<_, WebhookDelivery>(query)
+            .bind(photo_id)
+            .fetch_all(pool)
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDate;
+
+    // never connects, command hooks don't use the database
+    fn pool() -> PgPool {
+        PgPool::connect_lazy("postgres://localhost/unused").unwrap()
+    }
+
+    fn photo() -> Photo {
+        Photo {
+            photo_id: 7,
+            file_name: "beach.jpg".to_string(),
+            original_file_name: Some("beach.jpg".to_string()),
+            original_path: Some("/photos/beach.jpg".to_string()),
+            file_path: "/photos/beach.jpg".to_string(),
+            tags: vec!["beach".to_string()],
+            width: Some(640),
+            height: Some(480),
+            byte_size: Some(1024),
+            content_hash: None,
+            thumbnail_paths: None,
+            captured_at: None,
+            captured_at_utc: None,
+            camera_make: None,
+            camera_model: None,
+            exif_orientation: None,
+            gps_latitude: None,
+            gps_longitude: None,
+            duplicate_of: None,
+            quality: None,
+            locked: false,
+            created_at: NaiveDate::from_ymd_opt(2024, 1, 1).unwrap().and_hms_opt(0, 0, 0).unwrap(),
+        }
+    }
+
+    fn webhook(url: &str) -> Webhook {
+        Webhook {
+            webhook_id: 1,
+            url: url.to_string(),
+            secret: None,
+            events: EVENTS.iter().map(|event| event.to_string()).collect(),
+            enabled: true,
+            created_at: NaiveDate::from_ymd_opt(2024, 1, 1).unwrap().and_hms_opt(0, 0, 0).unwrap(),
+        }
+    }
+
+    #[test]
+    fn only_known_events_can_be_subscribed_to() {
+        assert_eq!(parse_event("photo.tagged"), Ok("photo.tagged".to_string()));
+        assert!(parse_event("photo.viewed").is_err());
+    }
+
+    #[tokio::test]
+    async fn command_receives_photo_as_env_and_json() {
+        let output = std::env::temp_dir().join(format!("image-index-ai-hook-{}", std::process::id()));
+        let hook = CommandHook {
+            command: format!("{{ echo \"$PHOTO_ID $PHOTO_PATH\"; cat; }} > '{}'", output.display()),
+        };
+
+        hook.after_ingest(&pool(), &photo()).await.unwrap();
+
+        let written = std::fs::read_to_string(&output).unwrap();
+        std::fs::remove_file(&output).unwrap();
+        let (env_line, json) = written.split_once('\n').unwrap();
+        assert_eq!(env_line, "7 /photos/beach.jpg");
+        let json: serde_json::Value = serde_json::from_str(json).unwrap();
+        assert_eq!(json["tags"], serde_json::json!(["beach"]));
+    }
+
+    #[tokio::test]
+    async fn failing_command_is_an_error() {
+        let hook = CommandHook { command: "echo 'nas offline' >&2; exit 3".to_string() };
+
+        let error = hook.after_ingest(&pool(), &photo()).await.unwrap_err();
+
+        assert!(error.to_string().contains("nas offline"), "{}", error);
+    }
+
+    #[tokio::test]
+    async fn webhook_is_signed_and_retried() {
+        use wiremock::matchers::{header, method};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(503))
+            .up_to_n_times(1)
+            .mount(&server)
+            .await;
+        let body = serde_json::to_vec(&json!({ "event": "photo.created", "photo": photo() })).unwrap();
+        let mut mac = Hmac::<Sha256>::new_from_slice(b"s3cret").unwrap();
+        mac.update(&body);
+        let signature = format!("sha256={}", HEXLOWER.encode(&mac.finalize().into_bytes()));
+        Mock::given(method("POST"))
+            .and(header("X-Signature-256", signature.as_str()))
+            .respond_with(ResponseTemplate::new(204))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let mut webhook = webhook(&server.uri());
+        webhook.secret = Some("s3cret".to_string());
+        let mut hook = WebhookHook::new();
+        hook.retry_delay = Duration::from_millis(10);
+
+        let attempts = hook.send(&webhook, "photo.created", &photo()).await.unwrap();
+        assert_eq!(attempts.iter().map(|attempt| attempt.status).collect::<Vec<_>>(), vec![Some(503), Some(204)]);
+        assert!(attempts[1].error.is_none());
+
+        hook.attempts = 1;
+        let attempts = hook.send(&webhook, "photo.deleted", &photo()).await.unwrap();
+        assert_eq!(attempts.len(), 1);
+        assert!(attempts[0].error.is_some());
+    }
+
+    #[test]
+    fn debug_output_hides_the_secret() {
+        let mut webhook = webhook("http://localhost/hook");
+        webhook.secret = Some("s3cret".to_string());
+
+        let debug = format!("{:?}", webhook);
+
+        assert!(!debug.contains("s3cret"), "{}", debug);
+        assert!(debug.contains("http://localhost/hook"), "{}", debug);
+    }
+}
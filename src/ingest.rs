@@ -0,0 +1,826 @@
+use std::collections::HashSet;
+use std::error::Error;
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Instant;
+
+use chrono_tz::Tz;
+use data_encoding::{BASE64, HEXLOWER};
+use futures::stream::{self, StreamExt, TryStreamExt};
+use image::codecs::jpeg::JpegEncoder;
+use image::{DynamicImage, ImageFormat, ImageOutputFormat};
+use sha2::{Digest, Sha256};
+use sqlx::PgPool;
+use walkdir::WalkDir;
+
+use crate::disk;
+use crate::events::{self, PhotoEvent};
+use crate::hooks::PostIngestHook;
+use crate::metadata::ExifMetadata;
+use crate::photo::{NewPhoto, Photo};
+use crate::provenance::TagProvenance;
+use crate::quality::Quality;
+use crate::tagger::Tagger;
+use crate::tagging;
+use crate::thumbnails::Thumbnails;
+use crate::trash::Trash;
+
+pub fn is_image_file(path: &Path) -> bool {
+    let extension = path
+        .extension()
+        .and_then(std::ffi::OsStr::to_str)
+        .unwrap_or_default()
+        .to_lowercase();
+
+    matches!(extension.as_str(), "png" | "jpg" | "jpeg" | "gif" | "bmp")
+}
+
+// Formats accepted for ingest, detected from the file's leading bytes
+pub fn detect_format(buffer: &[u8]) -> Option<ImageFormat> {
+    match image::guess_format(buffer) {
+        Ok(format @ (ImageFormat::Png | ImageFormat::Jpeg | ImageFormat::Gif | ImageFormat::Bmp)) => Some(format),
+        _ => None,
+    }
+}
+
+pub fn mime_type(format: ImageFormat) -> &'static str {
+    match format {
+        ImageFormat::Png => "image/png",
+        ImageFormat::Gif => "image/gif",
+        ImageFormat::Bmp => "image/bmp",
+        _ => "image/jpeg",
+    }
+}
+
+// The extension only preselects files, the content must be a supported image of the same format.
+// A `.png` holding a JPEG or a `.jpg` holding anything else is rejected.
+pub fn check_content(path: &Path, buffer: &[u8]) -> Result<ImageFormat, String> {
+    let format = detect_format(buffer).ok_or("content is not a supported image")?;
+    let extension = path
+        .extension()
+        .and_then(std::ffi::OsStr::to_str)
+        .unwrap_or_default()
+        .to_lowercase();
+    if !format.extensions_str().contains(&extension.as_str()) {
+        return Err(format!("content is {}, which does not match the .{} extension", mime_type(format), extension));
+    }
+    Ok(format)
+}
+
+// Size information captured at ingest, read from the image header only
+pub struct ImageInfo {
+    pub width: u32,
+    pub height: u32,
+    pub byte_size: u64,
+}
+
+pub fn image_info(buffer: &[u8]) -> Result<ImageInfo, Box<dyn Error>> {
+    let (width, height) = image::io::Reader::new(Cursor::new(buffer))
+        .with_guessed_format()?
+        .into_dimensions()?;
+
+    Ok(ImageInfo {
+        width,
+        height,
+        byte_size: buffer.len() as u64,
+    })
+}
+
+// Hex SHA-256 of the file contents, used to recognize an image that is already indexed
+pub fn content_hash(buffer: &[u8]) -> String {
+    HEXLOWER.encode(&Sha256::digest(buffer))
+}
+
+// Canonical format ingested images are transcoded to when normalization is enabled
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NormalizedFormat {
+    Jpeg { quality: u8 },
+    // Lossless; the `image` crate version in use has no WebP encoder
+    Png,
+}
+
+impl NormalizedFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            NormalizedFormat::Jpeg { .. } => "jpg",
+            NormalizedFormat::Png => "png",
+        }
+    }
+}
+
+// How transcoded files are named inside the output directory
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum NormalizedLayout {
+    // the sanitized original name, with a counter when it is taken
+    #[default]
+    Named,
+    // `ab/cd/<sha256>.<ext>` from the original's content hash, the name is kept in the database
+    Hashed,
+}
+
+// On-upload format normalization, configured through the environment (see `config`):
+//   NORMALIZE_FORMAT   jpeg | png (unset disables normalization)
+//   NORMALIZE_QUALITY  JPEG quality, 1-100 (default 85)
+//   NORMALIZED_DIR     where transcoded files are written (default ./normalized)
+//   NORMALIZED_LAYOUT  named | hashed, how the files are named there (default named)
+//   KEEP_ORIGINALS     set to false to delete originals once indexed (default true)
+#[derive(Debug)]
+pub struct Normalization {
+    pub format: NormalizedFormat,
+    pub output_dir: PathBuf,
+    pub keep_originals: bool,
+    pub layout: NormalizedLayout,
+}
+
+impl Normalization {
+    // Transcode the image in memory, returning the bytes `write` stores
+    pub fn transcode(&self, buffer: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+        let image = image::load_from_memory(buffer)?;
+        let mut output = Vec::new();
+        match self.format {
+            NormalizedFormat::Jpeg { quality } => {
+                // JPEG has no alpha channel
+                let image = DynamicImage::ImageRgb8(image.to_rgb8());
+                JpegEncoder::new_with_quality(&mut output, quality).encode_image(&image)?;
+            }
+            NormalizedFormat::Png => image.write_to(&mut output, ImageOutputFormat::Png)?,
+        }
+        Ok(output)
+    }
+
+    // Write a transcoded image of the file at `path` to the output directory, returning its path
+    // and whether the file was created by this call
+    pub fn write(&self, path: &Path, output: &[u8], content_hash: &str) -> Result<(PathBuf, bool), Box<dyn Error>> {
+        let target = match self.layout {
+            NormalizedLayout::Named => {
+                let stem = path.file_stem().map(|stem| stem.to_string_lossy()).unwrap_or_default();
+                self.unused_path(&sanitize_file_stem(&stem))
+            }
+            NormalizedLayout::Hashed => self.hashed_path(content_hash),
+        };
+        // a hashed file that exists already holds this very image, e.g. for a kept copy
+        if self.layout == NormalizedLayout::Hashed && target.exists() {
+            return Ok((target, false));
+        }
+        std::fs::create_dir_all(target.parent().unwrap_or(&self.output_dir))?;
+        disk::write_atomically(&target, output)?;
+
+        Ok((target, true))
+    }
+
+    // `<output_dir>/ab/cd/abcd....<ext>`, two levels keep directories small in large libraries
+    pub fn hashed_path(&self, content_hash: &str) -> PathBuf {
+        let prefix = |range: std::ops::Range<usize>| content_hash.get(range).unwrap_or("00");
+        self.output_dir
+            .join(prefix(0..2))
+            .join(prefix(2..4))
+            .join(format!("{}.{}", content_hash, self.format.extension()))
+    }
+
+    // Files from different folders can share a name, never overwrite an earlier one
+    fn unused_path(&self, stem: &str) -> PathBuf {
+        let extension = self.format.extension();
+        let mut target = self.output_dir.join(format!("{}.{}", stem, extension));
+        let mut counter = 1;
+        while target.exists() {
+            target = self.output_dir.join(format!("{}-{}.{}", stem, counter, extension));
+            counter += 1;
+        }
+        target
+    }
+
+    // Whether `path` is a transcoded copy written by this tool rather than a user's original
+    pub fn owns(&self, path: &Path) -> bool {
+        match (self.output_dir.canonicalize(), path.canonicalize()) {
+            (Ok(output_dir), Ok(path)) => path.starts_with(output_dir),
+            _ => false,
+        }
+    }
+}
+
+// Longest file stem written to disk, leaves room for a counter and extension under NAME_MAX
+pub const MAX_FILE_STEM_BYTES: usize = 200;
+
+// Make a file stem safe to join onto a directory: no separators or control characters (including
+// NUL), no leading dots (hidden files, `..`), bounded length, never empty
+pub fn sanitize_file_stem(stem: &str) -> String {
+    let cleaned: String = stem
+        .chars()
+        .filter(|c| !c.is_control() && !matches!(c, '/' | '\\'))
+        .collect();
+    let cleaned = cleaned.trim_start_matches(|c: char| c == '.' || c.is_whitespace());
+
+    let mut end = cleaned.len().min(MAX_FILE_STEM_BYTES);
+    while !cleaned.is_char_boundary(end) {
+        end -= 1;
+    }
+    let cleaned = cleaned[..end].trim_end();
+
+    if cleaned.is_empty() {
+        "image".to_string()
+    } else {
+        cleaned.to_string()
+    }
+}
+
+// What to do with an image whose content is already indexed, from INGEST_DUPLICATES (WATCH_DUPLICATES
+// for watched folders) or a `--duplicates <policy>` given to the command. Whatever the policy, a
+// file that is already indexed from the same path is skipped, so scanning a folder again changes
+// nothing.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum DuplicatePolicy {
+    // leave the existing photo alone and report the image as skipped
+    #[default]
+    Skip,
+    // report the image as failed, for sources that should never send duplicates
+    Reject,
+    // tag the image again and point the existing photo at it, keeping its id, links and events
+    Replace,
+    // index the image as well, as a copy pointing at the existing photo
+    KeepBoth,
+}
+
+impl FromStr for DuplicatePolicy {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "skip" => Ok(DuplicatePolicy::Skip),
+            "reject" => Ok(DuplicatePolicy::Reject),
+            "replace" => Ok(DuplicatePolicy::Replace),
+            "keep-both" => Ok(DuplicatePolicy::KeepBoth),
+            other => Err(format!(
+                "unknown duplicate policy '{}', expected skip, reject, replace or keep-both",
+                other
+            )),
+        }
+    }
+}
+
+// Optional processing steps applied to every ingested image
+#[derive(Debug, Default)]
+pub struct IngestOptions {
+    pub normalization: Option<Normalization>,
+    pub thumbnails: Option<Thumbnails>,
+    // How many images are tagged at the same time, from INGEST_CONCURRENCY (0 is treated as 1)
+    pub concurrency: usize,
+    // Run in order after each successful ingest
+    pub hooks: Vec<Box<dyn PostIngestHook>>,
+    // Time zone of the camera clock for photos whose EXIF has no UTC offset, from HOME_TIMEZONE
+    pub home_timezone: Option<Tz>,
+    pub duplicates: DuplicatePolicy,
+    // Ingest stops once less than this is free where it writes, from INGEST_MIN_FREE_MB
+    pub min_free_bytes: Option<u64>,
+    // Where removed files go instead of being deleted, see `Trash`
+    pub trash: Option<Trash>,
+}
+
+impl IngestOptions {
+    // Error when INGEST_MIN_FREE_MB is set and the scanned folder or an output directory is
+    // running out of space, so a full disk never leaves half-written copies or thumbnails behind
+    fn check_free_space(&self, directory: &Path) -> Result<(), String> {
+        let min_free_bytes = match self.min_free_bytes {
+            Some(min_free_bytes) => min_free_bytes,
+            None => return Ok(()),
+        };
+        let output_dirs = self
+            .normalization
+            .as_ref()
+            .map(|normalization| normalization.output_dir.as_path())
+            .into_iter()
+            .chain(self.thumbnails.as_ref().map(|thumbnails| thumbnails.output_dir.as_path()));
+        disk::check_free_space(std::iter::once(directory).chain(output_dirs), min_free_bytes)
+    }
+
+    // Whether `path` is a file this tool wrote (a transcoded copy or a thumbnail) or trashed
+    pub fn owns(&self, path: &Path) -> bool {
+        self.normalization.as_ref().is_some_and(|normalization| normalization.owns(path))
+            || self.thumbnails.as_ref().is_some_and(|thumbnails| thumbnails.owns(path))
+            || self.trash.as_ref().is_some_and(|trash| trash.owns(path))
+    }
+
+    // Move a file to the trash when one is configured, delete it otherwise
+    pub fn remove_file(&self, path: &Path) -> std::io::Result<()> {
+        match &self.trash {
+            Some(trash) => trash.remove(path).map(|_| ()),
+            None => std::fs::remove_file(path),
+        }
+    }
+}
+
+// Outcome of an upload run, one entry per image found
+#[derive(Debug, Default)]
+pub struct IngestReport {
+    pub added: Vec<(PathBuf, i32)>,
+    pub skipped: Vec<(PathBuf, String)>,
+    pub failed: Vec<(PathBuf, String)>,
+}
+
+enum IngestOutcome {
+    Added(i32),
+    Skipped(String),
+}
+
+// Walk `directory`, tag every image with the vision model and add it to the index. Up to
+// `options.concurrency` images are processed at once; an image that fails is reported and the
+// run continues.
+pub async fn upload_photos(
+    pool: &PgPool,
+    tagger: &dyn Tagger,
+    options: &IngestOptions,
+    directory: &str,
+) -> Result<IngestReport, Box<dyn Error>> {
+    options.check_free_space(Path::new(directory))?;
+
+    // Output directories may live inside the scanned folder, never index our own files
+    let entries = WalkDir::new(directory)
+        .into_iter()
+        .filter_entry(|entry| !(entry.file_type().is_dir() && options.owns(entry.path())));
+
+    let mut paths = Vec::new();
+    for entry in entries {
+        let entry = entry?;
+        if entry.path().is_file() && is_image_file(entry.path()) {
+            paths.push(entry.into_path());
+        }
+    }
+
+    Ok(ingest_paths(pool, tagger, options, paths).await)
+}
+
+// Tag and index the given image files, up to `options.concurrency` at once. Failures are
+// reported per image.
+pub async fn ingest_paths(
+    pool: &PgPool,
+    tagger: &dyn Tagger,
+    options: &IngestOptions,
+    paths: Vec<PathBuf>,
+) -> IngestReport {
+    ingest_paths_with(pool, tagger, options, options.duplicates, paths).await
+}
+
+// `ingest_paths` with another duplicate policy than the options', for sources with a policy of
+// their own such as watched folders
+pub async fn ingest_paths_with(
+    pool: &PgPool,
+    tagger: &dyn Tagger,
+    options: &IngestOptions,
+    duplicates: DuplicatePolicy,
+    paths: Vec<PathBuf>,
+) -> IngestReport {
+    // The images are interleaved on this task rather than spawned, so synchronous steps such as
+    // picking an unused output file name never race each other
+    let mut outcomes = stream::iter(paths)
+        .map(|path| async move {
+            let outcome = ingest_file(pool, tagger, options, duplicates, &path).await;
+            (path, outcome)
+        })
+        .buffer_unordered(options.concurrency.max(1));
+
+    let mut report = IngestReport::default();
+    while let Some((path, outcome)) = outcomes.next().await {
+        match outcome {
+            Ok(IngestOutcome::Added(photo_id)) => report.added.push((path, photo_id)),
+            Ok(IngestOutcome::Skipped(reason)) => {
+                println!("Skipping {}: {}", path.display(), reason);
+                report.skipped.push((path, reason));
+            }
+            Err(e) => {
+                println!("Failed to add {}: {}", path.display(), e);
+                report.failed.push((path, e.to_string()));
+            }
+        }
+    }
+    report
+}
+
+async fn ingest_file(
+    pool: &PgPool,
+    tagger: &dyn Tagger,
+    options: &IngestOptions,
+    duplicates: DuplicatePolicy,
+    original_path: &Path,
+) -> Result<IngestOutcome, Box<dyn Error>> {
+    let normalization = options.normalization.as_ref();
+
+    // Checked again per image, the disk can fill up during a long run
+    let directory = original_path.parent().unwrap_or(Path::new("."));
+    options.check_free_space(directory)?;
+
+    // Paths are stored as text, skip names that aren't valid UTF-8 instead of mangling them
+    if original_path.to_str().is_none() {
+        return Ok(IngestOutcome::Skipped("path is not valid UTF-8".to_string()));
+    }
+
+    let mut buffer = std::fs::read(original_path)?;
+    // a file that isn't what its name says is broken or disguised, not merely uninteresting
+    check_content(original_path, &buffer)?;
+    // canonical now, before normalization may delete the original
+    let source_path = original_path.canonicalize()?;
+    let source_path = source_path.to_str().ok_or("path is not valid UTF-8")?.to_string();
+    // Hash the original bytes so a re-upload is caught before any transcoding or tagging
+    let content_hash = content_hash(&buffer);
+    let mut replaces = None;
+    let mut duplicate_of = None;
+    if let Some(existing) = Photo::find_by_content_hash(pool, &content_hash).await? {
+        // this very file, e.g. the same folder scanned again: nothing to reject, replace or copy
+        let indexed = Photo::find_by_original_path(pool, &source_path).await?;
+        if let Some(indexed) = indexed.iter().find(|photo| photo.content_hash.as_deref() == Some(&content_hash)) {
+            return Ok(IngestOutcome::Skipped(format!("already indexed as photo #{}", indexed.photo_id)));
+        }
+        match duplicates {
+            DuplicatePolicy::Skip => {
+                return Ok(IngestOutcome::Skipped(format!("duplicate of photo #{}", existing.photo_id)));
+            }
+            DuplicatePolicy::Reject => return Err(format!("duplicate of photo #{}", existing.photo_id).into()),
+            DuplicatePolicy::Replace if existing.locked => {
+                return Err(format!("duplicate of photo #{}, which is locked", existing.photo_id).into());
+            }
+            DuplicatePolicy::Replace => replaces = Some(existing),
+            DuplicatePolicy::KeepBoth => duplicate_of = Some(existing.photo_id),
+        }
+    }
+
+    // Read EXIF from the original, transcoding does not carry it over
+    let exif = ExifMetadata::read(&buffer);
+
+    if let Some(normalization) = normalization {
+        buffer = normalization.transcode(&buffer)?;
+    }
+    let info = image_info(&buffer)?;
+    let image = image::load_from_memory(&buffer)?;
+    let quality = Quality::of(&image).score() as f32;
+    let base64_image = BASE64.encode(&buffer);
+    let tagging_started = Instant::now();
+    let (tags, response) = tagger.tag_image_with_response(&base64_image).await?;
+    let tagging_time = tagging_started.elapsed();
+
+    // Written only now that the image is tagged, and removed again if storing the photo fails, so
+    // a failed image leaves nothing in the output directory
+    let mut stored_path = original_path.to_path_buf();
+    let mut unstored = Unstored(None);
+    if let Some(normalization) = normalization {
+        let (target, created) = normalization.write(original_path, &buffer, &content_hash)?;
+        if created {
+            unstored.0 = Some(target.clone());
+        }
+        stored_path = target;
+    }
+    let path = stored_path.as_path();
+
+    let file_name = path
+        .file_name()
+        .and_then(std::ffi::OsStr::to_str)
+        .ok_or_else(|| format!("{} has no valid file name", path.display()))?;
+    let file_path = path.canonicalize()?;
+    let file_path = file_path
+        .to_str()
+        .ok_or_else(|| format!("{} is not valid UTF-8", file_path.display()))?;
+
+    let original_file_name = original_path
+        .file_name()
+        .and_then(std::ffi::OsStr::to_str)
+        .ok_or_else(|| format!("{} has no valid file name", original_path.display()))?;
+
+    let new_photo = NewPhoto {
+        file_name,
+        original_file_name,
+        original_path: &source_path,
+        file_path,
+        tags: tags.clone(),
+        info: &info,
+        exif: &exif,
+        captured_at_utc: exif.captured_at_utc(options.home_timezone),
+        content_hash: &content_hash,
+        duplicate_of,
+        quality,
+    };
+    let added = match &replaces {
+        // Only now that the image is tagged, a failure above leaves the existing photo as it was
+        Some(replaced) => {
+            if !Photo::replace(pool, replaced.photo_id, new_photo).await? {
+                return Err(format!("photo #{} was deleted or locked while replacing it", replaced.photo_id).into());
+            }
+            // the earlier transcoded copy, unless the replacement was written to the same path
+            // (hashed layout) or a kept copy shares it
+            let earlier = Path::new(&replaced.file_path);
+            if earlier != Path::new(file_path)
+                && options.owns(earlier)
+                && Photo::find_by_file_path(pool, &replaced.file_path).await?.is_empty()
+            {
+                options.remove_file(earlier)?;
+            }
+            Some(replaced.photo_id)
+        }
+        None => Photo::add_photo(pool, new_photo).await?,
+    };
+    let photo_id = match added {
+        Some(photo_id) => photo_id,
+        // Another ingest stored the same image since the check above
+        None => {
+            // a hashed file is the one the other ingest stored
+            if normalization.is_some_and(|normalization| normalization.layout == NormalizedLayout::Hashed) {
+                unstored.0 = None;
+            }
+            return Ok(IngestOutcome::Skipped("indexed concurrently".to_string()));
+        }
+    };
+    unstored.0 = None;
+
+    let uploaded = match (&replaces, duplicate_of) {
+        (Some(replaced), _) => format!("from {}, replacing {}", original_path.display(), replaced.file_path),
+        (_, Some(duplicate_of)) => format!("from {}, copy of photo #{}", original_path.display(), duplicate_of),
+        _ => format!("from {}", original_path.display()),
+    };
+    PhotoEvent::record(pool, photo_id, events::UPLOADED, Some(&uploaded)).await?;
+    let tagged = format!(
+        "{} tags in {} ms with model {}",
+        tags.len(),
+        tagging_time.as_millis(),
+        tagger.vision_model()
+    );
+    PhotoEvent::record(pool, photo_id, events::TAGGED, Some(&tagged)).await?;
+    TagProvenance::record(pool, photo_id, tagger.vision_model(), tagging::IMAGE_TAGGING_PROMPT, &response, &tags).await?;
+
+    if let Some(thumbnails) = &options.thumbnails {
+        let thumbnail_paths = thumbnails.generate(photo_id, &image)?;
+        Photo::set_thumbnail_paths(pool, photo_id, &thumbnail_paths).await?;
+
+        let thumbnailed = format!("sizes {:?}", thumbnails.sizes);
+        PhotoEvent::record(pool, photo_id, events::THUMBNAILED, Some(&thumbnailed)).await?;
+    }
+
+    if let Some(normalization) = normalization {
+        if !normalization.keep_originals {
+            options.remove_file(original_path)?;
+        }
+    }
+
+    println!("Added photo: {} ", file_name);
+
+    if !options.hooks.is_empty() {
+        let photo = Photo::find_by_id(pool, photo_id)
+            .await?
+            .ok_or_else(|| format!("photo #{} disappeared after ingest", photo_id))?;
+        for hook in &options.hooks {
+            if let Err(e) = hook.after_ingest(pool, &photo).await {
+                println!("Post-ingest hook failed for {}: {}", file_name, e);
+                let failed = format!("{:?}: {}", hook, e);
+                PhotoEvent::record(pool, photo_id, events::HOOK_FAILED, Some(&failed)).await?;
+            }
+        }
+    }
+
+    Ok(IngestOutcome::Added(photo_id))
+}
+
+// A transcoded file written for an image that is not stored yet, deleted when it is dropped
+// before the photo referring to it was stored
+struct Unstored(Option<PathBuf>);
+
+impl Drop for Unstored {
+    fn drop(&mut self) {
+        if let Some(path) = &self.0 {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+// Delete files in the output directories (transcoded copies, thumbnails, temporary files left by
+// a crash) that no photo refers to any more, returning their paths. With `dry_run` they are only
+// listed.
+pub async fn vacuum_orphans(pool: &PgPool, options: &IngestOptions, dry_run: bool) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+    let output_dirs: Vec<&Path> = options
+        .normalization
+        .as_ref()
+        .map(|normalization| normalization.output_dir.as_path())
+        .into_iter()
+        .chain(options.thumbnails.as_ref().map(|thumbnails| thumbnails.output_dir.as_path()))
+        .collect();
+    if output_dirs.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    // stored paths are canonical, so they compare directly against canonicalized files on disk
+    let mut referenced = HashSet::new();
+    let mut photos = Photo::stream_all(pool);
+    while let Some(photo) = photos.try_next().await? {
+        referenced.insert(PathBuf::from(photo.file_path));
+        referenced.extend(photo.thumbnail_paths.into_iter().flatten().map(PathBuf::from));
+    }
+
+    let mut orphans = Vec::new();
+    for output_dir in output_dirs {
+        if !output_dir.exists() {
+            continue;
+        }
+        for entry in WalkDir::new(output_dir) {
+            let entry = entry?;
+            if !entry.file_type().is_file() {
+                continue;
+            }
+            let path = entry.path().canonicalize()?;
+            if referenced.contains(&path) {
+                continue;
+            }
+            if !dry_run {
+                options.remove_file(&path)?;
+            }
+            orphans.push(path);
+        }
+    }
+    Ok(orphans)
+}
+
+// Move transcoded files into the hashed layout after switching NORMALIZED_LAYOUT to `hashed`,
+// updating the stored paths. Returns how many photos were moved. Originals in the user's own
+// folders and photos indexed before content hashes were kept stay where they are.
+pub async fn relocate_photos(pool: &PgPool, options: &IngestOptions) -> Result<usize, Box<dyn Error>> {
+    let normalization = match &options.normalization {
+        Some(normalization) if normalization.layout == NormalizedLayout::Hashed => normalization,
+        _ => return Err("relocating needs NORMALIZE_FORMAT set and NORMALIZED_LAYOUT=hashed".into()),
+    };
+
+    let photos: Vec<Photo> = Photo::stream_all(pool).try_collect().await?;
+    let mut relocated = 0;
+    for photo in photos {
+        let content_hash = match &photo.content_hash {
+            Some(content_hash) => content_hash,
+            None => continue,
+        };
+        if photo.locked {
+            println!("Leaving photo #{} at {}, it is locked", photo.photo_id, photo.file_path);
+            continue;
+        }
+        let current = Path::new(&photo.file_path);
+        let target = normalization.hashed_path(content_hash);
+        // moved already, possibly together with a copy sharing the file earlier in this run
+        if !current.exists() || target.canonicalize().is_ok_and(|target| target == current) || !normalization.owns(current) {
+            continue;
+        }
+
+        // a copy of the same image got there first, this file is redundant if it holds the same bytes
+        let redundant = target.exists();
+        if redundant && !disk::Fingerprint::of(current)?.matches(&target)? {
+            println!("Leaving photo #{} at {}, {} holds a different file", photo.photo_id, current.display(), target.display());
+            continue;
+        }
+        if !redundant {
+            std::fs::create_dir_all(target.parent().unwrap_or(&normalization.output_dir))?;
+            std::fs::rename(current, &target)?;
+        }
+        let target_path = target.canonicalize()?;
+        let target_path = target_path
+            .to_str()
+            .ok_or_else(|| format!("{} is not valid UTF-8", target_path.display()))?;
+        // kept copies sharing the file move with it
+        let moved = match Photo::move_file_path(pool, &photo.file_path, target_path).await {
+            Ok(moved) => moved,
+            Err(e) => {
+                if !redundant {
+                    std::fs::rename(&target, current)?;
+                }
+                return Err(e.into());
+            }
+        };
+        // a kept copy sharing the file is locked, or this photo was locked meanwhile
+        if moved == 0 {
+            if !redundant {
+                std::fs::rename(&target, current)?;
+            }
+            println!("Leaving photo #{} at {}, a photo stored there is locked", photo.photo_id, current.display());
+            continue;
+        }
+        if redundant {
+            options.remove_file(current)?;
+        }
+        relocated += moved as usize;
+    }
+    Ok(relocated)
+}
+
+// Remove a photo from the index, deleting (or trashing) its files only when they are copies this
+// tool wrote (transcoded files and thumbnails). Returns None when no photo has the given id, and
+// an error when the photo is locked.
+pub async fn delete_photo(
+    pool: &PgPool,
+    options: &IngestOptions,
+    photo_id: i32,
+) -> Result<Option<Photo>, Box<dyn Error>> {
+    let photo = match Photo::delete(pool, photo_id).await? {
+        Some(photo) => photo,
+        None => match Photo::find_by_id(pool, photo_id).await? {
+            Some(photo) if photo.locked => return Err(format!("photo #{} is locked, unlock it first", photo_id).into()),
+            _ => return Ok(None),
+        },
+    };
+    // kept copies share a hashed file with their original
+    let file_shared = !Photo::find_by_file_path(pool, &photo.file_path).await?.is_empty();
+
+    let thumbnail_paths = photo.thumbnail_paths.iter().flatten();
+    let file_path = Some(&photo.file_path).filter(|_| !file_shared);
+    for path in file_path.into_iter().chain(thumbnail_paths) {
+        let path = Path::new(path);
+        if !options.owns(path) {
+            continue;
+        }
+        match options.remove_file(path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+    }
+
+    // the photo's history is gone with it, a failing hook can only be reported (webhooks also
+    // keep their delivery log)
+    for hook in &options.hooks {
+        if let Err(e) = hook.after_delete(pool, &photo).await {
+            println!("Delete hook failed for photo #{}: {}", photo.photo_id, e);
+        }
+    }
+
+    Ok(Some(photo))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    #[test]
+    fn hashed_layout_nests_by_hash_prefix() {
+        let normalization = Normalization {
+            format: NormalizedFormat::Jpeg { quality: 85 },
+            output_dir: PathBuf::from("/library"),
+            keep_originals: true,
+            layout: NormalizedLayout::Hashed,
+        };
+
+        assert_eq!(normalization.hashed_path("abcdef0123"), Path::new("/library/ab/cd/abcdef0123.jpg"));
+    }
+
+    #[test]
+    fn parses_duplicate_policies() {
+        assert_eq!("keep-both".parse(), Ok(DuplicatePolicy::KeepBoth));
+        assert_eq!("replace".parse(), Ok(DuplicatePolicy::Replace));
+        assert!("overwrite".parse::<DuplicatePolicy>().unwrap_err().contains("keep-both"));
+    }
+
+    proptest! {
+        #[test]
+        fn sanitized_stems_are_safe_to_join(stem in prop_oneof![any::<String>(), "[./\\\\a-z\\x00 ]{0,16}"]) {
+            let sanitized = sanitize_file_stem(&stem);
+
+            prop_assert!(!sanitized.is_empty());
+            prop_assert!(sanitized.len() <= MAX_FILE_STEM_BYTES);
+            prop_assert!(!sanitized.starts_with('.'));
+            prop_assert!(!sanitized.chars().any(|c| c.is_control() || c == '/' || c == '\\'));
+            prop_assert_eq!(Path::new(&sanitized).components().count(), 1);
+        }
+
+        #[test]
+        fn long_unicode_stems_are_truncated_on_char_boundaries(stem in "[é☃𝄞a]{150,400}") {
+            let sanitized = sanitize_file_stem(&stem);
+
+            prop_assert!(sanitized.len() <= MAX_FILE_STEM_BYTES);
+            prop_assert!(stem.starts_with(&sanitized));
+        }
+
+        #[test]
+        fn image_info_rejects_arbitrary_bytes_without_panicking(buffer in proptest::collection::vec(any::<u8>(), 0..512)) {
+            let _ = image_info(&buffer);
+        }
+
+        #[test]
+        fn image_info_survives_truncated_headers(len in 0usize..64) {
+            // PNG signature plus the start of an IHDR chunk, cut at every length
+            let header = b"\x89PNG\r\n\x1a\n\x00\x00\x00\x0dIHDR\x00\x00\x01\x00\x00\x00\x00\x80\x08\x02\x00\x00\x00";
+            let _ = image_info(&header[..len.min(header.len())]);
+        }
+    }
+
+    fn encoded(format: ImageOutputFormat) -> Vec<u8> {
+        let mut buffer = Vec::new();
+        DynamicImage::new_rgb8(4, 4).write_to(&mut buffer, format).unwrap();
+        buffer
+    }
+
+    #[test]
+    fn content_must_match_the_extension() {
+        let png = encoded(ImageOutputFormat::Png);
+        let jpeg = encoded(ImageOutputFormat::Jpeg(80));
+
+        assert_eq!(check_content(Path::new("a.PNG"), &png), Ok(ImageFormat::Png));
+        assert_eq!(check_content(Path::new("a.jpeg"), &jpeg), Ok(ImageFormat::Jpeg));
+        assert!(check_content(Path::new("a.png"), &jpeg).unwrap_err().contains("image/jpeg"));
+        assert!(check_content(Path::new("a.jpg"), b"<script>alert(1)</script>").is_err());
+    }
+
+    #[test]
+    fn traversal_attempts_stay_in_the_output_directory() {
+        assert_eq!(sanitize_file_stem("../../etc/passwd"), "etcpasswd");
+        assert_eq!(sanitize_file_stem(".."), "image");
+        assert_eq!(sanitize_file_stem("a\0b"), "ab");
+        assert_eq!(sanitize_file_stem("Ürlaub am Meer"), "Ürlaub am Meer");
+    }
+}
@@ -0,0 +1,61 @@
+//! Index a folder of images with tags from a vision model and search them with natural language.
+//!
+//! The `image-index-ai` binary is a thin wrapper over this crate; the same building blocks can be
+//! used to drive ingestion or search from another application:
+//!
+//! ```no_run
+//! # async fn run() -> Result<(), Box<dyn std::error::Error>> {
+//! use image_index_ai::{create_photos_table, upload_photos, IngestOptions, OllamaClient};
+//!
+//! let pool = sqlx::PgPool::connect("postgres://localhost/photos").await?;
+//! create_photos_table(&pool).await?;
+//! upload_photos(&pool, &OllamaClient::new("http://localhost:11434"), &IngestOptions::default(), "./images").await?;
+//! # Ok(())
+//! # }
+//! ```
+
+pub mod best_shot;
+pub mod breaker;
+pub mod config;
+pub mod db;
+pub mod disk;
+pub mod events;
+pub mod expansion;
+pub mod export;
+pub mod hooks;
+pub mod ingest;
+pub mod limiter;
+pub mod link;
+pub mod metadata;
+pub mod ollama;
+pub mod openai;
+pub mod photo;
+pub mod provenance;
+pub mod quality;
+pub mod query;
+pub mod reindex;
+pub mod search;
+pub mod tag;
+pub mod tagger;
+pub mod tagging;
+pub mod thumbnails;
+pub mod trash;
+pub mod watch;
+
+pub use best_shot::{best_shots, ShotGroup};
+pub use config::Config;
+pub use db::create_photos_table;
+pub use events::PhotoEvent;
+pub use export::{export_archive, export_photos, import_archive, import_photos, Rebase};
+pub use hooks::{CommandHook, PostIngestHook, Webhook, WebhookDelivery, WebhookHook};
+pub use ingest::{delete_photo, ingest_paths, relocate_photos, upload_photos, vacuum_orphans, IngestOptions, IngestReport, Normalization};
+pub use link::{LinkKind, PhotoLink};
+pub use ollama::OllamaClient;
+pub use openai::OpenAiClient;
+pub use photo::Photo;
+pub use provenance::TagProvenance;
+pub use reindex::{drift_report, reindex_photos, ReindexFilter};
+pub use search::{search_photos_by_tags, SearchFilters};
+pub use tag::TagCount;
+pub use tagger::Tagger;
+pub use watch::{watch_folders, Storage, StoreMode, WatchOptions};
@@ -0,0 +1,155 @@
+use std::error::Error;
+use std::fmt;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use tokio::sync::{Semaphore, SemaphorePermit};
+
+use crate::tagger::Tagger;
+
+// Caps how many requests are in flight to the AI backend at once, with separate limits for vision
+// (image tagging) and text (query and related tags) calls, so concurrent ingests queue up here
+// instead of making the model server thrash. A call that waits longer than `queue_timeout` for its
+// turn fails.
+pub struct ConcurrencyLimit {
+    inner: Box<dyn Tagger>,
+    vision: Option<Semaphore>,
+    text: Option<Semaphore>,
+    queue_timeout: Option<Duration>,
+}
+
+impl ConcurrencyLimit {
+    pub fn new(inner: Box<dyn Tagger>, vision: Option<usize>, text: Option<usize>, queue_timeout: Option<Duration>) -> Self {
+        ConcurrencyLimit {
+            inner,
+            vision: vision.map(|permits| Semaphore::new(permits.max(1))),
+            text: text.map(|permits| Semaphore::new(permits.max(1))),
+            queue_timeout,
+        }
+    }
+
+    async fn acquire<'a>(&self, semaphore: &'a Option<Semaphore>) -> Result<Option<SemaphorePermit<'a>>, Box<dyn Error>> {
+        let semaphore = match semaphore {
+            Some(semaphore) => semaphore,
+            None => return Ok(None),
+        };
+        let permit = match self.queue_timeout {
+            Some(queue_timeout) => tokio::time::timeout(queue_timeout, semaphore.acquire())
+                .await
+                .map_err(|_| QueueTimeout { waited: queue_timeout })?,
+            None => semaphore.acquire().await,
+        };
+        Ok(Some(permit?))
+    }
+}
+
+// A call that gave up waiting for its turn. The backend itself was never asked, so this says
+// nothing about its health.
+#[derive(Debug)]
+pub struct QueueTimeout {
+    pub waited: Duration,
+}
+
+impl fmt::Display for QueueTimeout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "gave up after waiting {:?} for a free AI backend slot", self.waited)
+    }
+}
+
+impl Error for QueueTimeout {}
+
+#[async_trait]
+impl Tagger for ConcurrencyLimit {
+    fn vision_model(&self) -> &str {
+        self.inner.vision_model()
+    }
+
+    fn text_model(&self) -> &str {
+        self.inner.text_model()
+    }
+
+    async fn tag_image(&self, base64_image: &str) -> Result<Vec<String>, Box<dyn Error>> {
+        let _permit = self.acquire(&self.vision).await?;
+        self.inner.tag_image(base64_image).await
+    }
+
+    async fn tag_image_with_response(&self, base64_image: &str) -> Result<(Vec<String>, String), Box<dyn Error>> {
+        let _permit = self.acquire(&self.vision).await?;
+        self.inner.tag_image_with_response(base64_image).await
+    }
+
+    async fn tags_for_query(&self, query: &str) -> Result<Vec<String>, Box<dyn Error>> {
+        let _permit = self.acquire(&self.text).await?;
+        self.inner.tags_for_query(query).await
+    }
+
+    async fn related_tags(&self, tag: &str) -> Result<Vec<String>, Box<dyn Error>> {
+        let _permit = self.acquire(&self.text).await?;
+        self.inner.related_tags(tag).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::future::join_all;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    // Takes 20 ms per call and records the most calls it saw at once
+    #[derive(Default)]
+    struct SlowTagger {
+        in_flight: AtomicUsize,
+        max_in_flight: Arc<AtomicUsize>,
+    }
+
+    #[async_trait]
+    impl Tagger for SlowTagger {
+        fn vision_model(&self) -> &str {
+            "slow"
+        }
+
+        fn text_model(&self) -> &str {
+            "slow"
+        }
+
+        async fn tag_image(&self, _base64_image: &str) -> Result<Vec<String>, Box<dyn Error>> {
+            let in_flight = self.in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+            self.max_in_flight.fetch_max(in_flight, Ordering::SeqCst);
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            self.in_flight.fetch_sub(1, Ordering::SeqCst);
+            Ok(Vec::new())
+        }
+
+        async fn tags_for_query(&self, query: &str) -> Result<Vec<String>, Box<dyn Error>> {
+            self.tag_image(query).await
+        }
+
+        async fn related_tags(&self, tag: &str) -> Result<Vec<String>, Box<dyn Error>> {
+            self.tag_image(tag).await
+        }
+    }
+
+    #[tokio::test]
+    async fn vision_calls_are_capped() {
+        let max_in_flight = Arc::new(AtomicUsize::new(0));
+        let inner = SlowTagger { max_in_flight: max_in_flight.clone(), ..SlowTagger::default() };
+        let limit = ConcurrencyLimit::new(Box::new(inner), Some(2), None, None);
+
+        let results = join_all((0..6).map(|_| limit.tag_image(""))).await;
+
+        assert!(results.iter().all(Result::is_ok));
+        assert_eq!(max_in_flight.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn waiting_longer_than_the_queue_timeout_fails() {
+        let limit = ConcurrencyLimit::new(Box::new(SlowTagger::default()), None, Some(1), Some(Duration::from_millis(5)));
+
+        let results = join_all((0..2).map(|_| limit.tags_for_query(""))).await;
+
+        assert!(results[0].is_ok());
+        let error = results[1].as_ref().unwrap_err();
+        assert!(error.to_string().contains("waiting"), "{}", error);
+    }
+}
@@ -0,0 +1,122 @@
+use std::fmt;
+use std::str::FromStr;
+
+use chrono::NaiveDateTime;
+use serde::Serialize;
+use sqlx::PgPool;
+
+// How a photo derives from another one. Links point from the derived photo to its source; a
+// RAW+JPEG pair has no direction, the pair is stored once whichever way it was given.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LinkKind {
+    EditOf,
+    CropOf,
+    RawPair,
+}
+
+impl LinkKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            LinkKind::EditOf => "edit-of",
+            LinkKind::CropOf => "crop-of",
+            LinkKind::RawPair => "raw-pair",
+        }
+    }
+}
+
+impl fmt::Display for LinkKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for LinkKind {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "edit-of" => Ok(LinkKind::EditOf),
+            "crop-of" => Ok(LinkKind::CropOf),
+            "raw-pair" => Ok(LinkKind::RawPair),
+            other => Err(format!("unknown link kind '{}', expected edit-of, crop-of or raw-pair", other)),
+        }
+    }
+}
+
+// A relationship between two photos, removed with either of them
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct PhotoLink {
+    pub link_id: i32,
+    pub from_photo_id: i32,
+    pub to_photo_id: i32,
+    pub kind: String,
+    pub created_at: NaiveDateTime,
+}
+
+impl PhotoLink {
+    // Function to link `from_photo_id` to `to_photo_id`, e.g. a crop to the photo it was cut from.
+    // Linking the same pair twice keeps the first link.
+    pub async fn create(pool: &PgPool, from_photo_id: i32, kind: LinkKind, to_photo_id: i32) -> Result<Option<PhotoLink>, sqlx::Error> {
+        let (from_photo_id, to_photo_id) = match kind {
+            // one order for both directions, so the unique index catches the reversed pair too
+            LinkKind::RawPair => (from_photo_id.min(to_photo_id), from_photo_id.max(to_photo_id)),
+            _ => (from_photo_id, to_photo_id),
+        };
+        let query = "INSERT INTO photo_links (from_photo_id, to_photo_id, kind) VALUES ($1, $2, $3)
+            ON CONFLICT (from_photo_id, to_photo_id, kind) DO NOTHING
+            RETURNING link_id, from_photo_id, to_photo_id, kind, created_at";
+        sqlx::query_as::<_, PhotoLink>(query)
+            .bind(from_photo_id)
+            .bind(to_photo_id)
+            .bind(kind.as_str())
+            .fetch_optional(pool)
+            .await
+    }
+
+    // Function to remove a link, returning it if it existed
+    pub async fn delete(pool: &PgPool, link_id: i32) -> Result<Option<PhotoLink>, sqlx::Error> {
+        let query = "DELETE FROM photo_links WHERE link_id = $1 RETURNING link_id, from_photo_id, to_photo_id, kind, created_at";
+        sqlx::query_as::<_, PhotoLink>(query)
+            .bind(link_id)
+            .fetch_optional(pool)
+            .await
+    }
+
+    // Function to list the links of every photo connected to `photo_id`, directly or through
+    // other links, e.g. the RAW file, the JPEG from the camera and a crop of that JPEG
+    pub async fn list_group(pool: &PgPool, photo_id: i32) -> Result<Vec<PhotoLink>, sqlx::Error> {
+        let query = "SELECT link_id, from_photo_id, to_photo_id, kind, created_at FROM photo_links
+            WHERE from_photo_id = ANY($1) ORDER BY link_id";
+        sqlx::query_as::<_, PhotoLink>(query)
+            .bind(PhotoLink::group(pool, photo_id).await?)
+            .fetch_all(pool)
+            .await
+    }
+
+    // Function to get the ids of all photos connected to `photo_id` through links in either
+    // direction, including `photo_id` itself
+    pub async fn group(pool: &PgPool, photo_id: i32) -> Result<Vec<i32>, sqlx::Error> {
+        // UNION rather than UNION ALL stops at photos already reached, links may form cycles
+        let query = "WITH RECURSIVE linked (photo_id) AS (
+                SELECT $1::integer
+                UNION
+                SELECT CASE WHEN l.from_photo_id = linked.photo_id THEN l.to_photo_id ELSE l.from_photo_id END
+                FROM photo_links l JOIN linked ON linked.photo_id IN (l.from_photo_id, l.to_photo_id)
+            )
+            SELECT photo_id FROM linked ORDER BY photo_id";
+        sqlx::query_scalar(query).bind(photo_id).fetch_all(pool).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn kinds_round_trip_through_their_names() {
+        for kind in [LinkKind::EditOf, LinkKind::CropOf, LinkKind::RawPair] {
+            assert_eq!(kind.as_str().parse::<LinkKind>(), Ok(kind));
+        }
+        assert!("duplicate".parse::<LinkKind>().unwrap_err().contains("edit-of"));
+    }
+}
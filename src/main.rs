@@ -1,251 +1,639 @@
-
-use std::env;
 use std::error::Error;
-use std::fs::File;
-use std::io::{Read, BufReader};
-use std::path::{Path};
-use chrono::NaiveDateTime;
-use walkdir::WalkDir;
-use reqwest::Client;
-use serde_json::json;
-use data_encoding::BASE64;
-use sqlx::PgPool;
-
-
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn Error>> {
-    // Connect to the database
-    let pool = PgPool::connect(&env::var("DATABASE_URL")?).await?;
-
-    // Create photos table
-    create_photos_table(&pool).await?;
-
-    // UPLOAD FLOW
-    // get folder path from command line arguments
-    let folder_path = std::env::args().nth(1).unwrap_or_else(|| "./images".to_string());
-    // Upload photos to the database
-    upload_photos(&pool, &folder_path).await?;
-
-    /*
-    // SEARCH FLOW
-    // Search photos by tags
-    let query = "Give me pictures where I'm by the beach with my friends.";
-    let photos = search_photos_by_tags(&pool, query).await?;
-    for photo in photos {
-        println!("Photo: {:?}", photo.file_path);
-    }
-    */
+use std::path::PathBuf;
+use std::time::Duration;
 
+use clap::{Args, Parser, Subcommand};
+use sqlx::PgPool;
 
-    Ok(())
+use image_index_ai::best_shot;
+use image_index_ai::hooks;
+use image_index_ai::ingest::DuplicatePolicy;
+use image_index_ai::search::{self, AspectRatio, Orientation, Sort};
+use image_index_ai::{
+    best_shots, create_photos_table, delete_photo, drift_report, export_archive, export_photos, import_archive, import_photos, reindex_photos, relocate_photos, search_photos_by_tags, upload_photos,
+    vacuum_orphans, watch_folders, Config, LinkKind, Photo, PhotoEvent, PhotoLink, Rebase, ReindexFilter, SearchFilters, TagCount, TagProvenance,
+    Webhook, WebhookDelivery,
+};
+
+/// Index a folder of images with tags from a vision model and search them with natural language.
+///
+/// Settings come from the environment or a .env file, see .env.example.
+#[derive(Parser)]
+#[command(version, args_conflicts_with_subcommands = true)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    // without a command, index a folder as `ingest` does
+    #[command(flatten)]
+    ingest: IngestArgs,
 }
 
-fn is_image_file(path: &Path) -> bool {
-    let extension = path
-        .extension()
-        .and_then(std::ffi::OsStr::to_str)
-        .unwrap_or_default()
-        .to_lowercase();
-
-    matches!(extension.as_str(), "png" | "jpg" | "jpeg" | "gif" | "bmp")
+#[derive(Subcommand)]
+enum Command {
+    /// Index the images in a folder
+    Ingest(IngestArgs),
+    /// Find photos matching a natural-language or boolean query
+    Search(SearchArgs),
+    /// List the photos sharing the most tags with a photo
+    Similar {
+        photo_id: i32,
+        #[arg(long, default_value_t = 20)]
+        limit: i64,
+    },
+    /// Record that one photo derives from another
+    Link {
+        from_photo_id: i32,
+        /// edit-of, crop-of or raw-pair
+        kind: LinkKind,
+        to_photo_id: i32,
+    },
+    /// Remove a link by the id `link` and `links` print
+    Unlink { link_id: i32 },
+    /// List every photo connected to a photo and how
+    Links { photo_id: i32 },
+    /// Pick the best photo of each capture burst or linked group
+    BestShots {
+        /// Longest time between two frames of a burst, in seconds [default: 2]
+        #[arg(long, value_parser = parse_seconds)]
+        gap: Option<Duration>,
+    },
+    /// Write every photo, then every link, event and provenance record, as one JSON object per
+    /// line to stdout
+    Export {
+        /// Write a tar archive of the index and the image files instead
+        #[arg(long)]
+        archive: bool,
+    },
+    /// Restore an export on this database
+    Import {
+        file: PathBuf,
+        /// Where the image folders live now, e.g. /srv/photos:/data/photos
+        #[arg(long, value_parser = Rebase::parse)]
+        rebase: Option<Rebase>,
+        /// Restore an archive from `export --archive`, writing its image files to this folder
+        #[arg(long, conflicts_with = "rebase")]
+        restore_to: Option<PathBuf>,
+    },
+    /// Print all metadata of a photo
+    Show { photo_id: i32 },
+    /// List distinct tags with the number of photos using them
+    Tags {
+        /// Only tags starting with this, ignoring case and accents
+        #[arg(long)]
+        prefix: Option<String>,
+        /// Hide tags used by fewer photos
+        #[arg(long, default_value_t = 1)]
+        min_count: i64,
+    },
+    /// Tell which SHA-256 hashes are already indexed
+    Check {
+        #[arg(required = true)]
+        content_hashes: Vec<String>,
+    },
+    /// Print the processing timeline of a photo
+    Events { photo_id: i32 },
+    /// Manage the webhooks photo lifecycle events are posted to
+    #[command(subcommand)]
+    Webhook(WebhookCommand),
+    /// Show every webhook delivery made for a photo, deleted photos included
+    Deliveries { photo_id: i32 },
+    /// Tag indexed photos again with the current model
+    Reindex {
+        #[arg(long)]
+        from_id: Option<i32>,
+        #[arg(long)]
+        to_id: Option<i32>,
+        /// Only photos without tags
+        #[arg(long)]
+        missing_tags: bool,
+    },
+    /// Compare the stored tags of a random sample with the current model's
+    Drift {
+        #[arg(long, default_value_t = 20)]
+        sample: i64,
+    },
+    /// List which model produced a photo's tags, or check the whole record
+    Provenance {
+        #[arg(required_unless_present = "verify")]
+        photo_id: Option<i32>,
+        /// Check the hash chain of every record instead
+        #[arg(long, conflicts_with = "photo_id")]
+        verify: bool,
+    },
+    /// Remove a photo from the index
+    Delete { photo_id: i32 },
+    /// Protect a photo from reindex, relocate, replacing duplicates and delete
+    Lock { photo_id: i32 },
+    /// Let automated processes change a locked photo again
+    Unlock { photo_id: i32 },
+    /// Delete transcoded copies and thumbnails no photo refers to
+    VacuumOrphans {
+        /// Only list the files
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Index folders, then keep indexing images as they are dropped in
+    Watch {
+        /// Folders to watch, WATCH_DIRS when none are given
+        dirs: Vec<PathBuf>,
+        /// What to do with images already indexed, overriding WATCH_DUPLICATES and
+        /// INGEST_DUPLICATES: skip, reject, replace or keep-both
+        #[arg(long)]
+        duplicates: Option<DuplicatePolicy>,
+    },
+    /// Move transcoded files into the configured NORMALIZED_LAYOUT
+    Relocate,
+    /// Empty trash folders older than TRASH_RETENTION_DAYS
+    PurgeTrash,
 }
 
-async fn image_to_base64(path: &Path) -> Result<String, Box<dyn Error>> {
-    let file = File::open(path)?;
-    let mut reader = BufReader::new(file);
-    let mut buffer = Vec::new();
-
-    reader.read_to_end(&mut buffer).expect("Failed to read file");
-
-    let base64_encoded = BASE64.encode(&buffer);
-    Ok(base64_encoded)
+#[derive(Subcommand)]
+enum WebhookCommand {
+    /// Register a URL to POST events to
+    Add {
+        url: String,
+        /// Sign every body with HMAC-SHA256 using this secret
+        #[arg(long)]
+        secret: Option<String>,
+        /// Events to send, comma-separated: photo.created, photo.tagged, photo.deleted [default: all]
+        #[arg(long, value_delimiter = ',', value_parser = hooks::parse_event)]
+        events: Vec<String>,
+    },
+    /// List the registered webhooks
+    List,
+    /// Remove a webhook by the id `webhook list` prints
+    Remove { webhook_id: i32 },
+    /// Send events to a disabled webhook again
+    Enable { webhook_id: i32 },
+    /// Stop sending events to a webhook without removing it
+    Disable { webhook_id: i32 },
 }
 
-async fn create_photos_table(pool: &PgPool) -> Result<(), sqlx::Error> {
-    let query = r#"
-        CREATE TABLE IF NOT EXISTS photos (
-            photo_id SERIAL PRIMARY KEY,
-            file_name TEXT NOT NULL,
-            file_path TEXT NOT NULL,
-            tags TEXT[],
-            created_at TIMESTAMP DEFAULT NOW()
-        )
-    "#;
-
-    sqlx::query(query)
-        .execute(pool)
-        .await?;
+#[derive(Args)]
+struct IngestArgs {
+    /// Folder to index
+    #[arg(default_value = "./images")]
+    folder: String,
+    /// What to do with images already indexed, overriding INGEST_DUPLICATES: skip, reject,
+    /// replace or keep-both
+    #[arg(long)]
+    duplicates: Option<DuplicatePolicy>,
+}
 
-    Ok(())
+#[derive(Args)]
+struct SearchArgs {
+    /// Natural-language query, or tags combined with AND, OR and NOT
+    query: String,
+    /// Smallest width in pixels
+    #[arg(long)]
+    min_width: Option<u32>,
+    /// Smallest height in pixels
+    #[arg(long)]
+    min_height: Option<u32>,
+    /// Smallest file size in bytes
+    #[arg(long)]
+    min_bytes: Option<u64>,
+    /// Largest file size in bytes
+    #[arg(long)]
+    max_bytes: Option<u64>,
+    /// portrait, landscape or square
+    #[arg(long)]
+    orientation: Option<Orientation>,
+    /// e.g. 16:9, optionally with a tolerance as 16:9±0.05
+    #[arg(long)]
+    aspect_ratio: Option<AspectRatio>,
+    /// Earliest EXIF capture date, YYYY-MM-DD, in UTC where the time zone is known
+    #[arg(long, value_parser = search::parse_date)]
+    captured_after: Option<chrono::NaiveDate>,
+    /// Latest EXIF capture date, YYYY-MM-DD, in UTC where the time zone is known
+    #[arg(long, value_parser = search::parse_date)]
+    captured_before: Option<chrono::NaiveDate>,
+    /// Substring of the camera make or model
+    #[arg(long)]
+    camera: Option<String>,
+    /// Lowest quality score, 0 to 1
+    #[arg(long)]
+    min_quality: Option<f32>,
+    /// Tags every photo must have, comma-separated
+    #[arg(long, value_delimiter = ',')]
+    all_tags: Vec<String>,
+    /// Tags of which a photo must have at least one, comma-separated
+    #[arg(long, value_delimiter = ',')]
+    any_tags: Vec<String>,
+    /// Tags no photo may have, comma-separated
+    #[arg(long, value_delimiter = ',')]
+    exclude_tags: Vec<String>,
+    /// width, height, size, captured, added or quality; prefix with - for descending
+    #[arg(long, allow_hyphen_values = true)]
+    sort: Option<Sort>,
+    /// Search for the query's own tags only, without related tags
+    #[arg(long)]
+    no_expand: bool,
+    /// No fuzzy fallback when no tag matches exactly
+    #[arg(long)]
+    exact: bool,
+    /// Trigram similarity a tag needs in the fuzzy fallback
+    #[arg(long)]
+    fuzzy_threshold: Option<f64>,
 }
 
-async fn upload_photos(pool: &PgPool, directory: &str) -> Result<(), Box<dyn Error>> {
-    let folder_path = std::env::args().nth(1).unwrap_or_else(|| "./images".to_string());
-    let client = Client::new();
-
-    for entry in WalkDir::new(&folder_path) {
-        let entry = entry?;
-        let path = entry.path();
-
-        if path.is_file() && is_image_file(path) {
-            let base64_image = image_to_base64(path).await?;
-            let prompt = "
-You are an image tagging assistant. Your task is to analyze the given image and generate a comma-separated list of relevant tags or keywords that can be used to categorize and search for similar images in a database.
-
-When generating tags, please follow these guidelines:
-
-1. Use concise, descriptive words or short phrases that accurately describe the content of the image.
-2. Avoid using full sentences or unnecessary words in the tags.
-3. Include tags that describe the main subject(s), objects, scenes, activities, emotions, colors, and any other relevant aspects of the image.
-4. Use plural forms for nouns when appropriate (e.g., \"trees\" instead of \"tree\").
-5. Separate each tag with a comma and a space (e.g., \"nature, landscape, trees, mountain\").
-6. Do not include any additional text or explanations beyond the comma-separated list of tags.
-
-Please analyze the provided image and generate a list of relevant tags following the guidelines above.
-";
-            let payload = json!({
-                "stream": false,
-                "model": "llava",
-                "prompt": prompt,
-                "images": [base64_image]
-            });
-
-            let response = client
-                .post("http://localhost:11434/api/generate")
-                .json(&payload)
-                .send()
-                .await?;
-
-            let response_json: serde_json::Value = response.json().await?;
-            let response = response_json["response"].as_str().unwrap().trim();
-            println!("Tags: {}", response);
-            let tags: Vec<&str> = response.split(", ").collect();
-
-            Photo::add_photo(
-                &pool,
-                path.file_name().unwrap().to_str().unwrap(),
-                path.canonicalize().unwrap().to_str().unwrap(),
-                tags,
-            )
-                .await?;
-
-            println!("Added photo: {} ", path.file_name().unwrap().to_str().unwrap());
+impl SearchArgs {
+    fn filters(self) -> SearchFilters {
+        let tags = |tags: Vec<String>| -> Vec<String> {
+            tags.iter().map(|tag| tag.trim()).filter(|tag| !tag.is_empty()).map(str::to_string).collect()
+        };
+        SearchFilters {
+            min_width: self.min_width,
+            min_height: self.min_height,
+            min_bytes: self.min_bytes,
+            max_bytes: self.max_bytes,
+            orientation: self.orientation,
+            aspect_ratio: self.aspect_ratio,
+            captured_after: self.captured_after,
+            captured_before: self.captured_before,
+            camera: self.camera,
+            min_quality: self.min_quality,
+            all_tags: tags(self.all_tags),
+            any_tags: tags(self.any_tags),
+            exclude_tags: tags(self.exclude_tags),
+            sort: self.sort,
+            no_expansion: self.no_expand,
+            exact: self.exact,
+            fuzzy_threshold: self.fuzzy_threshold,
         }
     }
-    Ok(())
 }
 
-// Given a query from user, send a request to get relavant tags from user's search sentence
-async fn get_tags_from_search_query(query: &str) -> Result<Vec<String>, Box<dyn Error>> {
-    let client = Client::new();
-
-    let prompt = format!(
-        "You are a photo tagging assistant. Your task is to extract relevant tags from a given search query that can be used to search for photos in a database.
-
-The search query will be provided to you, and you should respond with a comma-separated list of tags that best represent the query.
-
-Here are some examples:
-
-Search query: \"Give me pictures from sunny days\"
-sunny, clear sky, daylight, outdoor, nature
-
-Search query: \"Show me photos of cars on the street\"
-cars, street, urban, transportation
-
-Search query: \"I want to see images of beaches with palm trees\"
-beach, palm trees, tropical, nature, coastline
-
-Remember to keep the tags concise, relevant, and easy to search for in a database. Avoid using full sentences or unnecessary words in the tags. Only output data as comma-separated tags. Do not output anything else.
+fn parse_seconds(value: &str) -> Result<Duration, String> {
+    value
+        .parse()
+        .ok()
+        .and_then(|seconds| Duration::try_from_secs_f64(seconds).ok())
+        .ok_or_else(|| format!("invalid duration '{}', expected seconds", value))
+}
 
-Search query: \"{}\"",
-        query
-    );
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    // Arguments first, so --help works without a database
+    let cli = Cli::parse();
 
-    let payload = json!({
-        "stream": false,
-        "model": "llama2",
-        "prompt": prompt,
-    });
-
-    let response = client
-        .post("http://localhost:11434/api/generate")
-        .json(&payload)
-        .send()
-        .await?;
-
-    let response_json: serde_json::Value = response.json().await?;
-    let response_text = response_json["response"].as_str().unwrap().trim();
-    println!("Tags to search: {}", response_text);
-    let tags: Vec<String> = response_text
-        .split(',')
-        .map(|s| s.trim().to_string())
-        .collect();
-
-    Ok(tags)
-}
+    // Settings may come from a .env file, variables set in the environment win
+    match dotenvy::dotenv() {
+        Err(e) if !e.not_found() => return Err(e.into()),
+        _ => {}
+    }
 
-async fn search_photos_by_tags(pool: &PgPool, query: &str) -> Result<Vec<Photo>, Box<dyn Error>> {
-    // get tags from query
-    let tags = get_tags_from_search_query(query).await?;
-    // search photos by tags
-    let photos = Photo::search_photos_by_tags(pool, tags).await?;
-    Ok(photos)
-}
+    // Every setting is checked here, before any command runs
+    let mut config = Config::from_env()?;
 
-#[derive(Debug, sqlx::FromRow)]
-struct Photo {
-    photo_id: i32,
-    file_name: String,
-    pub(crate) file_path: String,
-    tags: Vec<String>,
-    created_at: NaiveDateTime,
-}
+    // Connect to the database
+    let pool = PgPool::connect(&config.database_url).await?;
 
-impl Photo {
-    // Function to add a new photo to the database
-    async fn add_photo(pool: &PgPool, file_name: &str, file_path: &str, tags: Vec<&str>) -> Result<(), sqlx::Error> {
-        let tags_array = tags.into_iter().map(|s| s.to_string()).collect::<Vec<_>>();
+    // Create photos table
+    create_photos_table(&pool).await?;
 
-        let query = "INSERT INTO photos (file_name, file_path, tags) VALUES ($1, $2, $3)";
-        let _ = sqlx::query(query)
-            .bind(file_name)
-            .bind(file_path)
-            .bind(tags_array)
-            .execute(pool)
-            .await?;
+    let tagger = image_index_ai::tagger::from_config(&config.ai)?;
+
+    // images that failed to ingest, reported once the webhooks of the others are delivered
+    let mut failed = 0;
+    match cli.command.unwrap_or(Command::Ingest(cli.ingest)) {
+        // SEARCH FLOW
+        // e.g. `cargo run -- search "photos by the beach" --min-width 2000 --orientation portrait`
+        Command::Search(args) => {
+            let query = args.query.clone();
+            let filters = args.filters();
+            // Search photos by tags
+            let photos = search_photos_by_tags(&pool, tagger.as_ref(), &query, &filters).await?;
+            photos.iter().for_each(print_photo);
+        }
+        // SIMILAR FLOW
+        // Lists the photos sharing the most tags with a photo, e.g. `cargo run -- similar 42 --limit 10`
+        Command::Similar { photo_id, limit } => {
+            if Photo::find_by_id(&pool, photo_id).await?.is_none() {
+                return Err(format!("No photo with id {}", photo_id).into());
+            }
+            Photo::similar(&pool, photo_id, limit).await?.iter().for_each(print_photo);
+        }
+        // LINK FLOW
+        // Records that one photo derives from another, e.g. `cargo run -- link 43 crop-of 42`
+        Command::Link { from_photo_id, kind, to_photo_id } => {
+            if from_photo_id == to_photo_id {
+                return Err("a photo cannot be linked to itself".into());
+            }
+            for photo_id in [from_photo_id, to_photo_id] {
+                if Photo::find_by_id(&pool, photo_id).await?.is_none() {
+                    return Err(format!("No photo with id {}", photo_id).into());
+                }
+            }
+            match PhotoLink::create(&pool, from_photo_id, kind, to_photo_id).await? {
+                Some(link) => println!("Created link #{}", link.link_id),
+                None => println!("Photos #{} and #{} are already linked as {}", from_photo_id, to_photo_id, kind),
+            }
+        }
+        // UNLINK FLOW
+        // Removes a link by the id `link` and `links` print, e.g. `cargo run -- unlink 7`
+        Command::Unlink { link_id } => {
+            PhotoLink::delete(&pool, link_id)
+                .await?
+                .ok_or_else(|| format!("No link with id {}", link_id))?;
+            println!("Removed link #{}", link_id);
+        }
+        // LINKS FLOW
+        // Lists every photo connected to a photo and how, e.g. `cargo run -- links 42`
+        Command::Links { photo_id } => {
+            if Photo::find_by_id(&pool, photo_id).await?.is_none() {
+                return Err(format!("No photo with id {}", photo_id).into());
+            }
+            for link in PhotoLink::list_group(&pool, photo_id).await? {
+                println!("#{} {} {} {}", link.link_id, link.from_photo_id, link.kind, link.to_photo_id);
+            }
+        }
+        // BEST SHOTS FLOW
+        // Picks the best photo of each burst or linked group, e.g. `cargo run -- best-shots --gap 3`
+        Command::BestShots { gap } => {
+            let groups = best_shots(&pool, gap.unwrap_or(best_shot::DEFAULT_BURST_GAP)).await?;
+            for group in &groups {
+                let quality = |shot: &best_shot::Shot| shot.quality.map_or("unscored".to_string(), |quality| format!("{:.2}", quality));
+                println!("Best of {}: #{} {} (quality {})", group.others.len() + 1, group.best.photo_id, group.best.file_path, quality(&group.best));
+                for shot in &group.others {
+                    println!("    #{} {} (quality {})", shot.photo_id, shot.file_path, quality(shot));
+                }
+            }
+            println!("{} groups", groups.len());
+        }
+        // EXPORT FLOW
+        // Streams every photo, then every link, event and provenance record, as one JSON object per line,
+        // e.g. `cargo run -- export > photos.ndjson`, or with the image files `cargo run -- export --archive > library.tar`
+        Command::Export { archive } => {
+            if archive {
+                export_archive(&pool, std::io::stdout().lock()).await?;
+            } else {
+                export_photos(&pool, std::io::stdout().lock()).await?;
+            }
+        }
+        // IMPORT FLOW
+        // Restores an export on another database, e.g. `cargo run -- import photos.ndjson --rebase /srv/photos:/data/photos`
+        // after copying the image folders from /srv/photos to /data/photos, or with its files
+        // `cargo run -- import library.tar --restore-to /data/photos`
+        Command::Import { file, rebase, restore_to } => {
+            let reader = std::io::BufReader::new(std::fs::File::open(&file)?);
+            let report = match restore_to {
+                Some(restore_to) => import_archive(&pool, &config.ingest, reader, &restore_to).await?,
+                None => import_photos(&pool, &config.ingest, reader, rebase.as_ref()).await?,
+            };
+            println!("Imported {} photos, {} already present", report.imported, report.skipped);
+            println!("Imported {} links, {} already present", report.links_imported, report.links_skipped);
+            if !report.missing_files.is_empty() {
+                println!("{} imported photos have no file at their path yet:", report.missing_files.len());
+                for path in report.missing_files {
+                    println!("  {}", path);
+                }
+            }
+        }
+        // SHOW FLOW
+        // Prints all metadata of a single photo, e.g. `cargo run -- show 42`
+        Command::Show { photo_id } => {
+            let photo = Photo::find_by_id(&pool, photo_id)
+                .await?
+                .ok_or_else(|| format!("No photo with id {}", photo_id))?;
+            println!("{}", serde_json::to_string_pretty(&photo)?);
+        }
+        // TAGS FLOW
+        // Lists distinct tags with usage counts, e.g. `cargo run -- tags --prefix sun --min-count 3`
+        Command::Tags { prefix, min_count } => {
+            for tag in TagCount::list(&pool, prefix.as_deref(), min_count).await? {
+                println!("{} {}", tag.count, tag.tag);
+            }
+        }
+        // CHECK FLOW
+        // Tells which SHA-256 hashes are already indexed, e.g. `cargo run -- check $(sha256sum *.jpg | cut -d' ' -f1)`
+        Command::Check { content_hashes } => {
+            let content_hashes: Vec<String> = content_hashes.iter().map(|hash| hash.to_lowercase()).collect();
+            let existing = Photo::existing_content_hashes(&pool, &content_hashes).await?;
+            for content_hash in &content_hashes {
+                let status = if existing.contains(content_hash) { "exists" } else { "missing" };
+                println!("{} {}", content_hash, status);
+            }
+        }
+        // EVENTS FLOW
+        // Prints the processing timeline of a photo, e.g. `cargo run -- events 42`
+        Command::Events { photo_id } => {
+            if Photo::find_by_id(&pool, photo_id).await?.is_none() {
+                return Err(format!("No photo with id {}", photo_id).into());
+            }
+            for event in PhotoEvent::list_for_photo(&pool, photo_id).await? {
+                println!("{} {} {}", event.created_at, event.event, event.detail.unwrap_or_default());
+            }
+        }
+        // WEBHOOK FLOW
+        // Manages the receivers of lifecycle events, e.g.
+        // `cargo run -- webhook add http://homeassistant.local:8123/api/webhook/photos --secret s3cret --events photo.created`
+        Command::Webhook(command) => match command {
+            WebhookCommand::Add { url, secret, mut events } => {
+                if events.is_empty() {
+                    events = hooks::EVENTS.iter().map(|event| event.to_string()).collect();
+                }
+                events.dedup();
+                let webhook = Webhook::create(&pool, &url, secret.as_deref(), &events).await?;
+                println!("Created webhook #{}", webhook.webhook_id);
+            }
+            WebhookCommand::List => {
+                for webhook in Webhook::list(&pool).await? {
+                    println!(
+                        "#{} {} {}{}{}",
+                        webhook.webhook_id,
+                        webhook.url,
+                        webhook.events.join(","),
+                        if webhook.secret.is_some() { " signed" } else { "" },
+                        if webhook.enabled { "" } else { " disabled" }
+                    );
+                }
+            }
+            WebhookCommand::Remove { webhook_id } => {
+                if !Webhook::delete(&pool, webhook_id).await? {
+                    return Err(format!("No webhook with id {}", webhook_id).into());
+                }
+                println!("Removed webhook #{}", webhook_id);
+            }
+            WebhookCommand::Enable { webhook_id } | WebhookCommand::Disable { webhook_id } => {
+                let enabled = matches!(command, WebhookCommand::Enable { .. });
+                if !Webhook::set_enabled(&pool, webhook_id, enabled).await? {
+                    return Err(format!("No webhook with id {}", webhook_id).into());
+                }
+                println!("{} webhook #{}", if enabled { "Enabled" } else { "Disabled" }, webhook_id);
+            }
+        },
+        // DELIVERIES FLOW
+        // Shows every webhook POST made for a photo, deleted ones included, e.g. `cargo run -- deliveries 42`
+        Command::Deliveries { photo_id } => {
+            for delivery in WebhookDelivery::list_for_photo(&pool, photo_id).await? {
+                let status = delivery.status.map(|status| status.to_string()).unwrap_or_else(|| "-".to_string());
+                println!(
+                    "{} {} attempt {} to {}: {} {}",
+                    delivery.created_at,
+                    delivery.event,
+                    delivery.attempt,
+                    delivery.url,
+                    status,
+                    delivery.error.unwrap_or_default()
+                );
+            }
+        }
+        // REINDEX FLOW
+        // Re-tags indexed photos, e.g. `cargo run -- reindex --from-id 100 --to-id 200` or `--missing-tags`
+        Command::Reindex { from_id, to_id, missing_tags } => {
+            let filter = ReindexFilter { from_id, to_id, missing_tags };
+            let report = reindex_photos(&pool, tagger.as_ref(), &config.ingest.hooks, &filter).await?;
+            println!("Retagged {} photos, {} failed", report.retagged, report.failed.len());
+            for (photo_id, error) in report.failed {
+                println!("  #{}: {}", photo_id, error);
+            }
+        }
+        // DRIFT FLOW
+        // Compares stored tags of a random sample with the current model's, e.g. `cargo run -- drift --sample 50`
+        Command::Drift { sample } => {
+            let report = drift_report(&pool, tagger.as_ref(), sample).await?;
+            for drift in &report.photos {
+                if drift.similarity < 1.0 {
+                    println!("  #{}: {:?} -> {:?}", drift.photo_id, drift.stored, drift.current);
+                }
+            }
+            match report.mean_similarity() {
+                Some(mean) => println!("Mean tag similarity {:.2} over {} photos", mean, report.photos.len()),
+                None => println!("No photos could be compared"),
+            }
+            if !report.failed.is_empty() {
+                println!("{} photos failed", report.failed.len());
+            }
+        }
+        // PROVENANCE FLOW
+        // Lists which model produced a photo's tags, e.g. `cargo run -- provenance 42`, or checks
+        // the whole record with `cargo run -- provenance --verify`
+        Command::Provenance { photo_id, verify } => match photo_id {
+            Some(photo_id) if !verify => {
+                for record in TagProvenance::list_for_photo(&pool, photo_id).await? {
+                    println!(
+                        "{} #{} {} prompt {} response {} {:?}\n  hash {}",
+                        record.created_at,
+                        record.provenance_id,
+                        record.model,
+                        record.prompt_hash,
+                        record.response_hash,
+                        record.tags,
+                        record.record_hash
+                    );
+                }
+            }
+            _ => match TagProvenance::verify_chain(&pool).await? {
+                None => println!("Provenance chain intact"),
+                Some(provenance_id) => {
+                    return Err(format!("Provenance chain broken at record #{}", provenance_id).into())
+                }
+            },
+        },
+        // DELETE FLOW
+        // Removes the photo from the index, e.g. `cargo run -- delete 42`
+        Command::Delete { photo_id } => {
+            let photo = delete_photo(&pool, &config.ingest, photo_id)
+                .await?
+                .ok_or_else(|| format!("No photo with id {}", photo_id))?;
+            println!("Deleted photo #{}: {}", photo.photo_id, photo.file_path);
+        }
+        // LOCK FLOW
+        // Protects a finalized photo, e.g. `cargo run -- lock 42`, undone with `cargo run -- unlock 42`
+        Command::Lock { photo_id } => {
+            if !Photo::set_locked(&pool, photo_id, true).await? {
+                return Err(format!("No photo with id {}", photo_id).into());
+            }
+            println!("Locked photo #{}", photo_id);
+        }
+        Command::Unlock { photo_id } => {
+            if !Photo::set_locked(&pool, photo_id, false).await? {
+                return Err(format!("No photo with id {}", photo_id).into());
+            }
+            println!("Unlocked photo #{}", photo_id);
+        }
+        // VACUUM FLOW
+        // Deletes transcoded copies and thumbnails no photo refers to, e.g. `cargo run -- vacuum-orphans --dry-run`
+        Command::VacuumOrphans { dry_run } => {
+            let orphans = vacuum_orphans(&pool, &config.ingest, dry_run).await?;
+            for path in &orphans {
+                println!("{} {}", if dry_run { "Would delete" } else { "Deleted" }, path.display());
+            }
+            println!("{} orphaned files", orphans.len());
+        }
+        // WATCH FLOW
+        // Indexes images as they are dropped into the folders, e.g. `cargo run -- watch ~/Sync/Camera`,
+        // or the folders in WATCH_DIRS when none are given
+        Command::Watch { dirs, duplicates } => {
+            if !dirs.is_empty() {
+                config.watch.dirs = dirs;
+            }
+            if duplicates.is_some() {
+                config.watch.duplicates = duplicates;
+            }
+            watch_folders(&pool, tagger.as_ref(), &config.ingest, &config.watch).await?;
+        }
+        // RELOCATE FLOW
+        // Moves transcoded files into the hashed layout, e.g. `NORMALIZED_LAYOUT=hashed cargo run -- relocate`
+        Command::Relocate => {
+            let relocated = relocate_photos(&pool, &config.ingest).await?;
+            println!("Relocated {} photos", relocated);
+        }
+        // PURGE FLOW
+        // Empties trash folders older than TRASH_RETENTION_DAYS, e.g. from a daily cron job
+        Command::PurgeTrash => {
+            let trash = config.ingest.trash.as_ref().ok_or("TRASH_DIR is not set, nothing to purge")?;
+            let purged = trash.purge()?;
+            println!("Purged {} days of trash older than {} days", purged, trash.retention_days);
+        }
+        // UPLOAD FLOW
+        // `cargo run -- ingest ./images`, or just the folder path as before; e.g.
+        // `cargo run -- ./inbox --duplicates replace` overrides INGEST_DUPLICATES for this run
+        Command::Ingest(args) => {
+            if let Some(duplicates) = args.duplicates {
+                config.ingest.duplicates = duplicates;
+            }
+            // Upload photos to the database
+            let report = upload_photos(&pool, tagger.as_ref(), &config.ingest, &args.folder).await?;
+            println!(
+                "Added {} photos, {} skipped, {} failed",
+                report.added.len(),
+                report.skipped.len(),
+                report.failed.len()
+            );
+            failed = report.failed.len();
+            for (path, error) in report.failed {
+                println!("  {}: {}", path.display(), error);
+            }
+        }
+    }
 
-        Ok(())
+    // Webhook deliveries run in the background, let them finish before exiting
+    for hook in &config.ingest.hooks {
+        hook.finish().await;
     }
 
-    // Function to search for photos by tags
-    async fn search_photos_by_tags(
-        pool: &PgPool,
-        search_tags: Vec<String>,
-    ) -> Result<Vec<Photo>, sqlx::Error> {
-        if search_tags.is_empty() {
-            let query = "SELECT photo_id, file_name, file_path, tags, created_at FROM photos";
-            sqlx::query_as::<_, Photo>(query)
-                .fetch_all(pool)
-                .await
-        } else {
-            let tags_query = search_tags
-                .iter()
-                .map(|tag| format!("'{}'", tag))
-                .collect::<Vec<_>>()
-                .join(", ");
-
-            let query = format!(
-                "
-            SELECT p.photo_id, p.file_name, p.file_path, p.tags, p.created_at
-            FROM photos p
-            WHERE p.tags && ARRAY[{}]
-        ",
-                tags_query
-            );
+    if failed > 0 {
+        return Err(format!("{} images failed to ingest", failed).into());
+    }
+    Ok(())
+}
 
-            sqlx::query_as::<_, Photo>(&query)
-                .fetch_all(pool)
-                .await
-        }
+fn print_photo(photo: &Photo) {
+    println!(
+        "Photo #{}: {} at {} ({}x{}, {} bytes, added {}) {:?}",
+        photo.photo_id,
+        photo.file_name,
+        photo.file_path,
+        photo.width.unwrap_or_default(),
+        photo.height.unwrap_or_default(),
+        photo.byte_size.unwrap_or_default(),
+        photo.created_at,
+        photo.tags
+    );
+    if let Some(duplicate_of) = photo.duplicate_of {
+        println!("  copy of photo #{}", duplicate_of);
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,209 @@
+use std::io::Cursor;
+
+use chrono::{FixedOffset, NaiveDate, NaiveDateTime, TimeZone};
+use chrono_tz::Tz;
+use exif::{Exif, In, Tag, Value};
+
+// Structured EXIF fields stored with each photo. Every field is optional: screenshots, downloads
+// and transcoded files usually carry no EXIF at all.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct ExifMetadata {
+    // local time on the camera's clock
+    pub captured_at: Option<NaiveDateTime>,
+    // UTC offset recorded with the capture time (EXIF 2.31 OffsetTime*), missing on older cameras
+    pub utc_offset: Option<FixedOffset>,
+    pub camera_make: Option<String>,
+    pub camera_model: Option<String>,
+    // EXIF orientation, 1-8 (1 is upright)
+    pub orientation: Option<i16>,
+    pub gps_latitude: Option<f64>,
+    pub gps_longitude: Option<f64>,
+}
+
+impl ExifMetadata {
+    // Read EXIF from an image file's bytes; anything missing or malformed is left empty
+    pub fn read(buffer: &[u8]) -> ExifMetadata {
+        match exif::Reader::new().read_from_container(&mut Cursor::new(buffer)) {
+            Ok(exif) => ExifMetadata::from_exif(&exif),
+            Err(_) => ExifMetadata::default(),
+        }
+    }
+
+    fn from_exif(exif: &Exif) -> ExifMetadata {
+        // each timestamp has its own offset tag, keep the pair together
+        let timestamps = [(Tag::DateTimeOriginal, Tag::OffsetTimeOriginal), (Tag::DateTime, Tag::OffsetTime)];
+        let (captured_at, utc_offset) = timestamps
+            .iter()
+            .find_map(|&(tag, offset_tag)| {
+                let captured_at = ascii_field(exif, tag).and_then(|value| parse_date_time(&value))?;
+                Some((captured_at, ascii_field(exif, offset_tag).and_then(|value| parse_offset(&value))))
+            })
+            .unzip();
+
+        ExifMetadata {
+            captured_at,
+            utc_offset: utc_offset.flatten(),
+            camera_make: ascii_field(exif, Tag::Make),
+            camera_model: ascii_field(exif, Tag::Model),
+            orientation: exif
+                .get_field(Tag::Orientation, In::PRIMARY)
+                .and_then(|field| field.value.get_uint(0))
+                .and_then(|orientation| i16::try_from(orientation).ok()),
+            gps_latitude: gps_coordinate(exif, Tag::GPSLatitude, Tag::GPSLatitudeRef, "S"),
+            gps_longitude: gps_coordinate(exif, Tag::GPSLongitude, Tag::GPSLongitudeRef, "W"),
+        }
+    }
+}
+
+impl ExifMetadata {
+    // Capture time in UTC, so photos taken while travelling order correctly against each other. Uses
+    // the recorded offset, or else treats the camera clock as set to `home_timezone`.
+    pub fn captured_at_utc(&self, home_timezone: Option<Tz>) -> Option<NaiveDateTime> {
+        let captured_at = self.captured_at?;
+        match (self.utc_offset, home_timezone) {
+            (Some(offset), _) => Some(captured_at - offset),
+            // the earlier instant for times that occur twice when clocks go back
+            (None, Some(timezone)) => timezone
+                .from_local_datetime(&captured_at)
+                .earliest()
+                .map(|captured_at| captured_at.naive_utc()),
+            (None, None) => None,
+        }
+    }
+}
+
+fn ascii_field(exif: &Exif, tag: Tag) -> Option<String> {
+    match &exif.get_field(tag, In::PRIMARY)?.value {
+        Value::Ascii(values) => {
+            let value = String::from_utf8_lossy(values.first()?);
+            let value = value.trim_matches(|c: char| c == '\0' || c.is_whitespace());
+            (!value.is_empty()).then(|| value.to_string())
+        }
+        _ => None,
+    }
+}
+
+// EXIF timestamps look like `2023:07:14 18:30:05` and carry no time zone
+fn parse_date_time(value: &str) -> Option<NaiveDateTime> {
+    let date_time = exif::DateTime::from_ascii(value.as_bytes()).ok()?;
+    NaiveDate::from_ymd_opt(date_time.year.into(), date_time.month.into(), date_time.day.into())?.and_hms_opt(
+        date_time.hour.into(),
+        date_time.minute.into(),
+        date_time.second.into(),
+    )
+}
+
+// `+02:00` or `-05:30`
+fn parse_offset(value: &str) -> Option<FixedOffset> {
+    let sign = match value.get(..1)? {
+        "+" => 1,
+        "-" => -1,
+        _ => return None,
+    };
+    let (hours, minutes) = value[1..].split_once(':')?;
+    let seconds = hours.parse::<i32>().ok()? * 3600 + minutes.parse::<i32>().ok()? * 60;
+    FixedOffset::east_opt(sign * seconds)
+}
+
+// Degrees/minutes/seconds rationals plus an N/S or E/W reference, as signed decimal degrees
+fn gps_coordinate(exif: &Exif, tag: Tag, reference_tag: Tag, negative_reference: &str) -> Option<f64> {
+    let parts = match &exif.get_field(tag, In::PRIMARY)?.value {
+        Value::Rational(parts) if parts.len() >= 3 => parts,
+        _ => return None,
+    };
+    let degrees = parts[0].to_f64() + parts[1].to_f64() / 60.0 + parts[2].to_f64() / 3600.0;
+    if !degrees.is_finite() {
+        return None;
+    }
+
+    match ascii_field(exif, reference_tag).as_deref() {
+        Some(reference) if reference.eq_ignore_ascii_case(negative_reference) => Some(-degrees),
+        _ => Some(degrees),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use exif::experimental::Writer;
+    use exif::{Field, Rational};
+
+    fn ascii(tag: Tag, value: &str) -> Field {
+        Field { tag, ifd_num: In::PRIMARY, value: Value::Ascii(vec![value.as_bytes().to_vec()]) }
+    }
+
+    fn rationals(tag: Tag, values: &[(u32, u32)]) -> Field {
+        let values = values.iter().map(|&(num, denom)| Rational { num, denom }).collect();
+        Field { tag, ifd_num: In::PRIMARY, value: Value::Rational(values) }
+    }
+
+    fn tiff_with(fields: &[Field]) -> Vec<u8> {
+        let mut writer = Writer::new();
+        for field in fields {
+            writer.push_field(field);
+        }
+        let mut buffer = Cursor::new(Vec::new());
+        writer.write(&mut buffer, false).unwrap();
+        buffer.into_inner()
+    }
+
+    #[test]
+    fn reads_capture_details_and_gps() {
+        let buffer = tiff_with(&[
+            ascii(Tag::Make, "Canon"),
+            ascii(Tag::Model, "Canon EOS R6"),
+            Field { tag: Tag::Orientation, ifd_num: In::PRIMARY, value: Value::Short(vec![6]) },
+            ascii(Tag::DateTimeOriginal, "2023:07:14 18:30:05"),
+            ascii(Tag::OffsetTimeOriginal, "+10:00"),
+            rationals(Tag::GPSLatitude, &[(33, 1), (51, 1), (3600, 100)]),
+            ascii(Tag::GPSLatitudeRef, "S"),
+            rationals(Tag::GPSLongitude, &[(151, 1), (12, 1), (0, 1)]),
+            ascii(Tag::GPSLongitudeRef, "E"),
+        ]);
+
+        let metadata = ExifMetadata::read(&buffer);
+
+        assert_eq!(metadata.camera_make.as_deref(), Some("Canon"));
+        assert_eq!(metadata.camera_model.as_deref(), Some("Canon EOS R6"));
+        assert_eq!(metadata.orientation, Some(6));
+        assert_eq!(
+            metadata.captured_at,
+            NaiveDate::from_ymd_opt(2023, 7, 14).unwrap().and_hms_opt(18, 30, 5)
+        );
+        assert_eq!(
+            metadata.captured_at_utc(None),
+            NaiveDate::from_ymd_opt(2023, 7, 14).unwrap().and_hms_opt(8, 30, 5)
+        );
+        assert!((metadata.gps_latitude.unwrap() + 33.86).abs() < 1e-9);
+        assert!((metadata.gps_longitude.unwrap() - 151.2).abs() < 1e-9);
+    }
+
+    #[test]
+    fn falls_back_to_modification_date_and_skips_blank_values() {
+        let buffer = tiff_with(&[ascii(Tag::DateTime, "2020:01:02 03:04:05"), ascii(Tag::Model, "  ")]);
+
+        let metadata = ExifMetadata::read(&buffer);
+
+        assert_eq!(metadata.captured_at, NaiveDate::from_ymd_opt(2020, 1, 2).unwrap().and_hms_opt(3, 4, 5));
+        assert_eq!(metadata.camera_model, None);
+    }
+
+    #[test]
+    fn capture_time_without_offset_uses_home_timezone() {
+        let metadata = ExifMetadata::read(&tiff_with(&[ascii(Tag::DateTimeOriginal, "2023:07:14 18:30:05")]));
+
+        assert_eq!(metadata.utc_offset, None);
+        assert_eq!(metadata.captured_at_utc(None), None);
+        // CEST, two hours ahead of UTC in July
+        assert_eq!(
+            metadata.captured_at_utc(Some(chrono_tz::Europe::Berlin)),
+            NaiveDate::from_ymd_opt(2023, 7, 14).unwrap().and_hms_opt(16, 30, 5)
+        );
+    }
+
+    #[test]
+    fn images_without_exif_have_empty_metadata() {
+        assert_eq!(ExifMetadata::read(b"not an image"), ExifMetadata::default());
+        assert_eq!(ExifMetadata::read(&[]), ExifMetadata::default());
+    }
+}
@@ -0,0 +1,303 @@
+use std::error::Error;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use reqwest::Client;
+use serde_json::{json, Value};
+
+use crate::config::OllamaConfig;
+use crate::tagger::{Tagger, Timeouts};
+use crate::tagging;
+
+const DEFAULT_BASE_URL: &str = "http://localhost:11434";
+const DEFAULT_VISION_MODEL: &str = "llava";
+const DEFAULT_TEXT_MODEL: &str = "llama2";
+
+// Client for the Ollama `/api/generate` endpoint, used for image tagging and query tag extraction
+pub struct OllamaClient {
+    client: Client,
+    base_url: String,
+    vision_model: String,
+    text_model: String,
+    timeouts: Timeouts,
+}
+
+impl OllamaClient {
+    pub fn new(base_url: impl Into<String>) -> Self {
+        OllamaClient {
+            client: Client::new(),
+            base_url: base_url.into().trim_end_matches('/').to_string(),
+            vision_model: DEFAULT_VISION_MODEL.to_string(),
+            text_model: DEFAULT_TEXT_MODEL.to_string(),
+            timeouts: Timeouts::default(),
+        }
+    }
+
+    // A client for the configured server, defaulting to a local instance; models left unset keep
+    // the defaults
+    pub fn from_config(config: &OllamaConfig) -> Result<Self, Box<dyn Error>> {
+        let mut client = OllamaClient::new(config.url.as_deref().unwrap_or(DEFAULT_BASE_URL))
+            .with_operation_timeouts(config.timeouts);
+        if let Some(model) = &config.vision_model {
+            client.vision_model = model.clone();
+        }
+        if let Some(model) = &config.text_model {
+            client.text_model = model.clone();
+        }
+        match config.timeout {
+            Some(timeout) => client.with_timeout(timeout),
+            None => Ok(client),
+        }
+    }
+
+    // Fail requests that take longer than `timeout` instead of waiting indefinitely
+    pub fn with_timeout(mut self, timeout: Duration) -> Result<Self, Box<dyn Error>> {
+        self.client = Client::builder().timeout(timeout).build()?;
+        Ok(self)
+    }
+
+    pub fn with_models(mut self, vision_model: impl Into<String>, text_model: impl Into<String>) -> Self {
+        self.vision_model = vision_model.into();
+        self.text_model = text_model.into();
+        self
+    }
+
+    // Give image tagging and text requests their own timeouts, overriding the client-wide one
+    pub fn with_operation_timeouts(mut self, timeouts: Timeouts) -> Self {
+        self.timeouts = timeouts;
+        self
+    }
+
+    // Send a non-streaming generate request and return the trimmed `response` text
+    async fn generate(&self, payload: &Value, timeout: Option<Duration>) -> Result<String, Box<dyn Error>> {
+        let url = format!("{}/api/generate", self.base_url);
+        let mut request = self.client.post(&url).json(payload);
+        if let Some(timeout) = timeout {
+            request = request.timeout(timeout);
+        }
+        let response = request.send().await.map_err(|e| match timeout {
+            Some(timeout) if e.is_timeout() => format!("Ollama did not answer within {:?}", timeout).into(),
+            _ => Box::<dyn Error>::from(e),
+        })?;
+
+        let status = response.status();
+        if !status.is_success() {
+            let body = response.text().await.unwrap_or_default();
+            return Err(format!("Ollama returned {}: {}", status, body.trim()).into());
+        }
+
+        let response_json: Value = response
+            .json()
+            .await
+            .map_err(|e| format!("Ollama returned invalid JSON: {}", e))?;
+        if let Some(error) = response_json["error"].as_str() {
+            return Err(format!("Ollama error: {}", error).into());
+        }
+
+        let text = response_json["response"]
+            .as_str()
+            .ok_or("Ollama response has no `response` text")?;
+        Ok(text.trim().to_string())
+    }
+}
+
+#[async_trait]
+impl Tagger for OllamaClient {
+    fn vision_model(&self) -> &str {
+        &self.vision_model
+    }
+
+    fn text_model(&self) -> &str {
+        &self.text_model
+    }
+
+    async fn tag_image(&self, base64_image: &str) -> Result<Vec<String>, Box<dyn Error>> {
+        Ok(self.tag_image_with_response(base64_image).await?.0)
+    }
+
+    async fn tag_image_with_response(&self, base64_image: &str) -> Result<(Vec<String>, String), Box<dyn Error>> {
+        let payload = json!({
+            "stream": false,
+            "model": self.vision_model,
+            "prompt": tagging::IMAGE_TAGGING_PROMPT,
+            "images": [base64_image]
+        });
+
+        let response = self.generate(&payload, self.timeouts.tag).await?;
+        println!("Tags: {}", response);
+        Ok((tagging::parse_tags(&response), response))
+    }
+
+    async fn tags_for_query(&self, query: &str) -> Result<Vec<String>, Box<dyn Error>> {
+        let prompt = tagging::query_tagging_prompt(query);
+
+        let payload = json!({
+            "stream": false,
+            "model": self.text_model,
+            "prompt": prompt,
+        });
+
+        let response = self.generate(&payload, self.timeouts.text).await?;
+        println!("Tags to search: {}", response);
+        Ok(tagging::parse_tags(&response))
+    }
+
+    async fn related_tags(&self, tag: &str) -> Result<Vec<String>, Box<dyn Error>> {
+        let payload = json!({
+            "stream": false,
+            "model": self.text_model,
+            "prompt": tagging::related_tags_prompt(tag),
+        });
+
+        let response = self.generate(&payload, self.timeouts.text).await?;
+        Ok(tagging::parse_tags(&response))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wiremock::matchers::{body_partial_json, method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    async fn mock_generate(response: ResponseTemplate) -> MockServer {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/api/generate"))
+            .respond_with(response)
+            .mount(&server)
+            .await;
+        server
+    }
+
+    #[tokio::test]
+    async fn tags_image_with_vision_model() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/api/generate"))
+            .and(body_partial_json(json!({ "model": DEFAULT_VISION_MODEL, "stream": false, "images": ["aGVsbG8="] })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "model": DEFAULT_VISION_MODEL,
+                "response": " beach, sunset, palm trees ",
+                "done": true
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let tags = OllamaClient::new(server.uri()).tag_image("aGVsbG8=").await.unwrap();
+
+        assert_eq!(tags, vec!["beach", "sunset", "palm trees"]);
+    }
+
+    #[tokio::test]
+    async fn extracts_query_tags_with_text_model() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/api/generate"))
+            .and(body_partial_json(json!({ "model": DEFAULT_TEXT_MODEL })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "response": "Tags: cars, street, urban",
+                "done": true
+            })))
+            .mount(&server)
+            .await;
+
+        // a trailing slash on the base URL must not produce `//api/generate`
+        let client = OllamaClient::new(format!("{}/", server.uri()));
+        let tags = client.tags_for_query("cars on the street").await.unwrap();
+
+        assert_eq!(tags, vec!["cars", "street", "urban"]);
+    }
+
+    #[tokio::test]
+    async fn related_tags_use_text_model() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/api/generate"))
+            .and(body_partial_json(json!({ "model": "mistral" })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "response": "automobile, vehicle" })))
+            .mount(&server)
+            .await;
+
+        let client = OllamaClient::new(server.uri()).with_models("llava:13b", "mistral");
+        let tags = client.related_tags("car").await.unwrap();
+
+        assert_eq!(tags, vec!["automobile", "vehicle"]);
+    }
+
+    #[tokio::test]
+    async fn malformed_json_is_an_error() {
+        let server = mock_generate(ResponseTemplate::new(200).set_body_string("{\"response\": \"beach,")).await;
+
+        let error = OllamaClient::new(server.uri()).tag_image("").await.unwrap_err();
+
+        assert!(error.to_string().contains("invalid JSON"), "{}", error);
+    }
+
+    #[tokio::test]
+    async fn missing_response_field_is_an_error() {
+        let server = mock_generate(ResponseTemplate::new(200).set_body_json(json!({ "done": true }))).await;
+
+        let error = OllamaClient::new(server.uri()).tag_image("").await.unwrap_err();
+
+        assert!(error.to_string().contains("no `response`"), "{}", error);
+    }
+
+    #[tokio::test]
+    async fn error_body_is_reported() {
+        let server = mock_generate(
+            ResponseTemplate::new(200).set_body_json(json!({ "error": "model 'llava' not found" })),
+        )
+        .await;
+
+        let error = OllamaClient::new(server.uri()).tag_image("").await.unwrap_err();
+
+        assert!(error.to_string().contains("model 'llava' not found"), "{}", error);
+    }
+
+    #[tokio::test]
+    async fn error_status_is_reported() {
+        let server = mock_generate(ResponseTemplate::new(503).set_body_string("loading model")).await;
+
+        let error = OllamaClient::new(server.uri()).tags_for_query("beach").await.unwrap_err();
+
+        assert!(error.to_string().contains("503"), "{}", error);
+        assert!(error.to_string().contains("loading model"), "{}", error);
+    }
+
+    #[tokio::test]
+    async fn slow_response_times_out() {
+        let server = mock_generate(
+            ResponseTemplate::new(200)
+                .set_body_json(json!({ "response": "beach" }))
+                .set_delay(Duration::from_secs(2)),
+        )
+        .await;
+
+        let client = OllamaClient::new(server.uri())
+            .with_timeout(Duration::from_millis(100))
+            .unwrap();
+
+        assert!(client.tag_image("").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn operation_timeouts_apply_per_call_kind() {
+        let server = mock_generate(
+            ResponseTemplate::new(200)
+                .set_body_json(json!({ "response": "beach" }))
+                .set_delay(Duration::from_millis(300)),
+        )
+        .await;
+
+        let client = OllamaClient::new(server.uri()).with_operation_timeouts(Timeouts {
+            tag: Some(Duration::from_secs(5)),
+            text: Some(Duration::from_millis(50)),
+        });
+
+        assert_eq!(client.tag_image("").await.unwrap(), vec!["beach"]);
+        let error = client.tags_for_query("beach").await.unwrap_err();
+        assert!(error.to_string().contains("did not answer within 50ms"), "{}", error);
+    }
+}
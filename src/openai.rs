@@ -0,0 +1,288 @@
+use std::error::Error;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use data_encoding::BASE64;
+use reqwest::Client;
+use serde_json::{json, Value};
+
+use crate::config::OpenAiConfig;
+use crate::ingest;
+use crate::tagger::{Tagger, Timeouts};
+use crate::tagging;
+
+// LM Studio's default; vLLM and OpenAI itself only need OPENAI_BASE_URL changed
+const DEFAULT_BASE_URL: &str = "http://localhost:1234/v1";
+const DEFAULT_VISION_MODEL: &str = "llava";
+const DEFAULT_TEXT_MODEL: &str = "llama2";
+
+// Client for OpenAI-compatible `/chat/completions` endpoints
+pub struct OpenAiClient {
+    client: Client,
+    base_url: String,
+    api_key: Option<String>,
+    vision_model: String,
+    text_model: String,
+    timeouts: Timeouts,
+}
+
+impl OpenAiClient {
+    pub fn new(base_url: impl Into<String>) -> Self {
+        OpenAiClient {
+            client: Client::new(),
+            base_url: base_url.into().trim_end_matches('/').to_string(),
+            api_key: None,
+            vision_model: DEFAULT_VISION_MODEL.to_string(),
+            text_model: DEFAULT_TEXT_MODEL.to_string(),
+            timeouts: Timeouts::default(),
+        }
+    }
+
+    // A client for the configured server, models left unset keep the defaults
+    pub fn from_config(config: &OpenAiConfig) -> Result<Self, Box<dyn Error>> {
+        let mut client = OpenAiClient::new(config.base_url.as_deref().unwrap_or(DEFAULT_BASE_URL))
+            .with_operation_timeouts(config.timeouts);
+        client.api_key = config.api_key.clone();
+        if let Some(model) = &config.vision_model {
+            client.vision_model = model.clone();
+        }
+        if let Some(model) = &config.text_model {
+            client.text_model = model.clone();
+        }
+        match config.timeout {
+            Some(timeout) => client.with_timeout(timeout),
+            None => Ok(client),
+        }
+    }
+
+    // Fail requests that take longer than `timeout` instead of waiting indefinitely
+    pub fn with_timeout(mut self, timeout: Duration) -> Result<Self, Box<dyn Error>> {
+        self.client = Client::builder().timeout(timeout).build()?;
+        Ok(self)
+    }
+
+    pub fn with_api_key(mut self, api_key: impl Into<String>) -> Self {
+        self.api_key = Some(api_key.into());
+        self
+    }
+
+    pub fn with_models(mut self, vision_model: impl Into<String>, text_model: impl Into<String>) -> Self {
+        self.vision_model = vision_model.into();
+        self.text_model = text_model.into();
+        self
+    }
+
+    // Give image tagging and text requests their own timeouts, overriding the client-wide one
+    pub fn with_operation_timeouts(mut self, timeouts: Timeouts) -> Self {
+        self.timeouts = timeouts;
+        self
+    }
+
+    // Send a non-streaming chat completion and return the trimmed text of the first choice
+    async fn chat_completion(&self, payload: &Value, timeout: Option<Duration>) -> Result<String, Box<dyn Error>> {
+        let url = format!("{}/chat/completions", self.base_url);
+        let mut request = self.client.post(&url).json(payload);
+        if let Some(api_key) = &self.api_key {
+            request = request.bearer_auth(api_key);
+        }
+        if let Some(timeout) = timeout {
+            request = request.timeout(timeout);
+        }
+        let response = request.send().await.map_err(|e| match timeout {
+            Some(timeout) if e.is_timeout() => format!("AI server did not answer within {:?}", timeout).into(),
+            _ => Box::<dyn Error>::from(e),
+        })?;
+
+        let status = response.status();
+        if !status.is_success() {
+            let body = response.text().await.unwrap_or_default();
+            return Err(format!("AI server returned {}: {}", status, body.trim()).into());
+        }
+
+        let response_json: Value = response
+            .json()
+            .await
+            .map_err(|e| format!("AI server returned invalid JSON: {}", e))?;
+        let content = &response_json["choices"][0]["message"]["content"];
+        let text = message_text(content).ok_or("AI server response has no message content")?;
+        Ok(text.trim().to_string())
+    }
+}
+
+// The data URL needs the real content type, sniff it from the first decoded bytes
+fn image_mime_type(base64_image: &str) -> &'static str {
+    let prefix = &base64_image[..base64_image.len().min(24) / 4 * 4];
+    BASE64
+        .decode(prefix.as_bytes())
+        .ok()
+        .and_then(|bytes| ingest::detect_format(&bytes))
+        .map(ingest::mime_type)
+        .unwrap_or("image/jpeg")
+}
+
+// Message content is either a string or a list of parts like `{"type": "text", "text": "..."}`;
+// text parts are joined with newlines and anything else is ignored
+fn message_text(content: &Value) -> Option<String> {
+    match content {
+        Value::String(text) => Some(text.clone()),
+        Value::Array(parts) => {
+            let texts: Vec<&str> = parts
+                .iter()
+                .filter_map(|part| match part {
+                    Value::String(text) => Some(text.as_str()),
+                    _ => part["text"].as_str(),
+                })
+                .collect();
+            (!texts.is_empty()).then(|| texts.join("\n"))
+        }
+        _ => None,
+    }
+}
+
+#[async_trait]
+impl Tagger for OpenAiClient {
+    fn vision_model(&self) -> &str {
+        &self.vision_model
+    }
+
+    fn text_model(&self) -> &str {
+        &self.text_model
+    }
+
+    async fn tag_image(&self, base64_image: &str) -> Result<Vec<String>, Box<dyn Error>> {
+        Ok(self.tag_image_with_response(base64_image).await?.0)
+    }
+
+    async fn tag_image_with_response(&self, base64_image: &str) -> Result<(Vec<String>, String), Box<dyn Error>> {
+        let payload = json!({
+            "model": self.vision_model,
+            "stream": false,
+            "messages": [{
+                "role": "user",
+                "content": [
+                    { "type": "text", "text": tagging::IMAGE_TAGGING_PROMPT },
+                    {
+                        "type": "image_url",
+                        "image_url": { "url": format!("data:{};base64,{}", image_mime_type(base64_image), base64_image) }
+                    }
+                ]
+            }]
+        });
+
+        let response = self.chat_completion(&payload, self.timeouts.tag).await?;
+        println!("Tags: {}", response);
+        Ok((tagging::parse_tags(&response), response))
+    }
+
+    async fn tags_for_query(&self, query: &str) -> Result<Vec<String>, Box<dyn Error>> {
+        let payload = json!({
+            "model": self.text_model,
+            "stream": false,
+            "messages": [{ "role": "user", "content": tagging::query_tagging_prompt(query) }]
+        });
+
+        let response = self.chat_completion(&payload, self.timeouts.text).await?;
+        println!("Tags to search: {}", response);
+        Ok(tagging::parse_tags(&response))
+    }
+
+    async fn related_tags(&self, tag: &str) -> Result<Vec<String>, Box<dyn Error>> {
+        let payload = json!({
+            "model": self.text_model,
+            "stream": false,
+            "messages": [{ "role": "user", "content": tagging::related_tags_prompt(tag) }]
+        });
+
+        let response = self.chat_completion(&payload, self.timeouts.text).await?;
+        Ok(tagging::parse_tags(&response))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wiremock::matchers::{body_partial_json, header, method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    async fn mock_completions(response: ResponseTemplate) -> MockServer {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/v1/chat/completions"))
+            .respond_with(response)
+            .mount(&server)
+            .await;
+        server
+    }
+
+    fn completion(content: Value) -> ResponseTemplate {
+        ResponseTemplate::new(200).set_body_json(json!({
+            "choices": [{ "index": 0, "message": { "role": "assistant", "content": content } }]
+        }))
+    }
+
+    #[tokio::test]
+    async fn tags_image_with_data_url_and_api_key() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/v1/chat/completions"))
+            .and(header("authorization", "Bearer secret"))
+            .and(body_partial_json(json!({ "model": "gpt-4o-mini" })))
+            .respond_with(completion(json!("beach, sunset")))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let client = OpenAiClient::new(format!("{}/v1", server.uri()))
+            .with_api_key("secret")
+            .with_models("gpt-4o-mini", "gpt-4o-mini");
+        let tags = client.tag_image("aGVsbG8=").await.unwrap();
+
+        assert_eq!(tags, vec!["beach", "sunset"]);
+        let request = &server.received_requests().await.unwrap()[0];
+        let body: Value = serde_json::from_slice(&request.body).unwrap();
+        assert_eq!(
+            body["messages"][0]["content"][1]["image_url"]["url"],
+            "data:image/jpeg;base64,aGVsbG8="
+        );
+    }
+
+    #[test]
+    fn data_url_uses_detected_mime_type() {
+        let png = BASE64.encode(b"\x89PNG\r\n\x1a\n\x00\x00\x00\x0dIHDR\x00\x00\x00\x01");
+        assert_eq!(image_mime_type(&png), "image/png");
+        assert_eq!(image_mime_type(&BASE64.encode(b"GIF89a\x01\x00")), "image/gif");
+        assert_eq!(image_mime_type(""), "image/jpeg");
+    }
+
+    #[tokio::test]
+    async fn flattens_multi_part_content() {
+        let server = mock_completions(completion(json!([
+            { "type": "text", "text": "cars, street" },
+            { "type": "text", "text": "urban" }
+        ])))
+        .await;
+
+        let client = OpenAiClient::new(format!("{}/v1", server.uri()));
+        let tags = client.tags_for_query("cars on the street").await.unwrap();
+
+        assert_eq!(tags, vec!["cars", "street", "urban"]);
+    }
+
+    #[tokio::test]
+    async fn missing_choices_is_an_error() {
+        let server = mock_completions(ResponseTemplate::new(200).set_body_json(json!({ "choices": [] }))).await;
+
+        let error = OpenAiClient::new(format!("{}/v1", server.uri())).tag_image("").await.unwrap_err();
+
+        assert!(error.to_string().contains("no message content"), "{}", error);
+    }
+
+    #[tokio::test]
+    async fn error_status_is_reported() {
+        let server = mock_completions(ResponseTemplate::new(401).set_body_string("invalid api key")).await;
+
+        let error = OpenAiClient::new(format!("{}/v1", server.uri())).tag_image("").await.unwrap_err();
+
+        assert!(error.to_string().contains("401"), "{}", error);
+    }
+}
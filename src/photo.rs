@@ -0,0 +1,444 @@
+use chrono::NaiveDateTime;
+use serde::{Deserialize, Serialize};
+use sqlx::{PgPool, QueryBuilder};
+
+use crate::ingest::ImageInfo;
+use crate::link::PhotoLink;
+use crate::metadata::ExifMetadata;
+use crate::query::TagExpr;
+use crate::search::SearchFilters;
+use crate::tagging;
+
+// Columns selected into a Photo, shared by every query returning photos. A macro rather than a
+// const so queries can be assembled with `concat!` into `&'static str`.
+macro_rules! photo_columns {
+    () => {
+        "photo_id, file_name, original_file_name, original_path, file_path, tags, width, height, byte_size, content_hash, thumbnail_paths, \
+        captured_at, captured_at_utc, camera_make, camera_model, exif_orientation, gps_latitude, gps_longitude, duplicate_of, quality, locked, created_at"
+    };
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
+pub struct Photo {
+    pub photo_id: i32,
+    // Name of the stored file, which differs from the original when the image was normalized
+    pub file_name: String,
+    // Name of the file as found in the scanned folder, unset for photos indexed before it was kept
+    pub original_file_name: Option<String>,
+    // Canonical path of the file as found in the scanned folder, unset for photos indexed before it was kept
+    pub original_path: Option<String>,
+    pub file_path: String,
+    pub tags: Vec<String>,
+    pub width: Option<i32>,
+    pub height: Option<i32>,
+    pub byte_size: Option<i64>,
+    // Hex SHA-256 of the original file, lets clients skip uploading files that are already indexed
+    pub content_hash: Option<String>,
+    pub thumbnail_paths: Option<Vec<String>>,
+    pub captured_at: Option<NaiveDateTime>,
+    pub captured_at_utc: Option<NaiveDateTime>,
+    pub camera_make: Option<String>,
+    pub camera_model: Option<String>,
+    pub exif_orientation: Option<i16>,
+    pub gps_latitude: Option<f64>,
+    pub gps_longitude: Option<f64>,
+    // Set on copies kept despite having the same content as an existing photo
+    pub duplicate_of: Option<i32>,
+    // Sharpness and exposure score between 0 and 1, see `quality::Quality`
+    pub quality: Option<f32>,
+    // Set with `lock`, see `Photo::set_locked`; missing from exports made before it existed
+    #[serde(default)]
+    pub locked: bool,
+    pub created_at: NaiveDateTime,
+}
+
+// Everything known about a photo before it is inserted
+pub struct NewPhoto<'a> {
+    pub file_name: &'a str,
+    pub original_file_name: &'a str,
+    pub original_path: &'a str,
+    pub file_path: &'a str,
+    pub tags: Vec<String>,
+    pub info: &'a ImageInfo,
+    pub exif: &'a ExifMetadata,
+    pub captured_at_utc: Option<NaiveDateTime>,
+    pub content_hash: &'a str,
+    // The photo this one is a kept copy of
+    pub duplicate_of: Option<i32>,
+    pub quality: f32,
+}
+
+impl Photo {
+    // Function to add a new photo to the database, returning its id, or None when a photo with the
+    // same content hash already exists and this one isn't a kept copy. The unique index makes this
+    // safe against concurrent ingests.
+    pub async fn add_photo(pool: &PgPool, photo: NewPhoto<'_>) -> Result<Option<i32>, sqlx::Error> {
+        let query = "INSERT INTO photos (file_name, file_path, tags, width, height, byte_size, content_hash,
+                captured_at, camera_make, camera_model, exif_orientation, gps_latitude, gps_longitude, original_file_name, tag_keys, captured_at_utc,
+                duplicate_of, quality, original_path)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16, $17, $18, $19)
+            ON CONFLICT (content_hash) WHERE duplicate_of IS NULL DO NOTHING
+            RETURNING photo_id";
+        let tag_keys = tagging::tag_keys(&photo.tags);
+        sqlx::query_scalar(query)
+            .bind(photo.file_name)
+            .bind(photo.file_path)
+            .bind(photo.tags)
+            .bind(photo.info.width as i32)
+            .bind(photo.info.height as i32)
+            .bind(photo.info.byte_size as i64)
+            .bind(photo.content_hash)
+            .bind(photo.exif.captured_at)
+            .bind(&photo.exif.camera_make)
+            .bind(&photo.exif.camera_model)
+            .bind(photo.exif.orientation)
+            .bind(photo.exif.gps_latitude)
+            .bind(photo.exif.gps_longitude)
+            .bind(photo.original_file_name)
+            .bind(tag_keys)
+            .bind(photo.captured_at_utc)
+            .bind(photo.duplicate_of)
+            .bind(photo.quality)
+            .bind(photo.original_path)
+            .fetch_optional(pool)
+            .await
+    }
+
+    // Function to point an existing photo at a new file of the same content, with everything read
+    // from it, keeping the photo's id and `duplicate_of`. Returns false when the photo is gone or
+    // locked.
+    pub async fn replace(pool: &PgPool, photo_id: i32, photo: NewPhoto<'_>) -> Result<bool, sqlx::Error> {
+        let query = "UPDATE photos SET file_name = $1, file_path = $2, tags = $3, width = $4, height = $5, byte_size = $6,
+                captured_at = $7, camera_make = $8, camera_model = $9, exif_orientation = $10, gps_latitude = $11, gps_longitude = $12,
+                original_file_name = $13, tag_keys = $14, captured_at_utc = $15, quality = $16, original_path = $17
+            WHERE photo_id = $18 AND content_hash = $19 AND NOT locked";
+        let tag_keys = tagging::tag_keys(&photo.tags);
+        let result = sqlx::query(query)
+            .bind(photo.file_name)
+            .bind(photo.file_path)
+            .bind(photo.tags)
+            .bind(photo.info.width as i32)
+            .bind(photo.info.height as i32)
+            .bind(photo.info.byte_size as i64)
+            .bind(photo.exif.captured_at)
+            .bind(&photo.exif.camera_make)
+            .bind(&photo.exif.camera_model)
+            .bind(photo.exif.orientation)
+            .bind(photo.exif.gps_latitude)
+            .bind(photo.exif.gps_longitude)
+            .bind(photo.original_file_name)
+            .bind(tag_keys)
+            .bind(photo.captured_at_utc)
+            .bind(photo.quality)
+            .bind(photo.original_path)
+            .bind(photo_id)
+            .bind(photo.content_hash)
+            .execute(pool)
+            .await?;
+
+        Ok(result.rows_affected() == 1)
+    }
+
+    // Function to insert a photo from an export as it was, apart from its id and `duplicate_of`,
+    // which the caller maps to ids on this database, and its thumbnails, which are named after the
+    // id. Returns None when a photo with the same content hash already exists.
+    pub async fn import(pool: &PgPool, photo: &Photo, duplicate_of: Option<i32>) -> Result<Option<i32>, sqlx::Error> {
+        let query = "INSERT INTO photos (file_name, original_file_name, file_path, tags, tag_keys, width, height, byte_size,
+                content_hash, thumbnail_paths, captured_at, captured_at_utc, camera_make, camera_model, exif_orientation,
+                gps_latitude, gps_longitude, duplicate_of, quality, created_at, original_path, locked)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16, $17, $18, $19, $20, $21, $22)
+            ON CONFLICT (content_hash) WHERE duplicate_of IS NULL DO NOTHING
+            RETURNING photo_id";
+        sqlx::query_scalar(query)
+            .bind(&photo.file_name)
+            .bind(&photo.original_file_name)
+            .bind(&photo.file_path)
+            .bind(&photo.tags)
+            .bind(tagging::tag_keys(&photo.tags))
+            .bind(photo.width)
+            .bind(photo.height)
+            .bind(photo.byte_size)
+            .bind(&photo.content_hash)
+            .bind(None::<Vec<String>>)
+            .bind(photo.captured_at)
+            .bind(photo.captured_at_utc)
+            .bind(&photo.camera_make)
+            .bind(&photo.camera_model)
+            .bind(photo.exif_orientation)
+            .bind(photo.gps_latitude)
+            .bind(photo.gps_longitude)
+            .bind(duplicate_of)
+            .bind(photo.quality)
+            .bind(photo.created_at)
+            .bind(&photo.original_path)
+            .bind(photo.locked)
+            .fetch_optional(pool)
+            .await
+    }
+
+    // Function to find the photo with the given content hash, leaving out kept copies of it
+    pub async fn find_by_content_hash(pool: &PgPool, content_hash: &str) -> Result<Option<Photo>, sqlx::Error> {
+        let query = concat!("SELECT ", photo_columns!(), " FROM photos WHERE content_hash = $1 AND duplicate_of IS NULL");
+        sqlx::query_as::<_, Photo>(query)
+            .bind(content_hash)
+            .fetch_optional(pool)
+            .await
+    }
+
+    // Function to find which of the given content hashes are already indexed
+    pub async fn existing_content_hashes(pool: &PgPool, content_hashes: &[String]) -> Result<Vec<String>, sqlx::Error> {
+        let query = "SELECT DISTINCT content_hash FROM photos WHERE content_hash = ANY($1)";
+        sqlx::query_scalar(query)
+            .bind(content_hashes)
+            .fetch_all(pool)
+            .await
+    }
+
+    // Function to store new tags and quality for `tagged`, the photo as it was read before tagging.
+    // The row is locked while it is checked and updated; an ingest may have replaced or deleted the
+    // photo while the model was busy, or it may have been locked with `lock`, then nothing is stored
+    // and None is returned. Otherwise the updated photo is returned.
+    pub async fn retag(pool: &PgPool, tagged: &Photo, tags: &[String], quality: f32) -> Result<Option<Photo>, sqlx::Error> {
+        let mut transaction = pool.begin().await?;
+        let query = "SELECT file_path, content_hash FROM photos WHERE photo_id = $1 AND NOT locked FOR UPDATE";
+        let current: Option<(String, Option<String>)> = sqlx::query_as(query)
+            .bind(tagged.photo_id)
+            .fetch_optional(&mut *transaction)
+            .await?;
+        if current != Some((tagged.file_path.clone(), tagged.content_hash.clone())) {
+            return Ok(None);
+        }
+
+        let query = concat!("UPDATE photos SET tags = $1, tag_keys = $2, quality = $3 WHERE photo_id = $4 RETURNING ", photo_columns!());
+        let photo = sqlx::query_as::<_, Photo>(query)
+            .bind(tags)
+            .bind(tagging::tag_keys(tags))
+            .bind(quality)
+            .bind(tagged.photo_id)
+            .fetch_one(&mut *transaction)
+            .await?;
+        transaction.commit().await?;
+
+        Ok(Some(photo))
+    }
+
+    // Function to point the photos stored at `from` at the file's new location `to`, returning how
+    // many were moved. The file name follows the path. Nothing is moved when one of them is locked,
+    // they share the file.
+    pub async fn move_file_path(pool: &PgPool, from: &str, to: &str) -> Result<u64, sqlx::Error> {
+        let file_name = std::path::Path::new(to)
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let query = "UPDATE photos SET file_path = $1, file_name = $2
+            WHERE file_path = $3 AND NOT EXISTS (SELECT 1 FROM photos WHERE file_path = $3 AND locked)";
+        let result = sqlx::query(query)
+            .bind(to)
+            .bind(file_name)
+            .bind(from)
+            .execute(pool)
+            .await?;
+
+        Ok(result.rows_affected())
+    }
+
+    // Function to record that the original of a photo was moved to `to`. A photo stored as the
+    // original itself follows it, a normalized one keeps its transcoded file. Returns false when the
+    // photo is gone or locked.
+    pub async fn move_original_path(pool: &PgPool, photo_id: i32, to: &str) -> Result<bool, sqlx::Error> {
+        let file_name = std::path::Path::new(to)
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let query = "UPDATE photos SET
+                file_path = CASE WHEN file_path = original_path THEN $1 ELSE file_path END,
+                file_name = CASE WHEN file_path = original_path THEN $2 ELSE file_name END,
+                original_path = $1
+            WHERE photo_id = $3 AND NOT locked";
+        let result = sqlx::query(query)
+            .bind(to)
+            .bind(file_name)
+            .bind(photo_id)
+            .execute(pool)
+            .await?;
+
+        Ok(result.rows_affected() == 1)
+    }
+
+    // Function to lock or unlock a photo, returning false when no photo has the given id. A locked
+    // photo is left alone by reindex, relocate, replacing duplicates and delete until it is unlocked.
+    pub async fn set_locked(pool: &PgPool, photo_id: i32, locked: bool) -> Result<bool, sqlx::Error> {
+        let query = "UPDATE photos SET locked = $1 WHERE photo_id = $2";
+        let result = sqlx::query(query)
+            .bind(locked)
+            .bind(photo_id)
+            .execute(pool)
+            .await?;
+
+        Ok(result.rows_affected() == 1)
+    }
+
+    // Function to record the generated thumbnails of a photo
+    pub async fn set_thumbnail_paths(pool: &PgPool, photo_id: i32, thumbnail_paths: &[String]) -> Result<(), sqlx::Error> {
+        let query = "UPDATE photos SET thumbnail_paths = $1 WHERE photo_id = $2";
+        sqlx::query(query)
+            .bind(thumbnail_paths)
+            .bind(photo_id)
+            .execute(pool)
+            .await?;
+
+        Ok(())
+    }
+
+    // Function to get a single photo by its id
+    pub async fn find_by_id(pool: &PgPool, photo_id: i32) -> Result<Option<Photo>, sqlx::Error> {
+        let query = concat!("SELECT ", photo_columns!(), " FROM photos WHERE photo_id = $1");
+        sqlx::query_as::<_, Photo>(query)
+            .bind(photo_id)
+            .fetch_optional(pool)
+            .await
+    }
+
+    // Function to find the photos with the given file path
+    pub async fn find_by_file_path(pool: &PgPool, file_path: &str) -> Result<Vec<Photo>, sqlx::Error> {
+        let query = concat!("SELECT ", photo_columns!(), " FROM photos WHERE file_path = $1 ORDER BY photo_id");
+        sqlx::query_as::<_, Photo>(query)
+            .bind(file_path)
+            .fetch_all(pool)
+            .await
+    }
+
+    // Function to find the photos indexed from the given file in a scanned folder. Photos from
+    // before source paths were kept only match when they are stored at that path.
+    pub async fn find_by_original_path(pool: &PgPool, original_path: &str) -> Result<Vec<Photo>, sqlx::Error> {
+        let query = concat!(
+            "SELECT ", photo_columns!(), " FROM photos
+            WHERE original_path = $1 OR (original_path IS NULL AND file_path = $1) ORDER BY photo_id"
+        );
+        sqlx::query_as::<_, Photo>(query)
+            .bind(original_path)
+            .fetch_all(pool)
+            .await
+    }
+
+    // Function to delete a photo, returning the removed row if it existed and wasn't locked. The
+    // oldest kept copy of a deleted photo takes its place, the other copies then point at that one.
+    pub async fn delete(pool: &PgPool, photo_id: i32) -> Result<Option<Photo>, sqlx::Error> {
+        let mut transaction = pool.begin().await?;
+        let query = concat!("DELETE FROM photos WHERE photo_id = $1 AND NOT locked RETURNING ", photo_columns!());
+        let photo = sqlx::query_as::<_, Photo>(query)
+            .bind(photo_id)
+            .fetch_optional(&mut *transaction)
+            .await?;
+        // a locked photo keeps its copies
+        if photo.is_none() {
+            return Ok(None);
+        }
+
+        let query = "UPDATE photos SET duplicate_of = NULLIF(promoted.photo_id, photos.photo_id)
+            FROM (SELECT min(photo_id) AS photo_id FROM photos WHERE duplicate_of = $1) promoted
+            WHERE duplicate_of = $1";
+        sqlx::query(query)
+            .bind(photo_id)
+            .execute(&mut *transaction)
+            .await?;
+
+        transaction.commit().await?;
+        Ok(photo)
+    }
+
+    // Function to stream all photos without loading them at once
+    pub fn stream_all(pool: &PgPool) -> impl futures::Stream<Item = Result<Photo, sqlx::Error>> + '_ {
+        let query = concat!("SELECT ", photo_columns!(), " FROM photos ORDER BY photo_id");
+        sqlx::query_as::<_, Photo>(query).fetch(pool)
+    }
+
+    // Function to find the photos sharing the most tags with the given one, excluding itself and
+    // the photos linked to it
+    pub async fn similar(pool: &PgPool, photo_id: i32, limit: i64) -> Result<Vec<Photo>, sqlx::Error> {
+        let query = concat!(
+            "SELECT ", photo_columns!(), " FROM photos,
+                (SELECT tags AS source_tags FROM photos WHERE photo_id = $1) source
+            WHERE photo_id <> ALL($3) AND tags && source_tags
+            ORDER BY cardinality(ARRAY(SELECT unnest(tags) INTERSECT SELECT unnest(source_tags))) DESC, photo_id
+            LIMIT $2"
+        );
+        // edits and crops of the photo share its tags, they would crowd out everything else
+        let linked = PhotoLink::group(pool, photo_id).await?;
+        sqlx::query_as::<_, Photo>(query)
+            .bind(photo_id)
+            .bind(limit)
+            .bind(linked)
+            .fetch_all(pool)
+            .await
+    }
+
+    // Function to search for photos with a tag similar to one of the search tags (trigram
+    // similarity of at least `threshold`), best matches first unless the filters sort otherwise
+    pub async fn search_photos_by_similar_tags(
+        pool: &PgPool,
+        search_tags: &[String],
+        threshold: f64,
+        filters: &SearchFilters,
+    ) -> Result<Vec<Photo>, sqlx::Error> {
+        let search_keys = tagging::tag_keys(search_tags);
+        let mut query = QueryBuilder::new(concat!(
+            "SELECT ", photo_columns!(), " FROM photos p WHERE EXISTS (SELECT 1 FROM unnest(p.tag_keys) tag, unnest("
+        ));
+        query
+            .push_bind(search_keys.clone())
+            .push("::text[]) search WHERE similarity(tag, search) >= ")
+            .push_bind(threshold as f32)
+            .push(")");
+        filters.push_conditions(&mut query);
+        if filters.sort.is_some() {
+            filters.push_order(&mut query);
+        } else {
+            query
+                .push(" ORDER BY (SELECT max(similarity(tag, search)) FROM unnest(p.tag_keys) tag, unnest(")
+                .push_bind(search_keys)
+                .push("::text[]) search) DESC, p.photo_id");
+        }
+
+        query
+            .build_query_as::<Photo>()
+            .fetch_all(pool)
+            .await
+    }
+
+    // Function to search for photos matching a boolean tag expression
+    pub async fn search_photos_by_expression(
+        pool: &PgPool,
+        expr: &TagExpr,
+        filters: &SearchFilters,
+    ) -> Result<Vec<Photo>, sqlx::Error> {
+        let mut query = QueryBuilder::new(concat!("SELECT ", photo_columns!(), " FROM photos p WHERE "));
+        expr.push_sql(&mut query);
+        filters.push_conditions(&mut query);
+        filters.push_order(&mut query);
+
+        query
+            .build_query_as::<Photo>()
+            .fetch_all(pool)
+            .await
+    }
+
+    // Function to search for photos by tags
+    pub async fn search_photos_by_tags(
+        pool: &PgPool,
+        search_tags: Vec<String>,
+        filters: &SearchFilters,
+    ) -> Result<Vec<Photo>, sqlx::Error> {
+        let mut query = QueryBuilder::new(concat!("SELECT ", photo_columns!(), " FROM photos p WHERE TRUE"));
+        if !search_tags.is_empty() {
+            query.push(" AND p.tag_keys && ").push_bind(tagging::tag_keys(&search_tags));
+        }
+        filters.push_conditions(&mut query);
+        filters.push_order(&mut query);
+
+        query
+            .build_query_as::<Photo>()
+            .fetch_all(pool)
+            .await
+    }
+}
@@ -0,0 +1,184 @@
+use chrono::{NaiveDateTime, Timelike, Utc};
+use data_encoding::HEXLOWER;
+use futures::TryStreamExt;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::PgPool;
+
+// One tagging operation: which model produced which tags for a photo, from which prompt and raw
+// answer. Records form a hash chain, each one's hash covers the previous hash, so a record edited
+// or removed later breaks every hash after it. Kept when the photo is deleted.
+#[derive(Debug, Serialize, Deserialize, sqlx::FromRow)]
+pub struct TagProvenance {
+    pub provenance_id: i32,
+    pub photo_id: i32,
+    pub model: String,
+    // hex SHA-256 of the prompt and of the model's raw answer
+    pub prompt_hash: String,
+    pub response_hash: String,
+    pub tags: Vec<String>,
+    pub created_at: NaiveDateTime,
+    // record_hash of the record before this one, unset for the first
+    pub previous_hash: Option<String>,
+    pub record_hash: String,
+}
+
+// Arbitrary key for the advisory lock appending to the chain
+const CHAIN_LOCK: i64 = 0x7461_675f_6368_6169;
+
+impl TagProvenance {
+    // Function to append a tagging operation to the chain
+    pub async fn record(
+        pool: &PgPool,
+        photo_id: i32,
+        model: &str,
+        prompt: &str,
+        response: &str,
+        tags: &[String],
+    ) -> Result<(), sqlx::Error> {
+        let prompt_hash = sha256_hex(prompt.as_bytes());
+        let response_hash = sha256_hex(response.as_bytes());
+        let now = Utc::now().naive_utc();
+        TagProvenance::append(pool, photo_id, model, &prompt_hash, &response_hash, tags, now).await
+    }
+
+    // Function to append a tagging operation from an export, hashed into this database's chain
+    pub async fn import(pool: &PgPool, photo_id: i32, record: &TagProvenance) -> Result<(), sqlx::Error> {
+        let TagProvenance { model, prompt_hash, response_hash, tags, created_at, .. } = record;
+        TagProvenance::append(pool, photo_id, model, prompt_hash, response_hash, tags, *created_at).await
+    }
+
+    async fn append(
+        pool: &PgPool,
+        photo_id: i32,
+        model: &str,
+        prompt_hash: &str,
+        response_hash: &str,
+        tags: &[String],
+        created_at: NaiveDateTime,
+    ) -> Result<(), sqlx::Error> {
+        // the database keeps microseconds, hash exactly what is stored
+        let created_at = created_at.with_nanosecond(created_at.nanosecond() / 1000 * 1000).unwrap_or(created_at);
+
+        // concurrent ingests must not both chain onto the same previous record
+        let mut transaction = pool.begin().await?;
+        sqlx::query("SELECT pg_advisory_xact_lock($1)")
+            .bind(CHAIN_LOCK)
+            .execute(&mut *transaction)
+            .await?;
+        let previous_hash: Option<String> =
+            sqlx::query_scalar("SELECT record_hash FROM tag_provenance ORDER BY provenance_id DESC LIMIT 1")
+                .fetch_optional(&mut *transaction)
+                .await?;
+
+        let record_hash = record_hash(
+            previous_hash.as_deref(),
+            photo_id,
+            model,
+            prompt_hash,
+            response_hash,
+            tags,
+            created_at,
+        );
+        let query = "INSERT INTO tag_provenance (photo_id, model, prompt_hash, response_hash, tags, created_at, previous_hash, record_hash)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8)";
+        sqlx::query(query)
+            .bind(photo_id)
+            .bind(model)
+            .bind(prompt_hash)
+            .bind(response_hash)
+            .bind(tags)
+            .bind(created_at)
+            .bind(&previous_hash)
+            .bind(&record_hash)
+            .execute(&mut *transaction)
+            .await?;
+
+        transaction.commit().await
+    }
+
+    // Function to get the tagging operations of a photo, oldest first
+    pub async fn list_for_photo(pool: &PgPool, photo_id: i32) -> Result<Vec<TagProvenance>, sqlx::Error> {
+        let query = "SELECT provenance_id, photo_id, model, prompt_hash, response_hash, tags, created_at, previous_hash, record_hash
+            FROM tag_provenance WHERE photo_id = $1 ORDER BY provenance_id";
+        sqlx::query_as::<_, TagProvenance>(query)
+            .bind(photo_id)
+            .fetch_all(pool)
+            .await
+    }
+
+    // Function to check the whole chain, returning the id of the first record that doesn't match
+    // its hash or its predecessor, or None when the chain is intact
+    pub async fn verify_chain(pool: &PgPool) -> Result<Option<i32>, sqlx::Error> {
+        let query = "SELECT provenance_id, photo_id, model, prompt_hash, response_hash, tags, created_at, previous_hash, record_hash
+            FROM tag_provenance ORDER BY provenance_id";
+        let mut records = sqlx::query_as::<_, TagProvenance>(query).fetch(pool);
+
+        let mut previous_hash: Option<String> = None;
+        while let Some(record) = records.try_next().await? {
+            if record.previous_hash != previous_hash || record.expected_hash() != record.record_hash {
+                return Ok(Some(record.provenance_id));
+            }
+            previous_hash = Some(record.record_hash);
+        }
+        Ok(None)
+    }
+
+    fn expected_hash(&self) -> String {
+        record_hash(
+            self.previous_hash.as_deref(),
+            self.photo_id,
+            &self.model,
+            &self.prompt_hash,
+            &self.response_hash,
+            &self.tags,
+            self.created_at,
+        )
+    }
+}
+
+fn sha256_hex(bytes: &[u8]) -> String {
+    HEXLOWER.encode(&Sha256::digest(bytes))
+}
+
+// Fields are length-prefixed so no two different records hash the same input
+fn record_hash(
+    previous_hash: Option<&str>,
+    photo_id: i32,
+    model: &str,
+    prompt_hash: &str,
+    response_hash: &str,
+    tags: &[String],
+    created_at: NaiveDateTime,
+) -> String {
+    let mut hasher = Sha256::new();
+    let photo_id = photo_id.to_string();
+    let created_at = created_at.format("%Y-%m-%dT%H:%M:%S%.6f").to_string();
+    let fields = [previous_hash.unwrap_or_default(), &photo_id, model, prompt_hash, response_hash, &created_at];
+    for field in fields.iter().copied().chain(tags.iter().map(String::as_str)) {
+        hasher.update((field.len() as u64).to_be_bytes());
+        hasher.update(field.as_bytes());
+    }
+    HEXLOWER.encode(&hasher.finalize())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDate;
+
+    #[test]
+    fn record_hash_covers_every_field_and_the_previous_record() {
+        let at = NaiveDate::from_ymd_opt(2024, 5, 1).unwrap().and_hms_micro_opt(12, 0, 0, 123456).unwrap();
+        let tags = vec!["beach".to_string(), "sunset".to_string()];
+        let hash = record_hash(None, 7, "llava", "p", "r", &tags, at);
+
+        assert_eq!(hash, record_hash(None, 7, "llava", "p", "r", &tags, at));
+        assert_ne!(hash, record_hash(Some(&hash), 7, "llava", "p", "r", &tags, at));
+        assert_ne!(hash, record_hash(None, 7, "llava:13b", "p", "r", &tags, at));
+        assert_ne!(hash, record_hash(None, 7, "llava", "p", "r", &tags[..1], at));
+        // moving text between fields changes the hash too
+        let merged = vec!["beach, sunset".to_string()];
+        assert_ne!(hash, record_hash(None, 7, "llava", "p", "r", &merged, at));
+    }
+}
@@ -0,0 +1,91 @@
+use image::{DynamicImage, GrayImage};
+
+// Images are scored at this longest edge, so the score does not depend on the resolution
+const ANALYSIS_SIZE: u32 = 512;
+// Laplacian variance at which an image counts as reasonably sharp (score ~0.63)
+const SHARPNESS_SCALE: f64 = 150.0;
+// Share of the score coming from sharpness, the rest is exposure
+const SHARPNESS_WEIGHT: f64 = 0.6;
+
+// Technical quality of an image between 0 and 1, from its sharpness and exposure. Cheap enough
+// to run on every ingest and meant for ranking frames of the same scene against each other, not
+// for judging composition.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Quality {
+    // 0 for a flat or badly blurred image
+    pub sharpness: f64,
+    // 1 for a mid-grey average with no clipped shadows or highlights
+    pub exposure: f64,
+}
+
+impl Quality {
+    pub fn of(image: &DynamicImage) -> Quality {
+        let gray = image.thumbnail(ANALYSIS_SIZE, ANALYSIS_SIZE).to_luma8();
+        Quality {
+            sharpness: 1.0 - (-laplacian_variance(&gray) / SHARPNESS_SCALE).exp(),
+            exposure: exposure(&gray),
+        }
+    }
+
+    pub fn score(&self) -> f64 {
+        SHARPNESS_WEIGHT * self.sharpness + (1.0 - SHARPNESS_WEIGHT) * self.exposure
+    }
+}
+
+// Variance of the 4-neighbour Laplacian, high when the image has crisp edges
+fn laplacian_variance(gray: &GrayImage) -> f64 {
+    let (width, height) = gray.dimensions();
+    if width < 3 || height < 3 {
+        return 0.0;
+    }
+
+    let at = |x: u32, y: u32| f64::from(gray.get_pixel(x, y)[0]);
+    let mut sum = 0.0;
+    let mut sum_of_squares = 0.0;
+    for y in 1..height - 1 {
+        for x in 1..width - 1 {
+            let laplacian = 4.0 * at(x, y) - at(x - 1, y) - at(x + 1, y) - at(x, y - 1) - at(x, y + 1);
+            sum += laplacian;
+            sum_of_squares += laplacian * laplacian;
+        }
+    }
+    let count = f64::from((width - 2) * (height - 2));
+    let mean = sum / count;
+    sum_of_squares / count - mean * mean
+}
+
+fn exposure(gray: &GrayImage) -> f64 {
+    let pixels = gray.as_raw();
+    if pixels.is_empty() {
+        return 0.0;
+    }
+
+    let count = pixels.len() as f64;
+    let mean = pixels.iter().map(|&value| f64::from(value)).sum::<f64>() / count / 255.0;
+    let clipped = pixels.iter().filter(|&&value| value <= 5 || value >= 250).count() as f64 / count;
+    (1.0 - (mean - 0.5).abs() * 2.0) * (1.0 - clipped)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{GrayImage, Luma};
+
+    fn image(pixel: impl Fn(u32, u32) -> u8) -> DynamicImage {
+        DynamicImage::ImageLuma8(GrayImage::from_fn(64, 64, |x, y| Luma([pixel(x, y)])))
+    }
+
+    #[test]
+    fn sharp_well_exposed_images_score_higher() {
+        // fine detail around mid-grey
+        let sharp = Quality::of(&image(|x, y| if (x + y) % 2 == 0 { 90 } else { 170 }));
+        let flat = Quality::of(&image(|_, _| 128));
+        let dark = Quality::of(&image(|x, y| if (x + y) % 2 == 0 { 0 } else { 20 }));
+
+        assert!(sharp.sharpness > 0.9, "{:?}", sharp);
+        assert_eq!(flat.sharpness, 0.0);
+        assert!((flat.exposure - 1.0).abs() < 0.01, "{:?}", flat);
+        assert!(dark.exposure < 0.1, "{:?}", dark);
+        assert!(sharp.score() > flat.score() && flat.score() > dark.score());
+    }
+}
@@ -0,0 +1,249 @@
+use sqlx::{Postgres, QueryBuilder};
+
+use crate::tagging;
+
+// A boolean search over tags, e.g. `"golden retriever" AND beach NOT night`:
+//   - `AND`, `OR` and `NOT` (uppercase) combine terms; `AND` binds tighter than `OR`
+//   - adjacent terms without an operator are ANDed, so `beach NOT night` means beach AND NOT night
+//   - double quotes keep a multi-word tag together, parentheses group
+#[derive(Debug, Clone, PartialEq)]
+pub enum TagExpr {
+    Tag(String),
+    Not(Box<TagExpr>),
+    And(Vec<TagExpr>),
+    Or(Vec<TagExpr>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Term(String),
+    And,
+    Or,
+    Not,
+    Open,
+    Close,
+}
+
+// Whether the query uses the boolean syntax rather than natural language, which goes through the
+// text model instead. Only an uppercase operator outside quotes switches; quotes and parentheses
+// are common in natural language ("kids' party (outdoor)") and mean nothing on their own.
+pub fn is_boolean_query(query: &str) -> bool {
+    tokenize(query).is_ok_and(|tokens| {
+        tokens.iter().any(|token| matches!(token, Token::And | Token::Or | Token::Not))
+    })
+}
+
+impl TagExpr {
+    pub fn parse(query: &str) -> Result<TagExpr, String> {
+        let tokens = tokenize(query)?;
+        let mut parser = Parser { tokens: &tokens, position: 0, depth: 0 };
+        let expr = parser.or()?;
+        match parser.peek() {
+            None => Ok(expr),
+            Some(token) => Err(format!("unexpected {:?} in query", token)),
+        }
+    }
+
+    // Append the expression as a SQL condition on `p.tag_keys`, matching like the other tag filters
+    pub(crate) fn push_sql(&self, query: &mut QueryBuilder<Postgres>) {
+        match self {
+            TagExpr::Tag(tag) => {
+                // photos without tags match no tag, which keeps NOT well-defined
+                query
+                    .push("COALESCE(p.tag_keys @> ")
+                    .push_bind(vec![tagging::tag_key(tag)])
+                    .push(", FALSE)");
+            }
+            TagExpr::Not(expr) => {
+                query.push("NOT ");
+                expr.push_sql(query);
+            }
+            TagExpr::And(exprs) | TagExpr::Or(exprs) => {
+                let separator = if matches!(self, TagExpr::And(_)) { " AND " } else { " OR " };
+                query.push("(");
+                for (i, expr) in exprs.iter().enumerate() {
+                    if i > 0 {
+                        query.push(separator);
+                    }
+                    expr.push_sql(query);
+                }
+                query.push(")");
+            }
+        }
+    }
+}
+
+fn tokenize(query: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = query.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        match c {
+            c if c.is_whitespace() => {
+                chars.next();
+            }
+            '(' | ')' => {
+                chars.next();
+                tokens.push(if c == '(' { Token::Open } else { Token::Close });
+            }
+            '"' => {
+                chars.next();
+                let mut phrase = String::new();
+                let mut closed = false;
+                for c in chars.by_ref() {
+                    if c == '"' {
+                        closed = true;
+                        break;
+                    }
+                    phrase.push(c);
+                }
+                if !closed {
+                    return Err("unterminated quote in query".to_string());
+                }
+                let phrase = phrase.split_whitespace().collect::<Vec<_>>().join(" ");
+                if phrase.is_empty() {
+                    return Err("empty quoted phrase in query".to_string());
+                }
+                tokens.push(Token::Term(phrase));
+            }
+            _ => {
+                let mut word = String::new();
+                while let Some(&c) = chars.peek() {
+                    if c.is_whitespace() || matches!(c, '(' | ')' | '"') {
+                        break;
+                    }
+                    word.push(c);
+                    chars.next();
+                }
+                tokens.push(match word.as_str() {
+                    "AND" => Token::And,
+                    "OR" => Token::Or,
+                    "NOT" => Token::Not,
+                    _ => Token::Term(word),
+                });
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+// Deepest nesting of parentheses and NOTs a query may have; the parser recurses once per level, so
+// a query of thousands of `(` must not reach the end of the stack
+const MAX_DEPTH: usize = 64;
+
+struct Parser<'a> {
+    tokens: &'a [Token],
+    position: usize,
+    depth: usize,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Option<&Token> {
+        let token = self.tokens.get(self.position);
+        self.position += 1;
+        token
+    }
+
+    fn or(&mut self) -> Result<TagExpr, String> {
+        let mut exprs = vec![self.and()?];
+        while self.peek() == Some(&Token::Or) {
+            self.next();
+            exprs.push(self.and()?);
+        }
+        Ok(if exprs.len() == 1 { exprs.remove(0) } else { TagExpr::Or(exprs) })
+    }
+
+    fn and(&mut self) -> Result<TagExpr, String> {
+        let mut exprs = vec![self.unary()?];
+        loop {
+            match self.peek() {
+                Some(Token::And) => {
+                    self.next();
+                }
+                // implicit AND between adjacent terms
+                Some(Token::Term(_) | Token::Not | Token::Open) => {}
+                _ => break,
+            }
+            exprs.push(self.unary()?);
+        }
+        Ok(if exprs.len() == 1 { exprs.remove(0) } else { TagExpr::And(exprs) })
+    }
+
+    fn unary(&mut self) -> Result<TagExpr, String> {
+        if self.depth == MAX_DEPTH {
+            return Err("query nested too deeply".to_string());
+        }
+        self.depth += 1;
+        let expr = match self.next() {
+            Some(Token::Not) => self.unary().map(|expr| TagExpr::Not(Box::new(expr))),
+            Some(Token::Term(term)) => Ok(TagExpr::Tag(term.clone())),
+            Some(Token::Open) => self.or().and_then(|expr| match self.next() {
+                Some(Token::Close) => Ok(expr),
+                _ => Err("missing closing parenthesis in query".to_string()),
+            }),
+            Some(token) => Err(format!("unexpected {:?} in query", token)),
+            None => Err("query ends where a tag was expected".to_string()),
+        };
+        self.depth -= 1;
+        expr
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tag(tag: &str) -> TagExpr {
+        TagExpr::Tag(tag.to_string())
+    }
+
+    #[test]
+    fn parses_phrases_operators_and_implicit_and() {
+        assert_eq!(
+            TagExpr::parse("\"golden  retriever\" AND beach NOT night").unwrap(),
+            TagExpr::And(vec![
+                tag("golden retriever"),
+                tag("beach"),
+                TagExpr::Not(Box::new(tag("night"))),
+            ])
+        );
+    }
+
+    #[test]
+    fn and_binds_tighter_than_or_and_parentheses_group() {
+        assert_eq!(
+            TagExpr::parse("cat OR dog AND beach").unwrap(),
+            TagExpr::Or(vec![tag("cat"), TagExpr::And(vec![tag("dog"), tag("beach")])])
+        );
+        assert_eq!(
+            TagExpr::parse("(cat OR dog) beach").unwrap(),
+            TagExpr::And(vec![TagExpr::Or(vec![tag("cat"), tag("dog")]), tag("beach")])
+        );
+    }
+
+    #[test]
+    fn malformed_queries_are_errors() {
+        for query in ["", "beach AND", "(beach", "beach)", "\"beach", "OR beach", "\"\""] {
+            assert!(TagExpr::parse(query).is_err(), "{:?} should not parse", query);
+        }
+        let nested = format!("{}beach{}", "(".repeat(100_000), ")".repeat(100_000));
+        assert_eq!(TagExpr::parse(&nested), Err("query nested too deeply".to_string()));
+        assert_eq!(TagExpr::parse(&"NOT ".repeat(100_000)), Err("query nested too deeply".to_string()));
+        let allowed = format!("{}beach{}", "(".repeat(MAX_DEPTH - 1), ")".repeat(MAX_DEPTH - 1));
+        assert_eq!(TagExpr::parse(&allowed), Ok(TagExpr::Tag("beach".to_string())));
+    }
+
+    #[test]
+    fn natural_language_is_not_boolean() {
+        assert!(!is_boolean_query("photos of cats and dogs at night"));
+        assert!(!is_boolean_query("\"golden retriever\" at the beach"));
+        assert!(!is_boolean_query("kids' party (outdoor)"));
+        assert!(!is_boolean_query("a sign saying \"NOT here\""));
+        assert!(is_boolean_query("cats OR dogs"));
+        assert!(is_boolean_query("\"golden retriever\" AND (beach OR lake)"));
+        assert!(is_boolean_query("(cats)OR dogs"));
+    }
+}
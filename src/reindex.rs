@@ -0,0 +1,208 @@
+use std::collections::HashSet;
+use std::error::Error;
+use std::time::Instant;
+
+use data_encoding::BASE64;
+use sqlx::{PgPool, Postgres, QueryBuilder};
+
+use crate::events::{self, PhotoEvent};
+use crate::hooks::PostIngestHook;
+use crate::photo::Photo;
+use crate::provenance::TagProvenance;
+use crate::quality::Quality;
+use crate::tagger::Tagger;
+use crate::tagging;
+
+// Which photos a reindex run covers, all of them by default
+#[derive(Debug, Default)]
+pub struct ReindexFilter {
+    pub from_id: Option<i32>,
+    pub to_id: Option<i32>,
+    pub missing_tags: bool,
+}
+
+impl ReindexFilter {
+    async fn photo_ids(&self, pool: &PgPool) -> Result<Vec<i32>, sqlx::Error> {
+        // locked photos keep their tags
+        let mut query = QueryBuilder::<Postgres>::new("SELECT photo_id FROM photos WHERE NOT locked");
+        if let Some(from_id) = self.from_id {
+            query.push(" AND photo_id >= ").push_bind(from_id);
+        }
+        if let Some(to_id) = self.to_id {
+            query.push(" AND photo_id <= ").push_bind(to_id);
+        }
+        if self.missing_tags {
+            query.push(" AND (tags IS NULL OR cardinality(tags) = 0)");
+        }
+        query.push(" ORDER BY photo_id");
+
+        query.build_query_scalar().fetch_all(pool).await
+    }
+}
+
+// Outcome of a reindex run
+#[derive(Debug, Default)]
+pub struct ReindexReport {
+    pub retagged: usize,
+    pub failed: Vec<(i32, String)>,
+}
+
+// Re-run the vision model over already indexed photos and replace their tags, e.g. after switching
+// models. A photo that fails (missing file, model error) is reported and the run continues. The
+// `after_tag` hooks run for every re-tagged photo.
+pub async fn reindex_photos(
+    pool: &PgPool,
+    tagger: &dyn Tagger,
+    hooks: &[Box<dyn PostIngestHook>],
+    filter: &ReindexFilter,
+) -> Result<ReindexReport, Box<dyn Error>> {
+    let photo_ids = filter.photo_ids(pool).await?;
+    let mut report = ReindexReport::default();
+
+    for (i, photo_id) in photo_ids.iter().copied().enumerate() {
+        match reindex_photo(pool, tagger, photo_id).await {
+            Ok(photo) => {
+                report.retagged += 1;
+                println!("[{}/{}] Retagged photo #{}: {:?}", i + 1, photo_ids.len(), photo_id, photo.tags);
+                for hook in hooks {
+                    if let Err(e) = hook.after_tag(pool, &photo).await {
+                        println!("Tag hook failed for photo #{}: {}", photo_id, e);
+                        let failed = format!("{:?}: {}", hook, e);
+                        PhotoEvent::record(pool, photo_id, events::HOOK_FAILED, Some(&failed)).await?;
+                    }
+                }
+            }
+            Err(e) => {
+                println!("[{}/{}] Failed to retag photo #{}: {}", i + 1, photo_ids.len(), photo_id, e);
+                report.failed.push((photo_id, e.to_string()));
+            }
+        }
+    }
+
+    Ok(report)
+}
+
+async fn reindex_photo(pool: &PgPool, tagger: &dyn Tagger, photo_id: i32) -> Result<Photo, Box<dyn Error>> {
+    // the photo may have been deleted since the ids were listed
+    let photo = Photo::find_by_id(pool, photo_id)
+        .await?
+        .ok_or_else(|| format!("photo #{} no longer exists", photo_id))?;
+
+    let buffer = std::fs::read(&photo.file_path)?;
+    let tagging_started = Instant::now();
+    let (tags, response) = tagger.tag_image_with_response(&BASE64.encode(&buffer)).await?;
+    let tagging_time = tagging_started.elapsed();
+
+    // scores weren't computed for photos indexed before they existed
+    let quality = Quality::of(&image::load_from_memory(&buffer)?).score() as f32;
+    let photo = Photo::retag(pool, &photo, &tags, quality)
+        .await?
+        .ok_or_else(|| format!("photo #{} was replaced, deleted or locked while it was being re-tagged", photo_id))?;
+    TagProvenance::record(pool, photo_id, tagger.vision_model(), tagging::IMAGE_TAGGING_PROMPT, &response, &tags).await?;
+
+    let retagged = format!(
+        "{} tags in {} ms with model {}",
+        tags.len(),
+        tagging_time.as_millis(),
+        tagger.vision_model()
+    );
+    PhotoEvent::record(pool, photo_id, events::RETAGGED, Some(&retagged)).await?;
+
+    Ok(photo)
+}
+
+// How far the current model's tags for one photo are from the stored ones
+#[derive(Debug)]
+pub struct TagDrift {
+    pub photo_id: i32,
+    pub stored: Vec<String>,
+    pub current: Vec<String>,
+    // Jaccard similarity of the two tag sets, 1.0 means identical
+    pub similarity: f64,
+}
+
+// Outcome of a drift check over a random sample of photos
+#[derive(Debug, Default)]
+pub struct DriftReport {
+    pub photos: Vec<TagDrift>,
+    pub failed: Vec<(i32, String)>,
+}
+
+impl DriftReport {
+    pub fn mean_similarity(&self) -> Option<f64> {
+        if self.photos.is_empty() {
+            return None;
+        }
+        Some(self.photos.iter().map(|drift| drift.similarity).sum::<f64>() / self.photos.len() as f64)
+    }
+}
+
+// Tag a random sample of indexed photos with the current model and compare the result with their
+// stored tags, without changing anything. A low mean similarity suggests a reindex is worthwhile.
+pub async fn drift_report(pool: &PgPool, tagger: &dyn Tagger, sample: i64) -> Result<DriftReport, Box<dyn Error>> {
+    let photo_ids: Vec<i32> = sqlx::query_scalar("SELECT photo_id FROM photos ORDER BY random() LIMIT $1")
+        .bind(sample)
+        .fetch_all(pool)
+        .await?;
+    let mut report = DriftReport::default();
+
+    for (i, photo_id) in photo_ids.iter().copied().enumerate() {
+        match photo_drift(pool, tagger, photo_id).await {
+            Ok(drift) => {
+                println!("[{}/{}] Photo #{}: similarity {:.2}", i + 1, photo_ids.len(), photo_id, drift.similarity);
+                report.photos.push(drift);
+            }
+            Err(e) => {
+                println!("[{}/{}] Failed to tag photo #{}: {}", i + 1, photo_ids.len(), photo_id, e);
+                report.failed.push((photo_id, e.to_string()));
+            }
+        }
+    }
+
+    Ok(report)
+}
+
+async fn photo_drift(pool: &PgPool, tagger: &dyn Tagger, photo_id: i32) -> Result<TagDrift, Box<dyn Error>> {
+    let photo = Photo::find_by_id(pool, photo_id)
+        .await?
+        .ok_or_else(|| format!("photo #{} no longer exists", photo_id))?;
+
+    let buffer = std::fs::read(&photo.file_path)?;
+    let current = tagger.tag_image(&BASE64.encode(&buffer)).await?;
+
+    Ok(TagDrift {
+        photo_id,
+        similarity: tag_similarity(&photo.tags, &current),
+        stored: photo.tags,
+        current,
+    })
+}
+
+// Compared by tag key like search does, so "Café" and "cafe" count as the same tag
+fn tag_similarity(stored: &[String], current: &[String]) -> f64 {
+    let stored: HashSet<String> = tagging::tag_keys(stored).into_iter().collect();
+    let current: HashSet<String> = tagging::tag_keys(current).into_iter().collect();
+    let union = stored.union(&current).count();
+    if union == 0 {
+        return 1.0;
+    }
+    stored.intersection(&current).count() as f64 / union as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tags(tags: &[&str]) -> Vec<String> {
+        tags.iter().map(|tag| tag.to_string()).collect()
+    }
+
+    #[test]
+    fn similarity_is_jaccard_of_tag_sets() {
+        assert_eq!(tag_similarity(&tags(&["beach", "sunset"]), &tags(&["sunset", "beach"])), 1.0);
+        assert_eq!(tag_similarity(&tags(&["beach", "sunset"]), &tags(&["beach", "ocean"])), 1.0 / 3.0);
+        assert_eq!(tag_similarity(&tags(&["beach"]), &tags(&["forest"])), 0.0);
+        assert_eq!(tag_similarity(&tags(&["café", "Beach"]), &tags(&["cafe", "beach"])), 1.0);
+        assert_eq!(tag_similarity(&[], &[]), 1.0);
+    }
+}
@@ -0,0 +1,280 @@
+use std::error::Error;
+use std::str::FromStr;
+
+use chrono::{Days, NaiveDate};
+use sqlx::{PgPool, Postgres, QueryBuilder};
+
+use crate::expansion;
+use crate::photo::Photo;
+use crate::query::{self, TagExpr};
+use crate::tagger::Tagger;
+use crate::tagging;
+
+pub async fn search_photos_by_tags(pool: &PgPool, tagger: &dyn Tagger, query: &str, filters: &SearchFilters) -> Result<Vec<Photo>, Box<dyn Error>> {
+    // boolean queries name their tags already, no need to ask the model
+    if query::is_boolean_query(query) {
+        let expr = TagExpr::parse(query)?;
+        return Ok(Photo::search_photos_by_expression(pool, &expr, filters).await?);
+    }
+    // get tags from query
+    let mut tags = tagger.tags_for_query(query).await?;
+    if !filters.no_expansion {
+        // expansion only widens the search, go on with the query's own tags when it fails
+        match expansion::expand_tags(pool, tagger, &tags).await {
+            Ok(expanded) => {
+                tags = expanded;
+                println!("Expanded tags: {}", tags.join(", "));
+            }
+            Err(e) => println!("Tag expansion failed, searching for {} only: {}", tags.join(", "), e),
+        }
+    }
+    // search photos by tags
+    let photos = Photo::search_photos_by_tags(pool, tags.clone(), filters).await?;
+    // nothing matched exactly, the query may be misspelled ("bycicle") or inflected differently
+    if photos.is_empty() && !tags.is_empty() && !filters.exact {
+        let threshold = filters.fuzzy_threshold.unwrap_or(SearchFilters::DEFAULT_FUZZY_THRESHOLD);
+        return Ok(Photo::search_photos_by_similar_tags(pool, &tags, threshold, filters).await?);
+    }
+    Ok(photos)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Orientation {
+    Portrait,
+    Landscape,
+    Square,
+}
+
+impl FromStr for Orientation {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "portrait" => Ok(Orientation::Portrait),
+            "landscape" => Ok(Orientation::Landscape),
+            "square" => Ok(Orientation::Square),
+            other => Err(format!("unknown orientation '{}', expected portrait, landscape or square", other)),
+        }
+    }
+}
+
+// Width / height ratio, written as `16:9`, `1.5`, or with an explicit tolerance as `16:9±0.05`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AspectRatio {
+    pub ratio: f64,
+    pub tolerance: f64,
+}
+
+impl AspectRatio {
+    pub const DEFAULT_TOLERANCE: f64 = 0.02;
+}
+
+impl FromStr for AspectRatio {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid aspect ratio '{}', expected e.g. 16:9 or 16:9±0.05", value);
+
+        let (ratio, tolerance) = match value.split_once('±') {
+            Some((ratio, tolerance)) => (ratio, tolerance.parse::<f64>().map_err(|_| invalid())?),
+            None => (value, AspectRatio::DEFAULT_TOLERANCE),
+        };
+        let ratio = match ratio.split_once(':') {
+            Some((width, height)) => {
+                let width = width.parse::<f64>().map_err(|_| invalid())?;
+                let height = height.parse::<f64>().map_err(|_| invalid())?;
+                width / height
+            }
+            None => ratio.parse::<f64>().map_err(|_| invalid())?,
+        };
+
+        if !ratio.is_finite() || ratio <= 0.0 || !tolerance.is_finite() || tolerance < 0.0 {
+            return Err(invalid());
+        }
+        Ok(AspectRatio { ratio, tolerance })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SortKey {
+    Width,
+    Height,
+    ByteSize,
+    CapturedAt,
+    CreatedAt,
+    Quality,
+}
+
+// When a photo was taken, in UTC where its time zone is known. Photos indexed before UTC times
+// were kept, or without an offset or HOME_TIMEZONE, only have the camera's local time.
+const CAPTURED_AT: &str = "COALESCE(p.captured_at_utc, p.captured_at)";
+
+// Result order, written as `size`, `width`, `height`, `captured`, `added` or `quality`, prefixed with `-` for
+// descending, e.g. `--sort=-size` for the largest files first
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Sort {
+    pub key: SortKey,
+    pub descending: bool,
+}
+
+impl Sort {
+    fn column(&self) -> &'static str {
+        match self.key {
+            SortKey::Width => "p.width",
+            SortKey::Height => "p.height",
+            SortKey::ByteSize => "p.byte_size",
+            SortKey::CapturedAt => CAPTURED_AT,
+            SortKey::CreatedAt => "p.created_at",
+            SortKey::Quality => "p.quality",
+        }
+    }
+}
+
+impl FromStr for Sort {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (key, descending) = match value.strip_prefix('-') {
+            Some(key) => (key, true),
+            None => (value, false),
+        };
+        let key = match key {
+            "width" => SortKey::Width,
+            "height" => SortKey::Height,
+            "size" => SortKey::ByteSize,
+            "captured" => SortKey::CapturedAt,
+            "added" => SortKey::CreatedAt,
+            "quality" => SortKey::Quality,
+            other => {
+                return Err(format!(
+                    "unknown sort '{}', expected width, height, size, captured, added or quality",
+                    other
+                ))
+            }
+        };
+        Ok(Sort { key, descending })
+    }
+}
+
+// Optional filters applied on top of the tag match
+#[derive(Debug, Default)]
+pub struct SearchFilters {
+    pub min_width: Option<u32>,
+    pub min_height: Option<u32>,
+    // file size range in bytes, both inclusive
+    pub min_bytes: Option<u64>,
+    pub max_bytes: Option<u64>,
+    pub orientation: Option<Orientation>,
+    pub aspect_ratio: Option<AspectRatio>,
+    // capture date range from EXIF, both days inclusive, compared with the UTC capture time like
+    // the `captured` sort (the camera's local time where the time zone is unknown)
+    pub captured_after: Option<NaiveDate>,
+    pub captured_before: Option<NaiveDate>,
+    // case-insensitive substring of the camera make or model
+    pub camera: Option<String>,
+    // quality score between 0 and 1, photos without a score don't match
+    pub min_quality: Option<f32>,
+    // explicit tags, comma-separated: photos must have every one of `all_tags`, at least one of
+    // `any_tags` and none of `exclude_tags`
+    pub all_tags: Vec<String>,
+    pub any_tags: Vec<String>,
+    pub exclude_tags: Vec<String>,
+    pub sort: Option<Sort>,
+    // disables broadening the query's tags with related tags from the text model
+    pub no_expansion: bool,
+    // disables the fuzzy fallback used when no tag matches exactly
+    pub exact: bool,
+    // trigram similarity a tag needs in the fallback, DEFAULT_FUZZY_THRESHOLD when unset
+    pub fuzzy_threshold: Option<f64>,
+}
+
+impl SearchFilters {
+    // pg_trgm's own default for the `%` operator
+    pub const DEFAULT_FUZZY_THRESHOLD: f64 = 0.3;
+
+    pub(crate) fn push_conditions(&self, query: &mut QueryBuilder<Postgres>) {
+        // the columns are signed, bounds beyond them saturate rather than wrap around
+        if let Some(min_width) = self.min_width {
+            query.push(" AND p.width >= ").push_bind(i32::try_from(min_width).unwrap_or(i32::MAX));
+        }
+        if let Some(min_height) = self.min_height {
+            query.push(" AND p.height >= ").push_bind(i32::try_from(min_height).unwrap_or(i32::MAX));
+        }
+        if let Some(min_bytes) = self.min_bytes {
+            query.push(" AND p.byte_size >= ").push_bind(i64::try_from(min_bytes).unwrap_or(i64::MAX));
+        }
+        if let Some(max_bytes) = self.max_bytes {
+            query.push(" AND p.byte_size <= ").push_bind(i64::try_from(max_bytes).unwrap_or(i64::MAX));
+        }
+        match self.orientation {
+            Some(Orientation::Portrait) => {
+                query.push(" AND p.height > p.width");
+            }
+            Some(Orientation::Landscape) => {
+                query.push(" AND p.width > p.height");
+            }
+            Some(Orientation::Square) => {
+                query.push(" AND p.width = p.height");
+            }
+            None => {}
+        }
+        if let Some(aspect_ratio) = self.aspect_ratio {
+            query
+                .push(" AND ABS(p.width::float8 / NULLIF(p.height, 0) - ")
+                .push_bind(aspect_ratio.ratio)
+                .push(") <= ")
+                .push_bind(aspect_ratio.tolerance);
+        }
+        if let Some(captured_after) = self.captured_after {
+            query.push(format!(" AND {} >= ", CAPTURED_AT)).push_bind(captured_after.and_time(Default::default()));
+        }
+        if let Some(captured_before) = self.captured_before {
+            // before the start of the following day, so the given day is included
+            let end = captured_before.checked_add_days(Days::new(1)).unwrap_or(captured_before);
+            query.push(format!(" AND {} < ", CAPTURED_AT)).push_bind(end.and_time(Default::default()));
+        }
+        if let Some(camera) = &self.camera {
+            let pattern = format!("%{}%", camera.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_"));
+            query
+                .push(" AND (p.camera_make ILIKE ")
+                .push_bind(pattern.clone())
+                .push(" OR p.camera_model ILIKE ")
+                .push_bind(pattern)
+                .push(")");
+        }
+        if let Some(min_quality) = self.min_quality {
+            query.push(" AND p.quality >= ").push_bind(min_quality);
+        }
+        if !self.all_tags.is_empty() {
+            query.push(" AND p.tag_keys @> ").push_bind(tagging::tag_keys(&self.all_tags));
+        }
+        if !self.any_tags.is_empty() {
+            query.push(" AND p.tag_keys && ").push_bind(tagging::tag_keys(&self.any_tags));
+        }
+        if !self.exclude_tags.is_empty() {
+            // photos without tags have nothing to exclude
+            query
+                .push(" AND NOT COALESCE(p.tag_keys && ")
+                .push_bind(tagging::tag_keys(&self.exclude_tags))
+                .push(", FALSE)");
+        }
+    }
+
+    pub(crate) fn push_order(&self, query: &mut QueryBuilder<Postgres>) {
+        if let Some(sort) = self.sort {
+            // photos without the value (e.g. no EXIF date) go last either way
+            let direction = if sort.descending { "DESC NULLS LAST" } else { "ASC NULLS LAST" };
+            query
+                .push(" ORDER BY ")
+                .push(sort.column())
+                .push(" ")
+                .push(direction)
+                .push(", p.photo_id");
+        }
+    }
+}
+
+// Dates are given as YYYY-MM-DD
+pub fn parse_date(value: &str) -> Result<NaiveDate, String> {
+    NaiveDate::parse_from_str(value, "%Y-%m-%d").map_err(|_| format!("invalid date '{}', expected YYYY-MM-DD", value))
+}
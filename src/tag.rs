@@ -0,0 +1,32 @@
+use serde::Serialize;
+use sqlx::{PgPool, Postgres, QueryBuilder};
+
+use crate::tagging;
+
+// A distinct tag and the number of photos carrying it
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct TagCount {
+    pub tag: String,
+    pub count: i64,
+}
+
+impl TagCount {
+    // Function to list all distinct tags, most used first. `prefix` narrows the list for
+    // autocomplete, `min_count` drops rare tags.
+    pub async fn list(pool: &PgPool, prefix: Option<&str>, min_count: i64) -> Result<Vec<TagCount>, sqlx::Error> {
+        // tag_keys holds the folded key of each tag, position for position
+        let mut query = QueryBuilder::<Postgres>::new(
+            "SELECT tag, COUNT(DISTINCT photo_id) AS count FROM photos, unnest(tags, tag_keys) AS t(tag, key) WHERE TRUE",
+        );
+        if let Some(prefix) = prefix {
+            // matched like search, so `cafe` completes to `café`
+            query.push(" AND starts_with(key, ").push_bind(tagging::tag_key(prefix)).push(")");
+        }
+        query
+            .push(" GROUP BY tag HAVING COUNT(DISTINCT photo_id) >= ")
+            .push_bind(min_count)
+            .push(" ORDER BY count DESC, tag");
+
+        query.build_query_as::<TagCount>().fetch_all(pool).await
+    }
+}
@@ -0,0 +1,71 @@
+use std::error::Error;
+use std::time::Duration;
+
+use async_trait::async_trait;
+
+use crate::breaker::CircuitBreaker;
+use crate::config::{AiConfig, Provider};
+use crate::limiter::ConcurrencyLimit;
+use crate::ollama::OllamaClient;
+use crate::openai::OpenAiClient;
+
+// An AI backend able to tag images and turn search queries into tags. Ingest, reindex and search
+// only talk to this trait, so any runtime can be plugged in (or a fake one in tests).
+#[async_trait]
+pub trait Tagger: Send + Sync {
+    // Name of the model used to tag images, recorded in each photo's history
+    fn vision_model(&self) -> &str;
+
+    // Name of the model used for text prompts (query tags, related tags)
+    fn text_model(&self) -> &str;
+
+    // Ask the vision model for tags describing a base64 encoded image
+    async fn tag_image(&self, base64_image: &str) -> Result<Vec<String>, Box<dyn Error>>;
+
+    // Like `tag_image`, also returning the model's raw answer the tags were parsed from, for the
+    // provenance record. Backends that have no raw answer report the tags themselves.
+    async fn tag_image_with_response(&self, base64_image: &str) -> Result<(Vec<String>, String), Box<dyn Error>> {
+        let tags = self.tag_image(base64_image).await?;
+        let response = tags.join(", ");
+        Ok((tags, response))
+    }
+
+    // Given a query from user, get relevant tags from user's search sentence
+    async fn tags_for_query(&self, query: &str) -> Result<Vec<String>, Box<dyn Error>>;
+
+    // Synonyms and closely related tags for a tag, e.g. automobile and vehicle for car
+    async fn related_tags(&self, tag: &str) -> Result<Vec<String>, Box<dyn Error>>;
+}
+
+// Per-operation request timeouts, on top of a client-wide one. Image tagging on a busy GPU can
+// take far longer than turning a query into tags, so one value rarely fits both.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Timeouts {
+    // image tagging
+    pub tag: Option<Duration>,
+    // query tags and related tags
+    pub text: Option<Duration>,
+}
+
+// Build the configured backend, Ollama or any OpenAI-compatible server (OpenAI, LM Studio, vLLM,
+// ...), behind a concurrency limit and a circuit breaker
+pub fn from_config(config: &AiConfig) -> Result<Box<dyn Tagger>, Box<dyn Error>> {
+    let mut tagger: Box<dyn Tagger> = match &config.provider {
+        Provider::Ollama(ollama) => Box::new(OllamaClient::from_config(ollama)?),
+        Provider::OpenAi(openai) => Box::new(OpenAiClient::from_config(openai)?),
+    };
+    if config.max_vision_requests.is_some() || config.max_text_requests.is_some() {
+        tagger = Box::new(ConcurrencyLimit::new(
+            tagger,
+            config.max_vision_requests,
+            config.max_text_requests,
+            config.queue_timeout,
+        ));
+    }
+    // fail fast while the backend is down rather than queueing for a slot first; the breaker
+    // ignores calls that timed out in the queue, they never reached the backend
+    if config.breaker_failures > 0 {
+        tagger = Box::new(CircuitBreaker::new(tagger, config.breaker_failures, config.breaker_cooldown));
+    }
+    Ok(tagger)
+}
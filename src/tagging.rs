@@ -0,0 +1,254 @@
+use serde_json::Value;
+use unicode_normalization::char::is_combining_mark;
+use unicode_normalization::UnicodeNormalization;
+
+// Prompt sent with every image, asking the vision model for comma-separated tags
+pub const IMAGE_TAGGING_PROMPT: &str = "
+You are an image tagging assistant. Your task is to analyze the given image and generate a comma-separated list of relevant tags or keywords that can be used to categorize and search for similar images in a database.
+
+When generating tags, please follow these guidelines:
+
+1. Use concise, descriptive words or short phrases that accurately describe the content of the image.
+2. Avoid using full sentences or unnecessary words in the tags.
+3. Include tags that describe the main subject(s), objects, scenes, activities, emotions, colors, and any other relevant aspects of the image.
+4. Use plural forms for nouns when appropriate (e.g., \"trees\" instead of \"tree\").
+5. Separate each tag with a comma and a space (e.g., \"nature, landscape, trees, mountain\").
+6. Do not include any additional text or explanations beyond the comma-separated list of tags.
+
+Please analyze the provided image and generate a list of relevant tags following the guidelines above.
+";
+
+// Prompt asking a text model to turn a natural-language search query into tags
+pub fn query_tagging_prompt(query: &str) -> String {
+    format!(
+        "You are a photo tagging assistant. Your task is to extract relevant tags from a given search query that can be used to search for photos in a database.
+
+The search query will be provided to you, and you should respond with a comma-separated list of tags that best represent the query.
+
+Here are some examples:
+
+Search query: \"Give me pictures from sunny days\"
+sunny, clear sky, daylight, outdoor, nature
+
+Search query: \"Show me photos of cars on the street\"
+cars, street, urban, transportation
+
+Search query: \"I want to see images of beaches with palm trees\"
+beach, palm trees, tropical, nature, coastline
+
+Remember to keep the tags concise, relevant, and easy to search for in a database. Avoid using full sentences or unnecessary words in the tags. Only output data as comma-separated tags. Do not output anything else.
+
+Search query: \"{}\"",
+        query
+    )
+}
+
+// Prompt asking a text model for tags that photos matching `tag` might carry instead
+pub fn related_tags_prompt(tag: &str) -> String {
+    format!(
+        "You are a photo tagging assistant. Photos in a database are tagged with short keywords, and a search for one tag should also find photos tagged with its synonyms or closely related terms.
+
+Respond with a comma-separated list of up to 5 synonyms or closely related tags for the given tag. Do not repeat the tag itself. Only output data as comma-separated tags. Do not output anything else.
+
+Tag: \"car\"
+automobile, vehicle, sedan
+
+Tag: \"{}\"",
+        tag
+    )
+}
+
+// Longest entry still treated as a tag, anything wordier is chatter from the model
+const MAX_TAG_WORDS: usize = 5;
+
+// Turn a raw model response into a clean list of tags.
+//
+// Models don't reliably stick to "comma-separated tags only": depending on the model and prompt
+// the answer comes back as plain lists, markdown bullet or numbered lists, lists introduced by a
+// sentence ("Sure! Here are the tags:"), or JSON (optionally inside a code fence).
+pub fn parse_tags(response: &str) -> Vec<String> {
+    let response = strip_code_fence(response.trim());
+
+    let candidates = match parse_json_tags(response) {
+        Some(tags) => tags,
+        None => parse_text_tags(response),
+    };
+
+    let mut tags: Vec<String> = Vec::new();
+    for candidate in candidates {
+        if let Some(tag) = clean_tag(&candidate) {
+            if !tags.contains(&tag) {
+                tags.push(tag);
+            }
+        }
+    }
+    tags
+}
+
+fn strip_code_fence(response: &str) -> &str {
+    match response.strip_prefix("```") {
+        Some(fenced) => {
+            // drop the info string of the fence, e.g. ```json
+            let body = fenced.split_once('\n').map(|(_, body)| body).unwrap_or(fenced);
+            body.trim_end().trim_end_matches("```").trim()
+        }
+        None => response,
+    }
+}
+
+// `["a", "b"]`, `{"tags": ["a", "b"]}` or the same embedded in surrounding text
+fn parse_json_tags(response: &str) -> Option<Vec<String>> {
+    let start = response.find(['[', '{'])?;
+    let end = response.rfind([']', '}'])?;
+    if end < start {
+        return None;
+    }
+
+    let value: Value = serde_json::from_str(&response[start..=end]).ok()?;
+    let tags = match &value {
+        Value::Array(_) => &value,
+        Value::Object(object) => ["tags", "keywords", "labels"]
+            .iter()
+            .find_map(|key| object.get(*key))?,
+        _ => return None,
+    };
+
+    match tags {
+        Value::Array(items) => Some(
+            items
+                .iter()
+                .filter_map(|item| item.as_str().map(str::to_string))
+                .collect(),
+        ),
+        // {"tags": "a, b, c"}
+        Value::String(text) => Some(text.split(',').map(str::to_string).collect()),
+        _ => None,
+    }
+}
+
+fn parse_text_tags(response: &str) -> Vec<String> {
+    let mut candidates = Vec::new();
+    for line in response.lines() {
+        let line = strip_list_marker(line.trim());
+        let line = match line.split_once(':') {
+            // "Tags: a, b" or an intro sentence ending in a colon
+            Some((_, rest)) => rest,
+            None => line,
+        };
+        candidates.extend(line.split(',').map(str::to_string));
+    }
+    candidates
+}
+
+// "- tag", "* tag", "• tag", "1. tag", "2) tag"
+fn strip_list_marker(line: &str) -> &str {
+    if let Some(rest) = line
+        .strip_prefix("- ")
+        .or_else(|| line.strip_prefix("* "))
+        .or_else(|| line.strip_prefix("• "))
+    {
+        return rest;
+    }
+
+    let digits = line.len() - line.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+    if digits > 0 {
+        if let Some(rest) = line[digits..]
+            .strip_prefix(". ")
+            .or_else(|| line[digits..].strip_prefix(") "))
+        {
+            return rest;
+        }
+    }
+    line
+}
+
+fn clean_tag(candidate: &str) -> Option<String> {
+    let tag = candidate
+        .trim()
+        .trim_matches(|c: char| matches!(c, '"' | '\'' | '`' | '*' | '.' | '!'))
+        .trim()
+        .to_lowercase();
+
+    if tag.is_empty() || tag.split_whitespace().count() > MAX_TAG_WORDS {
+        return None;
+    }
+    Some(tag)
+}
+
+// Form of a tag used for matching: lowercase with accents stripped, so "Café" matches "cafe".
+// Tags keep their display form, the keys are stored next to them.
+pub fn tag_key(tag: &str) -> String {
+    tag.nfd().filter(|&c| !is_combining_mark(c)).collect::<String>().to_lowercase()
+}
+
+pub fn tag_keys(tags: &[String]) -> Vec<String> {
+    tags.iter().map(|tag| tag_key(tag)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+    use std::fs;
+    use std::path::Path;
+
+    // Every `<name>.txt` under tests/fixtures/tagging is a real model response, `<name>.expected`
+    // holds the tags it must parse to, one per line.
+    #[test]
+    fn parses_golden_model_responses() {
+        let fixtures = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/tagging");
+        let mut checked = 0;
+
+        for entry in fs::read_dir(&fixtures).unwrap() {
+            let path = entry.unwrap().path();
+            if path.extension().and_then(|e| e.to_str()) != Some("txt") {
+                continue;
+            }
+
+            let response = fs::read_to_string(&path).unwrap();
+            let expected: Vec<String> = fs::read_to_string(path.with_extension("expected"))
+                .unwrap()
+                .lines()
+                .map(str::to_string)
+                .collect();
+
+            assert_eq!(parse_tags(&response), expected, "fixture {}", path.display());
+            checked += 1;
+        }
+
+        assert!(checked > 0, "no fixtures found in {}", fixtures.display());
+    }
+
+    #[test]
+    fn tag_keys_ignore_case_and_accents() {
+        assert_eq!(tag_key("Café"), "cafe");
+        assert_eq!(tag_key("cafe\u{301}"), "cafe");
+        assert_eq!(tag_key("Ürlaub am Meer"), "urlaub am meer");
+        assert_eq!(tag_key("東京"), "東京");
+    }
+
+    #[test]
+    fn empty_response_has_no_tags() {
+        assert!(parse_tags("").is_empty());
+        assert!(parse_tags("  \n ").is_empty());
+    }
+
+    proptest! {
+        #[test]
+        fn parsed_tags_are_clean_and_unique(response in any::<String>()) {
+            let tags = parse_tags(&response);
+
+            for (i, tag) in tags.iter().enumerate() {
+                prop_assert!(!tag.is_empty());
+                prop_assert_eq!(tag.trim(), tag.as_str());
+                prop_assert_eq!(&tag.to_lowercase(), tag);
+                prop_assert!(!tags[..i].contains(tag));
+            }
+        }
+
+        #[test]
+        fn list_markers_on_multibyte_lines_do_not_panic(line in "[0-9]{0,3}[.)]?[ ]?[é☃𝄞a-z, ]{0,20}") {
+            let _ = parse_tags(&line);
+        }
+    }
+}
@@ -0,0 +1,51 @@
+use std::error::Error;
+use std::path::{Path, PathBuf};
+
+use image::codecs::jpeg::JpegEncoder;
+use image::DynamicImage;
+
+use crate::disk;
+
+const THUMBNAIL_QUALITY: u8 = 80;
+
+// Thumbnail generation on upload, configured through the environment (see `config`):
+//   THUMBNAIL_SIZES  comma-separated longest-edge sizes in pixels, e.g. 256,1024 (unset disables)
+//   THUMBNAIL_DIR    root of the thumbnail tree (default ./thumbs)
+//
+// Thumbnails are written as `<THUMBNAIL_DIR>/<size>/<photo_id>.jpg`, so the directory can be served
+// as-is under `/thumbs/{size}/{file}` by any static file server.
+#[derive(Debug)]
+pub struct Thumbnails {
+    pub sizes: Vec<u32>,
+    pub output_dir: PathBuf,
+}
+
+impl Thumbnails {
+    // Write one thumbnail per configured size, returning their paths in the order of `sizes`
+    pub fn generate(&self, photo_id: i32, image: &DynamicImage) -> Result<Vec<String>, Box<dyn Error>> {
+        let mut paths = Vec::with_capacity(self.sizes.len());
+        for &size in &self.sizes {
+            let dir = self.output_dir.join(size.to_string());
+            std::fs::create_dir_all(&dir)?;
+
+            // fits the thumbnail within size x size, keeping the aspect ratio
+            let thumbnail = DynamicImage::ImageRgb8(image.thumbnail(size, size).to_rgb8());
+            let mut output = Vec::new();
+            JpegEncoder::new_with_quality(&mut output, THUMBNAIL_QUALITY).encode_image(&thumbnail)?;
+
+            let path = dir.join(format!("{}.jpg", photo_id));
+            disk::write_atomically(&path, &output)?;
+            paths.push(path.canonicalize()?.to_string_lossy().into_owned());
+        }
+        Ok(paths)
+    }
+
+    // Whether `path` lies inside the thumbnail tree
+    pub fn owns(&self, path: &Path) -> bool {
+        match (self.output_dir.canonicalize(), path.canonicalize()) {
+            (Ok(output_dir), Ok(path)) => path.starts_with(output_dir),
+            _ => false,
+        }
+    }
+}
+
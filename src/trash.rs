@@ -0,0 +1,110 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use chrono::{Days, Local, NaiveDate};
+
+// Quarantine for files the tool would otherwise delete (copies of deleted photos, orphaned
+// thumbnails, originals removed after normalization), configured through the environment (see `config`):
+//   TRASH_DIR             where trashed files go (unset deletes them right away)
+//   TRASH_RETENTION_DAYS  how long `purge` keeps them (default 30)
+//
+// Files go to `<TRASH_DIR>/<YYYY-MM-DD>/<parent folder>/<file name>`, by the day they were trashed.
+#[derive(Debug)]
+pub struct Trash {
+    pub dir: PathBuf,
+    pub retention_days: u64,
+}
+
+impl Trash {
+    // Whether `path` lies inside the trash
+    pub fn owns(&self, path: &Path) -> bool {
+        match (self.dir.canonicalize(), path.canonicalize()) {
+            (Ok(dir), Ok(path)) => path.starts_with(dir),
+            _ => false,
+        }
+    }
+
+    // Move `path` into today's trash folder, returning where it ended up
+    pub fn remove(&self, path: &Path) -> io::Result<PathBuf> {
+        // keep the parent folder's name, so thumbnails of different sizes stay apart
+        let mut day_dir = self.dir.join(Local::now().date_naive().to_string());
+        if let Some(parent) = path.parent().and_then(Path::file_name) {
+            day_dir.push(parent);
+        }
+        fs::create_dir_all(&day_dir)?;
+
+        let file_name = path
+            .file_name()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("{} has no file name", path.display())))?;
+        // the same name can be trashed more than once a day
+        let mut target = day_dir.join(file_name);
+        let mut counter = 1;
+        while target.exists() {
+            target = day_dir.join(format!("{}.{}", counter, file_name.to_string_lossy()));
+            counter += 1;
+        }
+
+        // a rename can't cross file systems, copy instead
+        if fs::rename(path, &target).is_err() {
+            fs::copy(path, &target)?;
+            fs::remove_file(path)?;
+        }
+        Ok(target)
+    }
+
+    // Delete the trash folders of days more than `retention_days` ago, returning how many
+    pub fn purge(&self) -> io::Result<usize> {
+        let today = Local::now().date_naive();
+        let cutoff = today.checked_sub_days(Days::new(self.retention_days)).unwrap_or(today);
+
+        // created by the first file trashed
+        let entries = match fs::read_dir(&self.dir) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+            entries => entries?,
+        };
+        let mut purged = 0;
+        for entry in entries {
+            let entry = entry?;
+            let day = entry.file_name().to_str().and_then(|name| name.parse::<NaiveDate>().ok());
+            // anything not named like a day was not put there by `remove`
+            if matches!(day, Some(day) if day < cutoff) && entry.file_type()?.is_dir() {
+                fs::remove_dir_all(entry.path())?;
+                purged += 1;
+            }
+        }
+        Ok(purged)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn trashed_files_are_kept_until_purged() {
+        let root = std::env::temp_dir().join(format!("image-index-ai-trash-{}", std::process::id()));
+        let trash = Trash { dir: root.join("trash"), retention_days: 30 };
+        fs::create_dir_all(&root).unwrap();
+        // nothing trashed yet, the directory doesn't exist
+        assert_eq!(trash.purge().unwrap(), 0);
+        fs::write(root.join("a.jpg"), b"first").unwrap();
+        let first = trash.remove(&root.join("a.jpg")).unwrap();
+        fs::write(root.join("a.jpg"), b"second").unwrap();
+        let second = trash.remove(&root.join("a.jpg")).unwrap();
+
+        assert!(!root.join("a.jpg").exists());
+        assert_ne!(first, second);
+        assert_eq!(fs::read(&first).unwrap(), b"first");
+        assert_eq!(fs::read(&second).unwrap(), b"second");
+
+        let old_day = trash.dir.join("2020-01-01");
+        fs::create_dir_all(&old_day).unwrap();
+        fs::create_dir_all(trash.dir.join("keep-me")).unwrap();
+        assert_eq!(trash.purge().unwrap(), 1);
+        assert!(!old_day.exists());
+        assert!(first.exists() && trash.dir.join("keep-me").exists());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,384 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use notify::{EventKind, RecursiveMode, Watcher};
+use sqlx::PgPool;
+use tokio::sync::mpsc;
+use walkdir::WalkDir;
+
+use crate::disk::Fingerprint;
+use crate::ingest::{self, DuplicatePolicy, IngestOptions, IngestReport};
+use crate::photo::Photo;
+use crate::tagger::Tagger;
+
+// Folders indexed automatically as files appear in them, e.g. a Syncthing share (see `config`):
+//   WATCH_DIRS         comma-separated folders to watch, recursively
+//   WATCH_SETTLE_SECS  how long a file must go without changes before it is ingested (default 2),
+//                      so files still being copied or synced are not read half-written
+//   WATCH_STORAGE_DIR  managed storage; settled files are taken in here once they are indexed,
+//                      under their path relative to the watched folder
+//   WATCH_STORE        how files are taken into WATCH_STORAGE_DIR: `move` (default) empties the
+//                      drop folder, `link` hard-links them and leaves the drop folder as it is
+//   WATCH_DUPLICATES   duplicate policy for watched files, INGEST_DUPLICATES when unset, so an
+//                      automated drop folder can reject duplicates while manual uploads skip them
+#[derive(Debug)]
+pub struct WatchOptions {
+    pub dirs: Vec<PathBuf>,
+    pub settle: Duration,
+    pub storage: Option<Storage>,
+    pub duplicates: Option<DuplicatePolicy>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StoreMode {
+    Move,
+    Link,
+}
+
+impl FromStr for StoreMode {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "move" => Ok(StoreMode::Move),
+            "link" => Ok(StoreMode::Link),
+            other => Err(format!("invalid WATCH_STORE '{}', expected move or link", other)),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Storage {
+    pub dir: PathBuf,
+    pub mode: StoreMode,
+}
+
+// Where a dropped file goes in storage
+enum Place {
+    // a stored file with the same content, so the drop is already stored
+    Stored(PathBuf),
+    // a free path to store it under
+    Free(PathBuf),
+}
+
+impl Storage {
+    // Take `path`, found under the watched folder `watched_dir`, into storage and return where it
+    // is now. A file already stored with the same content is reused, another file of the same name
+    // gets a counter appended.
+    pub fn store(&self, watched_dir: &Path, path: &Path) -> io::Result<PathBuf> {
+        let target = match self.place(watched_dir, path)? {
+            Place::Stored(stored) => {
+                if self.mode == StoreMode::Move {
+                    fs::remove_file(path)?;
+                }
+                return Ok(stored);
+            }
+            Place::Free(target) => target,
+        };
+        let dir = target.parent().unwrap_or(&self.dir);
+        fs::create_dir_all(dir)?;
+
+        match self.mode {
+            StoreMode::Link => fs::hard_link(path, &target)?,
+            StoreMode::Move => {
+                if fs::rename(path, &target).is_err() {
+                    // another file system, copy under a hidden name so a crash mid-copy leaves
+                    // nothing that looks like a stored image
+                    let name = target.file_name().unwrap_or_default().to_string_lossy();
+                    let temp = dir.join(format!(".{}.{}.tmp", name, std::process::id()));
+                    let copied = fs::copy(path, &temp).and_then(|_| fs::rename(&temp, &target));
+                    if let Err(e) = copied {
+                        let _ = fs::remove_file(&temp);
+                        return Err(e);
+                    }
+                    fs::remove_file(path)?;
+                }
+            }
+        }
+        Ok(target)
+    }
+
+    // The stored file holding the same content as `path`, e.g. one linked on an earlier run
+    pub fn stored(&self, watched_dir: &Path, path: &Path) -> io::Result<Option<PathBuf>> {
+        match self.place(watched_dir, path)? {
+            Place::Stored(stored) => Ok(Some(stored)),
+            Place::Free(_) => Ok(None),
+        }
+    }
+
+    // Under the path relative to the watched folder, with a counter while the name is taken by
+    // another file
+    fn place(&self, watched_dir: &Path, path: &Path) -> io::Result<Place> {
+        let relative = path
+            .strip_prefix(watched_dir)
+            .ok()
+            .filter(|relative| !relative.as_os_str().is_empty())
+            .or_else(|| path.file_name().map(Path::new))
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("{} has no file name", path.display())))?;
+        let first = self.dir.join(relative);
+        let dir = first.parent().unwrap_or(&self.dir);
+
+        let stem = first.file_stem().unwrap_or_default().to_string_lossy().into_owned();
+        let extension = first.extension().map(|extension| format!(".{}", extension.to_string_lossy())).unwrap_or_default();
+        let mut dropped = Fingerprint::of(path)?;
+        let mut target = first.clone();
+        let mut counter = 1;
+        while target.exists() {
+            if dropped.matches(&target)? {
+                return Ok(Place::Stored(target));
+            }
+            target = dir.join(format!("{}-{}{}", stem, counter, extension));
+            counter += 1;
+        }
+        Ok(Place::Free(target))
+    }
+
+    fn contains(&self, path: &Path) -> bool {
+        path.starts_with(&self.dir) || path.canonicalize().is_ok_and(|path| path.starts_with(&self.dir))
+    }
+}
+
+impl WatchOptions {
+    // Ingest settled files and take the ones that were indexed into storage when it is
+    // configured. A file that fails (or is skipped) stays where it was dropped and is tried again
+    // on the next start; one that cannot be stored stays indexed where it is.
+    pub async fn ingest(&self, pool: &PgPool, tagger: &dyn Tagger, options: &IngestOptions, paths: Vec<PathBuf>) -> IngestReport {
+        let duplicates = self.duplicates.unwrap_or(options.duplicates);
+        let Some(storage) = &self.storage else {
+            return ingest::ingest_paths_with(pool, tagger, options, duplicates, paths).await;
+        };
+
+        // stored on an earlier run (linked files are still in the drop folder), the stored file
+        // is ingested in their place so it is indexed once even if that run was cut short
+        let mut report = IngestReport::default();
+        let mut to_ingest = Vec::new();
+        for path in paths {
+            match storage.stored(self.watched_dir(&path), &path) {
+                Ok(Some(stored)) => {
+                    if storage.mode == StoreMode::Move {
+                        if let Err(e) = fs::remove_file(&path) {
+                            println!("Failed to remove {}, already stored as {}: {}", path.display(), stored.display(), e);
+                        }
+                    }
+                    to_ingest.push(stored);
+                }
+                Ok(None) => to_ingest.push(path),
+                Err(e) => {
+                    println!("Failed to store {}: {}", path.display(), e);
+                    report.failed.push((path, e.to_string()));
+                }
+            }
+        }
+
+        let ingested = ingest::ingest_paths_with(pool, tagger, options, duplicates, to_ingest).await;
+        report.skipped.extend(ingested.skipped);
+        report.failed.extend(ingested.failed);
+        for (path, photo_id) in ingested.added {
+            // already stored, or an original deleted once it was normalized
+            if storage.contains(&path) || !path.exists() {
+                report.added.push((path, photo_id));
+                continue;
+            }
+            let moved = match storage.store(self.watched_dir(&path), &path).and_then(|stored| stored.canonicalize()) {
+                Ok(stored) => match stored.to_str() {
+                    Some(stored_path) => match Photo::move_original_path(pool, photo_id, stored_path).await {
+                        Ok(true) => Ok(stored),
+                        Ok(false) => Err(format!("photo #{} was deleted or locked meanwhile", photo_id)),
+                        Err(e) => Err(e.to_string()),
+                    },
+                    None => Err(format!("{} is not valid UTF-8", stored.display())),
+                },
+                Err(e) => Err(e.to_string()),
+            };
+            match moved {
+                Ok(stored) => report.added.push((stored, photo_id)),
+                Err(e) => {
+                    println!("Failed to store {}, indexed where it is: {}", path.display(), e);
+                    report.added.push((path, photo_id));
+                }
+            }
+        }
+        report
+    }
+
+    // The watched folder a file was found in, its own folder when it isn't under one as given
+    fn watched_dir<'a>(&'a self, path: &'a Path) -> &'a Path {
+        match self.dirs.iter().find(|dir| path.starts_with(dir)) {
+            Some(dir) => dir.as_path(),
+            None => path.parent().unwrap_or(Path::new("")),
+        }
+    }
+}
+
+// Files seen changing, ingested once they have been quiet for the settle time
+#[derive(Debug, Default)]
+struct Pending {
+    last_change: HashMap<PathBuf, Instant>,
+}
+
+impl Pending {
+    fn touch(&mut self, path: PathBuf, now: Instant) {
+        self.last_change.insert(path, now);
+    }
+
+    fn forget(&mut self, path: &Path) {
+        self.last_change.remove(path);
+    }
+
+    fn take_settled(&mut self, now: Instant, settle: Duration) -> Vec<PathBuf> {
+        let settled: Vec<PathBuf> = self
+            .last_change
+            .iter()
+            .filter(|(_, &changed)| now.duration_since(changed) >= settle)
+            .map(|(path, _)| path.clone())
+            .collect();
+        for path in &settled {
+            self.last_change.remove(path);
+        }
+        settled
+    }
+}
+
+// Index what is already in the watched folders, then keep indexing images as they are added,
+// through the same pipeline as an upload. Runs until the process is stopped.
+pub async fn watch_folders(
+    pool: &PgPool,
+    tagger: &dyn Tagger,
+    options: &IngestOptions,
+    watch: &WatchOptions,
+) -> Result<(), Box<dyn Error>> {
+    if watch.dirs.is_empty() {
+        return Err("no folders to watch, set WATCH_DIRS or pass them as arguments".into());
+    }
+
+    let (sender, mut events) = mpsc::unbounded_channel();
+    let mut watcher = notify::recommended_watcher(move |event| {
+        // the receiver only goes away when watching stops
+        let _ = sender.send(event);
+    })?;
+    for dir in &watch.dirs {
+        watcher.watch(dir, RecursiveMode::Recursive)?;
+        println!("Watching {}", dir.display());
+    }
+
+    // files added while the watcher wasn't running; already indexed ones are skipped
+    for dir in &watch.dirs {
+        let mut paths = Vec::new();
+        let entries = WalkDir::new(dir)
+            .into_iter()
+            .filter_entry(|entry| !watch.storage.as_ref().is_some_and(|storage| storage.contains(entry.path())));
+        for entry in entries {
+            let entry = entry?;
+            if entry.file_type().is_file() && is_candidate(options, entry.path()) {
+                paths.push(entry.into_path());
+            }
+        }
+        let report = watch.ingest(pool, tagger, options, paths).await;
+        println!("Added {} photos from {}", report.added.len(), dir.display());
+    }
+
+    let mut pending = Pending::default();
+    let mut tick = tokio::time::interval(Duration::from_secs(1));
+    loop {
+        tokio::select! {
+            event = events.recv() => {
+                let event = match event {
+                    Some(Ok(event)) => event,
+                    Some(Err(e)) => {
+                        println!("Watch error: {}", e);
+                        continue;
+                    }
+                    None => return Err("file watcher stopped".into()),
+                };
+                for path in event.paths {
+                    match event.kind {
+                        // files taken into storage aren't dropped files again
+                        EventKind::Create(_) | EventKind::Modify(_)
+                            if is_candidate(options, &path)
+                                && !watch.storage.as_ref().is_some_and(|storage| storage.contains(&path)) =>
+                        {
+                            pending.touch(path, Instant::now());
+                        }
+                        EventKind::Remove(_) => pending.forget(&path),
+                        _ => {}
+                    }
+                }
+            }
+            _ = tick.tick() => {
+                // a file renamed away shows up as a change to the old path too
+                let settled: Vec<PathBuf> =
+                    pending.take_settled(Instant::now(), watch.settle).into_iter().filter(|path| path.is_file()).collect();
+                if !settled.is_empty() {
+                    let report = watch.ingest(pool, tagger, options, settled).await;
+                    for (path, photo_id) in report.added {
+                        println!("Indexed {} as photo #{}", path.display(), photo_id);
+                    }
+                }
+            }
+        }
+    }
+}
+
+// Image files outside the directories this tool writes to
+fn is_candidate(options: &IngestOptions, path: &Path) -> bool {
+    ingest::is_image_file(path)
+        && !path.ancestors().skip(1).any(|dir| options.owns(dir))
+        && !path.file_name().is_some_and(|name| name.to_string_lossy().starts_with('.'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn files_are_released_once_they_stop_changing() {
+        let start = Instant::now();
+        let settle = Duration::from_secs(2);
+        let mut pending = Pending::default();
+
+        pending.touch(PathBuf::from("a.jpg"), start);
+        pending.touch(PathBuf::from("b.jpg"), start);
+        pending.touch(PathBuf::from("c.jpg"), start);
+        // still being written
+        pending.touch(PathBuf::from("b.jpg"), start + Duration::from_secs(1));
+        pending.forget(Path::new("c.jpg"));
+
+        assert!(pending.take_settled(start + Duration::from_secs(1), settle).is_empty());
+        assert_eq!(pending.take_settled(start + Duration::from_secs(2), settle), vec![PathBuf::from("a.jpg")]);
+        assert_eq!(pending.take_settled(start + Duration::from_secs(3), settle), vec![PathBuf::from("b.jpg")]);
+        assert!(pending.take_settled(start + Duration::from_secs(10), settle).is_empty());
+    }
+
+    #[test]
+    fn stored_files_keep_their_relative_path_and_never_overwrite() {
+        let root = std::env::temp_dir().join(format!("image-index-ai-store-{}", std::process::id()));
+        let (inbox, storage_dir) = (root.join("inbox"), root.join("storage"));
+        fs::create_dir_all(inbox.join("2024")).unwrap();
+        fs::create_dir_all(&storage_dir).unwrap();
+        let storage = |mode| Storage { dir: storage_dir.clone(), mode };
+
+        fs::write(inbox.join("2024/a.jpg"), b"first").unwrap();
+        let stored = storage(StoreMode::Move).store(&inbox, &inbox.join("2024/a.jpg")).unwrap();
+        assert_eq!(stored, storage_dir.join("2024/a.jpg"));
+        assert!(!inbox.join("2024/a.jpg").exists());
+
+        // same name, other content
+        fs::write(inbox.join("2024/a.jpg"), b"second").unwrap();
+        let stored = storage(StoreMode::Link).store(&inbox, &inbox.join("2024/a.jpg")).unwrap();
+        assert_eq!(stored, storage_dir.join("2024/a-1.jpg"));
+        assert_eq!(fs::read(&stored).unwrap(), b"second");
+        assert!(inbox.join("2024/a.jpg").exists());
+
+        // linked again, e.g. on the next start: the stored file is reused
+        let again = storage(StoreMode::Link).store(&inbox, &inbox.join("2024/a.jpg")).unwrap();
+        assert_eq!(again, stored);
+        assert_eq!(fs::read(storage_dir.join("2024/a.jpg")).unwrap(), b"first");
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
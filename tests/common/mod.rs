@@ -0,0 +1,115 @@
+// Shared setup for the database tests. They run against DATABASE_URL when it is set and are
+// skipped otherwise; each test gets a schema of its own, so tests run in parallel and never see
+// the photos already in that database.
+#![allow(dead_code)]
+
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use async_trait::async_trait;
+use image_index_ai::{create_photos_table, Tagger};
+use sqlx::postgres::PgPoolOptions;
+use sqlx::{Executor, PgPool};
+
+static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+fn unique_name(prefix: &str) -> String {
+    format!("{}_{}_{}", prefix, std::process::id(), COUNTER.fetch_add(1, Ordering::SeqCst))
+}
+
+pub struct TestDb {
+    pub pool: PgPool,
+    admin: PgPool,
+    schema: String,
+}
+
+impl TestDb {
+    // Connect and create the tables in a fresh schema, None when DATABASE_URL is unset
+    pub async fn new() -> Option<TestDb> {
+        let url = match std::env::var("DATABASE_URL") {
+            Ok(url) => url,
+            Err(_) => {
+                eprintln!("DATABASE_URL is not set, skipping database test");
+                return None;
+            }
+        };
+        let admin = PgPoolOptions::new().max_connections(1).connect(&url).await.unwrap();
+
+        // extensions belong to the whole database, create pg_trgm in public once rather than in a
+        // test schema that is dropped afterwards
+        let mut transaction = admin.begin().await.unwrap();
+        transaction.execute("SELECT pg_advisory_xact_lock(7541)").await.unwrap();
+        transaction.execute("CREATE EXTENSION IF NOT EXISTS pg_trgm SCHEMA public").await.unwrap();
+        transaction.commit().await.unwrap();
+
+        let schema = unique_name("image_index_ai_test");
+        admin.execute(format!("CREATE SCHEMA {}", schema).as_str()).await.unwrap();
+        let search_path = format!("SET search_path TO {}, public", schema);
+        let pool = PgPoolOptions::new()
+            .max_connections(4)
+            .after_connect(move |connection, _| {
+                let search_path = search_path.clone();
+                Box::pin(async move {
+                    connection.execute(search_path.as_str()).await?;
+                    Ok(())
+                })
+            })
+            .connect(&url)
+            .await
+            .unwrap();
+        create_photos_table(&pool).await.unwrap();
+
+        Some(TestDb { pool, admin, schema })
+    }
+
+    // Drop the schema; a failed test leaves its schema behind for inspection
+    pub async fn close(self) {
+        self.pool.close().await;
+        self.admin
+            .execute(format!("DROP SCHEMA {} CASCADE", self.schema).as_str())
+            .await
+            .unwrap();
+    }
+}
+
+// An empty directory under the system temp dir
+pub fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(unique_name(&format!("image-index-ai-{}", name)));
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+// Write a small image whose pixels, and so content hash, depend on `seed`
+pub fn write_image(path: &Path, seed: u8) {
+    std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+    image::RgbImage::from_fn(16, 12, |x, y| image::Rgb([seed, x as u8 * 8, y as u8 * 8]))
+        .save(path)
+        .unwrap();
+}
+
+// Tags every image with the same tags and a query with its words
+pub struct FakeTagger;
+
+#[async_trait]
+impl Tagger for FakeTagger {
+    fn vision_model(&self) -> &str {
+        "fake-vision"
+    }
+
+    fn text_model(&self) -> &str {
+        "fake-text"
+    }
+
+    async fn tag_image(&self, _base64_image: &str) -> Result<Vec<String>, Box<dyn Error>> {
+        Ok(vec!["beach".to_string(), "sunset".to_string()])
+    }
+
+    async fn tags_for_query(&self, query: &str) -> Result<Vec<String>, Box<dyn Error>> {
+        Ok(query.split_whitespace().map(str::to_string).collect())
+    }
+
+    async fn related_tags(&self, _tag: &str) -> Result<Vec<String>, Box<dyn Error>> {
+        Ok(Vec::new())
+    }
+}
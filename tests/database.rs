@@ -0,0 +1,764 @@
+mod common;
+
+use std::error::Error;
+
+use async_trait::async_trait;
+use common::{temp_dir, write_image, FakeTagger, TestDb};
+use image_index_ai::events;
+use image_index_ai::ingest::{DuplicatePolicy, ImageInfo, NormalizedFormat, NormalizedLayout};
+use image_index_ai::metadata::ExifMetadata;
+use image_index_ai::photo::NewPhoto;
+use image_index_ai::query::TagExpr;
+use image_index_ai::search::{Orientation, Sort, SortKey};
+use image_index_ai::{
+    delete_photo, export_archive, export_photos, import_archive, import_photos, reindex_photos, relocate_photos, search_photos_by_tags, upload_photos, IngestOptions, LinkKind, Normalization, Photo, PhotoLink, SearchFilters,
+    PhotoEvent, ReindexFilter, Storage, StoreMode, TagCount, TagProvenance, Tagger, WatchOptions, Webhook, WebhookDelivery, WebhookHook,
+};
+use sqlx::PgPool;
+
+async fn add(pool: &PgPool, name: &str, tags: &[&str], width: u32, content_hash: &str, duplicate_of: Option<i32>) -> Option<i32> {
+    let info = ImageInfo { width, height: 100, byte_size: 1000 };
+    let photo = NewPhoto {
+        file_name: name,
+        original_file_name: name,
+        original_path: name,
+        file_path: name,
+        tags: tags.iter().map(|tag| tag.to_string()).collect(),
+        info: &info,
+        exif: &ExifMetadata::default(),
+        captured_at_utc: None,
+        content_hash,
+        duplicate_of,
+        quality: 0.5,
+    };
+    Photo::add_photo(pool, photo).await.unwrap()
+}
+
+fn ids(photos: &[Photo]) -> Vec<i32> {
+    photos.iter().map(|photo| photo.photo_id).collect()
+}
+
+#[tokio::test]
+async fn same_content_is_inserted_once_unless_marked_as_a_copy() {
+    let Some(db) = TestDb::new().await else { return };
+
+    let original = add(&db.pool, "a.jpg", &["beach"], 100, "hash-a", None).await.unwrap();
+    assert_eq!(add(&db.pool, "b.jpg", &["beach"], 100, "hash-a", None).await, None);
+    let copy = add(&db.pool, "c.jpg", &["beach"], 100, "hash-a", Some(original)).await.unwrap();
+    assert_ne!(copy, original);
+
+    // an import of the same content is skipped as well
+    let exported = Photo::find_by_id(&db.pool, original).await.unwrap().unwrap();
+    assert_eq!(Photo::import(&db.pool, &exported, None).await.unwrap(), None);
+    assert_eq!(Photo::find_by_content_hash(&db.pool, "hash-a").await.unwrap().unwrap().photo_id, original);
+
+    db.close().await;
+}
+
+#[tokio::test]
+async fn deleting_a_photo_promotes_its_oldest_copy() {
+    let Some(db) = TestDb::new().await else { return };
+    let original = add(&db.pool, "a.jpg", &["beach"], 100, "hash-a", None).await.unwrap();
+    let first_copy = add(&db.pool, "b.jpg", &["beach"], 100, "hash-a", Some(original)).await.unwrap();
+    let second_copy = add(&db.pool, "c.jpg", &["beach"], 100, "hash-a", Some(original)).await.unwrap();
+
+    assert_eq!(Photo::delete(&db.pool, original).await.unwrap().unwrap().photo_id, original);
+
+    let promoted = Photo::find_by_id(&db.pool, first_copy).await.unwrap().unwrap();
+    assert_eq!(promoted.duplicate_of, None);
+    assert_eq!(Photo::find_by_id(&db.pool, second_copy).await.unwrap().unwrap().duplicate_of, Some(first_copy));
+    assert_eq!(Photo::find_by_content_hash(&db.pool, "hash-a").await.unwrap().unwrap().photo_id, first_copy);
+    assert!(Photo::delete(&db.pool, original).await.unwrap().is_none());
+
+    db.close().await;
+}
+
+#[tokio::test]
+async fn link_groups_follow_links_both_ways_and_stop_at_cycles() {
+    let Some(db) = TestDb::new().await else { return };
+    let raw = add(&db.pool, "a.cr2", &["beach"], 100, "hash-a", None).await.unwrap();
+    let jpeg = add(&db.pool, "a.jpg", &["beach"], 100, "hash-b", None).await.unwrap();
+    let crop = add(&db.pool, "a-crop.jpg", &["beach"], 100, "hash-c", None).await.unwrap();
+    let other = add(&db.pool, "b.jpg", &["beach"], 100, "hash-d", None).await.unwrap();
+
+    PhotoLink::create(&db.pool, jpeg, LinkKind::RawPair, raw).await.unwrap().unwrap();
+    PhotoLink::create(&db.pool, crop, LinkKind::CropOf, jpeg).await.unwrap().unwrap();
+    PhotoLink::create(&db.pool, raw, LinkKind::EditOf, crop).await.unwrap().unwrap();
+    // the reversed pair is the same RAW+JPEG pair
+    assert!(PhotoLink::create(&db.pool, raw, LinkKind::RawPair, jpeg).await.unwrap().is_none());
+
+    assert_eq!(PhotoLink::group(&db.pool, crop).await.unwrap(), vec![raw, jpeg, crop]);
+    assert_eq!(PhotoLink::group(&db.pool, other).await.unwrap(), vec![other]);
+    assert_eq!(PhotoLink::list_group(&db.pool, raw).await.unwrap().len(), 3);
+    assert_eq!(ids(&Photo::similar(&db.pool, raw, 10).await.unwrap()), vec![other]);
+
+    // links go with either photo
+    Photo::delete(&db.pool, jpeg).await.unwrap();
+    assert_eq!(PhotoLink::group(&db.pool, raw).await.unwrap(), vec![raw, crop]);
+
+    db.close().await;
+}
+
+#[tokio::test]
+async fn export_and_import_keep_links() {
+    let (Some(source), Some(target)) = (TestDb::new().await, TestDb::new().await) else { return };
+    let raw = add(&source.pool, "a.cr2", &["beach"], 100, "hash-a", None).await.unwrap();
+    let jpeg = add(&source.pool, "a.jpg", &["beach"], 100, "hash-b", None).await.unwrap();
+    let crop = add(&source.pool, "a-crop.jpg", &["beach"], 100, "hash-c", None).await.unwrap();
+    PhotoLink::create(&source.pool, jpeg, LinkKind::RawPair, raw).await.unwrap().unwrap();
+    PhotoLink::create(&source.pool, crop, LinkKind::CropOf, jpeg).await.unwrap().unwrap();
+    // shift the ids on the target so a link kept by its old ids would point elsewhere
+    add(&target.pool, "unrelated.jpg", &["forest"], 100, "hash-z", None).await.unwrap();
+
+    let mut export = Vec::new();
+    export_photos(&source.pool, &mut export).await.unwrap();
+    let report = import_photos(&target.pool, &IngestOptions::default(), export.as_slice(), None).await.unwrap();
+    assert_eq!((report.imported, report.links_imported), (3, 2));
+
+    let crop = Photo::find_by_file_path(&target.pool, "a-crop.jpg").await.unwrap().remove(0).photo_id;
+    let group = PhotoLink::group(&target.pool, crop).await.unwrap();
+    let mut names = Vec::new();
+    for photo_id in group {
+        names.push(Photo::find_by_id(&target.pool, photo_id).await.unwrap().unwrap().file_path);
+    }
+    names.sort();
+    assert_eq!(names, vec!["a-crop.jpg", "a.cr2", "a.jpg"]);
+
+    let report = import_photos(&target.pool, &IngestOptions::default(), export.as_slice(), None).await.unwrap();
+    assert_eq!((report.skipped, report.links_skipped), (3, 2));
+
+    source.close().await;
+    target.close().await;
+}
+
+#[tokio::test]
+async fn archive_import_restores_files_and_history() {
+    let (Some(source), Some(target)) = (TestDb::new().await, TestDb::new().await) else { return };
+    let folder = temp_dir("archive-source");
+    write_image(&folder.join("a.png"), 1);
+    let path = folder.join("a.png").to_string_lossy().into_owned();
+    let photo_id = add(&source.pool, &path, &["beach"], 100, "hash-a", None).await.unwrap();
+    PhotoEvent::record(&source.pool, photo_id, events::TAGGED, Some("beach")).await.unwrap();
+    TagProvenance::record(&source.pool, photo_id, "llava", "prompt", "beach", &["beach".to_string()]).await.unwrap();
+
+    let mut archive = Vec::new();
+    export_archive(&source.pool, &mut archive).await.unwrap();
+    let restore_to = temp_dir("archive-target");
+    let report = import_archive(&target.pool, &IngestOptions::default(), archive.as_slice(), &restore_to).await.unwrap();
+    assert_eq!(report.imported, 1);
+    assert!(report.missing_files.is_empty());
+
+    let imported = Photo::find_by_content_hash(&target.pool, "hash-a").await.unwrap().unwrap();
+    assert!(imported.file_path.starts_with(restore_to.canonicalize().unwrap().to_str().unwrap()));
+    assert_eq!(std::fs::read(&imported.file_path).unwrap(), std::fs::read(&path).unwrap());
+    let timeline = PhotoEvent::list_for_photo(&target.pool, imported.photo_id).await.unwrap();
+    assert!(timeline.iter().any(|event| event.event == events::TAGGED));
+    assert_eq!(TagProvenance::list_for_photo(&target.pool, imported.photo_id).await.unwrap().len(), 1);
+
+    // a second run reuses the restored file and adds no history
+    let report = import_archive(&target.pool, &IngestOptions::default(), archive.as_slice(), &restore_to).await.unwrap();
+    assert_eq!((report.imported, report.skipped), (0, 1));
+    assert_eq!(std::fs::read_dir(&restore_to).unwrap().count(), 1);
+    assert_eq!(TagProvenance::list_for_photo(&target.pool, imported.photo_id).await.unwrap().len(), 1);
+
+    source.close().await;
+    target.close().await;
+}
+
+#[tokio::test]
+async fn default_timestamps_are_utc_whatever_the_session_time_zone() {
+    let Some(db) = TestDb::new().await else { return };
+    let photo_id = add(&db.pool, "a.jpg", &["beach"], 100, "hash-a", None).await.unwrap();
+    let mut connection = db.pool.acquire().await.unwrap();
+    sqlx::query("SET TIME ZONE 'Pacific/Kiritimati'").execute(&mut *connection).await.unwrap();
+    let created_at: chrono::NaiveDateTime =
+        sqlx::query_scalar("INSERT INTO photo_events (photo_id, event) VALUES ($1, 'uploaded') RETURNING created_at")
+            .bind(photo_id)
+            .fetch_one(&mut *connection)
+            .await
+            .unwrap();
+    sqlx::query("RESET TIME ZONE").execute(&mut *connection).await.unwrap();
+
+    let difference = chrono::Utc::now().naive_utc() - created_at;
+    assert!(difference.num_minutes().abs() < 5, "{}", difference);
+
+    drop(connection);
+    db.close().await;
+}
+
+#[tokio::test]
+async fn retagging_skips_photos_changed_meanwhile() {
+    let Some(db) = TestDb::new().await else { return };
+    let kept = add(&db.pool, "a.jpg", &["beach"], 100, "hash-a", None).await.unwrap();
+    let moved = add(&db.pool, "b.jpg", &["beach"], 100, "hash-b", None).await.unwrap();
+    let deleted = add(&db.pool, "c.jpg", &["beach"], 100, "hash-c", None).await.unwrap();
+    let mut tagged = Vec::new();
+    for photo_id in [kept, moved, deleted] {
+        tagged.push(Photo::find_by_id(&db.pool, photo_id).await.unwrap().unwrap());
+    }
+    // what a replace or delete does while the model is busy
+    Photo::move_file_path(&db.pool, "b.jpg", "b-new.jpg").await.unwrap();
+    Photo::delete(&db.pool, deleted).await.unwrap();
+
+    let tags = vec!["sunset".to_string()];
+    let retagged = Photo::retag(&db.pool, &tagged[0], &tags, 0.9).await.unwrap().unwrap();
+    assert_eq!((retagged.tags, retagged.quality), (tags.clone(), Some(0.9)));
+    assert!(Photo::retag(&db.pool, &tagged[1], &tags, 0.9).await.unwrap().is_none());
+    assert!(Photo::retag(&db.pool, &tagged[2], &tags, 0.9).await.unwrap().is_none());
+    assert_eq!(Photo::find_by_id(&db.pool, moved).await.unwrap().unwrap().tags, vec!["beach"]);
+
+    db.close().await;
+}
+
+#[tokio::test]
+async fn locked_photos_are_left_alone() {
+    let Some(db) = TestDb::new().await else { return };
+    let locked = add(&db.pool, "a.jpg", &["beach"], 100, "hash-a", None).await.unwrap();
+    // a kept copy sharing the locked photo's file
+    let copy = add(&db.pool, "a.jpg", &["beach"], 100, "hash-a", Some(locked)).await.unwrap();
+    let tagged = Photo::find_by_id(&db.pool, locked).await.unwrap().unwrap();
+    assert!(Photo::set_locked(&db.pool, locked, true).await.unwrap());
+    assert!(!Photo::set_locked(&db.pool, locked + 100, true).await.unwrap());
+
+    let tags = vec!["sunset".to_string()];
+    assert!(Photo::retag(&db.pool, &tagged, &tags, 0.9).await.unwrap().is_none());
+    let info = ImageInfo { width: 200, height: 100, byte_size: 1000 };
+    let replacement = NewPhoto {
+        file_name: "b.jpg",
+        original_file_name: "b.jpg",
+        original_path: "b.jpg",
+        file_path: "b.jpg",
+        tags: tags.clone(),
+        info: &info,
+        exif: &ExifMetadata::default(),
+        captured_at_utc: None,
+        content_hash: "hash-a",
+        duplicate_of: None,
+        quality: 0.9,
+    };
+    assert!(!Photo::replace(&db.pool, locked, replacement).await.unwrap());
+    assert!(!Photo::move_original_path(&db.pool, locked, "b.jpg").await.unwrap());
+    // the copy isn't locked, but moving it would move the locked photo's file
+    assert_eq!(Photo::move_file_path(&db.pool, "a.jpg", "b.jpg").await.unwrap(), 0);
+    assert!(Photo::delete(&db.pool, locked).await.unwrap().is_none());
+    let error = delete_photo(&db.pool, &IngestOptions::default(), locked).await.unwrap_err();
+    assert!(error.to_string().contains("locked"));
+    let report = reindex_photos(&db.pool, &FakeTagger, &[], &ReindexFilter { to_id: Some(locked), ..ReindexFilter::default() })
+        .await
+        .unwrap();
+    assert_eq!((report.retagged, report.failed.len()), (0, 0));
+
+    let photo = Photo::find_by_id(&db.pool, locked).await.unwrap().unwrap();
+    assert!(photo.locked);
+    assert_eq!((photo.file_path.as_str(), photo.tags), ("a.jpg", vec!["beach".to_string()]));
+    assert_eq!(Photo::find_by_id(&db.pool, copy).await.unwrap().unwrap().duplicate_of, Some(locked));
+
+    assert!(Photo::set_locked(&db.pool, locked, false).await.unwrap());
+    assert!(Photo::retag(&db.pool, &tagged, &tags, 0.9).await.unwrap().is_some());
+    assert_eq!(Photo::delete(&db.pool, locked).await.unwrap().unwrap().photo_id, locked);
+
+    db.close().await;
+}
+
+#[tokio::test]
+async fn provenance_chain_detects_edited_records() {
+    let Some(db) = TestDb::new().await else { return };
+    for photo_id in 1..=3 {
+        TagProvenance::record(&db.pool, photo_id, "llava", "prompt", "beach, sunset", &["beach".to_string()])
+            .await
+            .unwrap();
+    }
+    assert_eq!(TagProvenance::verify_chain(&db.pool).await.unwrap(), None);
+    let records = TagProvenance::list_for_photo(&db.pool, 2).await.unwrap();
+    assert_eq!(records.len(), 1);
+
+    sqlx::query("UPDATE tag_provenance SET tags = '{people}' WHERE provenance_id = $1")
+        .bind(records[0].provenance_id)
+        .execute(&db.pool)
+        .await
+        .unwrap();
+    assert_eq!(TagProvenance::verify_chain(&db.pool).await.unwrap(), Some(records[0].provenance_id));
+
+    db.close().await;
+}
+
+#[tokio::test]
+async fn capture_date_filter_uses_the_same_time_as_the_sort() {
+    let Some(db) = TestDb::new().await else { return };
+    // 23:30 in New York is the next day in UTC
+    let travel = add(&db.pool, "a.jpg", &["beach"], 100, "hash-a", None).await.unwrap();
+    let home = add(&db.pool, "b.jpg", &["beach"], 100, "hash-b", None).await.unwrap();
+    sqlx::query("UPDATE photos SET captured_at = '2024-01-01 23:30', captured_at_utc = '2024-01-02 04:30' WHERE photo_id = $1")
+        .bind(travel)
+        .execute(&db.pool)
+        .await
+        .unwrap();
+    sqlx::query("UPDATE photos SET captured_at = '2024-01-02 02:00' WHERE photo_id = $1")
+        .bind(home)
+        .execute(&db.pool)
+        .await
+        .unwrap();
+
+    let january_second = SearchFilters {
+        captured_after: chrono::NaiveDate::from_ymd_opt(2024, 1, 2),
+        captured_before: chrono::NaiveDate::from_ymd_opt(2024, 1, 2),
+        sort: Some(Sort { key: SortKey::CapturedAt, descending: false }),
+        no_expansion: true,
+        ..SearchFilters::default()
+    };
+    let found = search_photos_by_tags(&db.pool, &FakeTagger, "beach", &january_second).await.unwrap();
+    assert_eq!(ids(&found), vec![home, travel]);
+
+    db.close().await;
+}
+
+#[tokio::test]
+async fn search_applies_tags_filters_and_order() {
+    let Some(db) = TestDb::new().await else { return };
+    let narrow = add(&db.pool, "a.jpg", &["beach", "Café"], 80, "hash-a", None).await.unwrap();
+    let wide = add(&db.pool, "b.jpg", &["beach", "people"], 300, "hash-b", None).await.unwrap();
+    let widest = add(&db.pool, "c.jpg", &["sunset"], 400, "hash-c", None).await.unwrap();
+    let filters = |filters: SearchFilters| SearchFilters { no_expansion: true, ..filters };
+
+    let found = search_photos_by_tags(&db.pool, &FakeTagger, "beach", &filters(SearchFilters::default()))
+        .await
+        .unwrap();
+    assert_eq!(ids(&found), vec![narrow, wide]);
+
+    let found = search_photos_by_tags(&db.pool, &FakeTagger, "cafe", &filters(SearchFilters::default()))
+        .await
+        .unwrap();
+    assert_eq!(ids(&found), vec![narrow]);
+
+    let landscape = SearchFilters {
+        orientation: Some(Orientation::Landscape),
+        exclude_tags: vec!["people".to_string()],
+        ..SearchFilters::default()
+    };
+    let found = search_photos_by_tags(&db.pool, &FakeTagger, "beach sunset", &filters(landscape)).await.unwrap();
+    assert_eq!(ids(&found), vec![widest]);
+
+    // bounds past the signed columns don't wrap around to match everything
+    let huge = SearchFilters { min_width: Some(u32::MAX), ..SearchFilters::default() };
+    let found = search_photos_by_tags(&db.pool, &FakeTagger, "beach sunset", &filters(huge)).await.unwrap();
+    assert!(found.is_empty());
+    let any_size = SearchFilters { max_bytes: Some(u64::MAX), ..SearchFilters::default() };
+    let found = search_photos_by_tags(&db.pool, &FakeTagger, "beach sunset", &filters(any_size)).await.unwrap();
+    assert_eq!(found.len(), 3);
+
+    let by_width = SearchFilters {
+        sort: Some(Sort { key: SortKey::Width, descending: true }),
+        ..SearchFilters::default()
+    };
+    let found = search_photos_by_tags(&db.pool, &FakeTagger, "beach sunset", &filters(by_width)).await.unwrap();
+    assert_eq!(ids(&found), vec![widest, wide, narrow]);
+
+    // misspelled, only the fuzzy fallback finds it
+    let found = search_photos_by_tags(&db.pool, &FakeTagger, "sunet", &filters(SearchFilters::default()))
+        .await
+        .unwrap();
+    assert_eq!(ids(&found), vec![widest]);
+
+    let expr = TagExpr::parse("beach AND NOT people").unwrap();
+    let found = Photo::search_photos_by_expression(&db.pool, &expr, &SearchFilters::default()).await.unwrap();
+    assert_eq!(ids(&found), vec![narrow]);
+
+    db.close().await;
+}
+
+#[tokio::test]
+async fn tag_prefixes_match_folded_tags() {
+    let Some(db) = TestDb::new().await else { return };
+    add(&db.pool, "a.jpg", &["Café", "beach"], 80, "hash-a", None).await.unwrap();
+    add(&db.pool, "b.jpg", &["cafe terrace"], 80, "hash-b", None).await.unwrap();
+
+    let tags = TagCount::list(&db.pool, Some("CAFE"), 1).await.unwrap();
+    let tags: Vec<&str> = tags.iter().map(|tag| tag.tag.as_str()).collect();
+    assert_eq!(tags, vec!["Café", "cafe terrace"]);
+
+    db.close().await;
+}
+
+// FakeTagger whose text model cannot come up with related tags
+struct NoExpansionTagger;
+
+#[async_trait]
+impl Tagger for NoExpansionTagger {
+    fn vision_model(&self) -> &str {
+        FakeTagger.vision_model()
+    }
+
+    fn text_model(&self) -> &str {
+        FakeTagger.text_model()
+    }
+
+    async fn tag_image(&self, base64_image: &str) -> Result<Vec<String>, Box<dyn Error>> {
+        FakeTagger.tag_image(base64_image).await
+    }
+
+    async fn tags_for_query(&self, query: &str) -> Result<Vec<String>, Box<dyn Error>> {
+        FakeTagger.tags_for_query(query).await
+    }
+
+    async fn related_tags(&self, _tag: &str) -> Result<Vec<String>, Box<dyn Error>> {
+        Err("text model unavailable".into())
+    }
+}
+
+#[tokio::test]
+async fn search_without_expansion_when_it_fails() {
+    let Some(db) = TestDb::new().await else { return };
+    let beach = add(&db.pool, "a.jpg", &["beach"], 80, "hash-a", None).await.unwrap();
+    add(&db.pool, "b.jpg", &["forest"], 80, "hash-b", None).await.unwrap();
+
+    let found = search_photos_by_tags(&db.pool, &NoExpansionTagger, "beach", &SearchFilters::default())
+        .await
+        .unwrap();
+    assert_eq!(ids(&found), vec![beach]);
+
+    db.close().await;
+}
+
+#[tokio::test]
+async fn rescanning_a_folder_skips_indexed_images() {
+    let Some(db) = TestDb::new().await else { return };
+    let folder = temp_dir("rescan");
+    write_image(&folder.join("a.png"), 1);
+    write_image(&folder.join("sub/b.png"), 2);
+    let folder_path = folder.to_str().unwrap();
+
+    let first = upload_photos(&db.pool, &FakeTagger, &IngestOptions::default(), folder_path).await.unwrap();
+    assert_eq!((first.added.len(), first.skipped.len(), first.failed.len()), (2, 0, 0));
+    let second = upload_photos(&db.pool, &FakeTagger, &IngestOptions::default(), folder_path).await.unwrap();
+    assert_eq!((second.added.len(), second.skipped.len(), second.failed.len()), (0, 2, 0));
+
+    let (_, photo_id) = first.added[0];
+    let photo = Photo::find_by_id(&db.pool, photo_id).await.unwrap().unwrap();
+    assert_eq!(photo.tags, vec!["beach", "sunset"]);
+    assert_eq!(TagProvenance::list_for_photo(&db.pool, photo_id).await.unwrap().len(), 1);
+
+    std::fs::remove_dir_all(&folder).unwrap();
+    db.close().await;
+}
+
+#[tokio::test]
+async fn relocating_twice_keeps_hashed_files() {
+    let Some(db) = TestDb::new().await else { return };
+    let folder = temp_dir("relocate");
+    write_image(&folder.join("a.png"), 1);
+    write_image(&folder.join("b.png"), 2);
+    // relative like the default ./normalized, stored paths are canonical
+    let output_dir = std::path::PathBuf::from(format!("target/relocate-test-{}", std::process::id()));
+    std::fs::create_dir_all(&output_dir).unwrap();
+    let options = |layout| IngestOptions {
+        normalization: Some(Normalization {
+            format: NormalizedFormat::Png,
+            output_dir: output_dir.clone(),
+            keep_originals: false,
+            layout,
+        }),
+        ..IngestOptions::default()
+    };
+    let named = upload_photos(&db.pool, &FakeTagger, &options(NormalizedLayout::Named), folder.to_str().unwrap())
+        .await
+        .unwrap();
+    assert_eq!(named.added.len(), 2);
+    write_image(&folder.join("c.png"), 3);
+    let hashed = options(NormalizedLayout::Hashed);
+    upload_photos(&db.pool, &FakeTagger, &hashed, folder.to_str().unwrap()).await.unwrap();
+
+    assert_eq!(relocate_photos(&db.pool, &hashed).await.unwrap(), 2);
+    assert_eq!(relocate_photos(&db.pool, &hashed).await.unwrap(), 0);
+
+    let photos: Vec<Photo> = futures::TryStreamExt::try_collect(Photo::stream_all(&db.pool)).await.unwrap();
+    assert_eq!(photos.len(), 3);
+    for photo in &photos {
+        let expected = hashed.normalization.as_ref().unwrap().hashed_path(photo.content_hash.as_ref().unwrap());
+        assert_eq!(std::path::Path::new(&photo.file_path), expected.canonicalize().unwrap());
+    }
+    // only the hashed files are left
+    assert_eq!(walkdir::WalkDir::new(&output_dir).into_iter().filter(|entry| entry.as_ref().unwrap().file_type().is_file()).count(), 3);
+
+    std::fs::remove_dir_all(&folder).unwrap();
+    std::fs::remove_dir_all(&output_dir).unwrap();
+    db.close().await;
+}
+
+#[tokio::test]
+async fn webhook_deliveries_are_logged_for_every_event() {
+    use wiremock::matchers::method;
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    let Some(db) = TestDb::new().await else { return };
+    let server = MockServer::start().await;
+    Mock::given(method("POST")).respond_with(ResponseTemplate::new(500)).mount(&server).await;
+    let all_events: Vec<String> = ["photo.created", "photo.tagged", "photo.deleted"].iter().map(|event| event.to_string()).collect();
+    Webhook::create(&db.pool, &server.uri(), Some("s3cret"), &all_events).await.unwrap();
+    // neither a removed nor a disabled webhook is posted to, nor one for other events
+    let removed = Webhook::create(&db.pool, "http://127.0.0.1:9/removed", None, &all_events).await.unwrap();
+    assert!(Webhook::delete(&db.pool, removed.webhook_id).await.unwrap());
+    let disabled = Webhook::create(&db.pool, "http://127.0.0.1:9/disabled", None, &all_events).await.unwrap();
+    assert!(Webhook::set_enabled(&db.pool, disabled.webhook_id, false).await.unwrap());
+    Webhook::create(&db.pool, "http://127.0.0.1:9/other", None, &[]).await.unwrap();
+    let mut hook = WebhookHook::new();
+    hook.attempts = 2;
+    hook.retry_delay = std::time::Duration::from_millis(10);
+    let options = IngestOptions { hooks: vec![Box::new(hook)], ..IngestOptions::default() };
+    let folder = temp_dir("webhook");
+    write_image(&folder.join("a.png"), 1);
+
+    let report = upload_photos(&db.pool, &FakeTagger, &options, folder.to_str().unwrap()).await.unwrap();
+    let (_, photo_id) = report.added[0];
+    options.hooks[0].finish().await;
+    reindex_photos(&db.pool, &FakeTagger, &options.hooks, &ReindexFilter::default()).await.unwrap();
+    options.hooks[0].finish().await;
+
+    let failures: Vec<String> = PhotoEvent::list_for_photo(&db.pool, photo_id)
+        .await
+        .unwrap()
+        .into_iter()
+        .filter(|event| event.event == "hook_failed")
+        .filter_map(|event| event.detail)
+        .collect();
+    assert_eq!(failures.len(), 2);
+    assert!(failures.iter().all(|failure| !failure.contains("s3cret")), "{:?}", failures);
+
+    delete_photo(&db.pool, &options, photo_id).await.unwrap().unwrap();
+    options.hooks[0].finish().await;
+    let deliveries = WebhookDelivery::list_for_photo(&db.pool, photo_id).await.unwrap();
+    let logged: Vec<(&str, &str, i32, Option<i16>)> = deliveries
+        .iter()
+        .map(|delivery| (delivery.event.as_str(), delivery.url.as_str(), delivery.attempt, delivery.status))
+        .collect();
+    let url = server.uri();
+    assert_eq!(
+        logged,
+        vec![
+            ("photo.created", url.as_str(), 1, Some(500)),
+            ("photo.created", url.as_str(), 2, Some(500)),
+            ("photo.tagged", url.as_str(), 1, Some(500)),
+            ("photo.tagged", url.as_str(), 2, Some(500)),
+            ("photo.deleted", url.as_str(), 1, Some(500)),
+            ("photo.deleted", url.as_str(), 2, Some(500)),
+        ]
+    );
+
+    std::fs::remove_dir_all(&folder).unwrap();
+    db.close().await;
+}
+
+#[tokio::test]
+async fn slow_webhooks_do_not_hold_up_ingest() {
+    use std::time::{Duration, Instant};
+    use wiremock::matchers::method;
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    let Some(db) = TestDb::new().await else { return };
+    let server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .respond_with(ResponseTemplate::new(204).set_delay(Duration::from_secs(2)))
+        .mount(&server)
+        .await;
+    Webhook::create(&db.pool, &server.uri(), None, &["photo.created".to_string()]).await.unwrap();
+    let options = IngestOptions { hooks: vec![Box::new(WebhookHook::new())], ..IngestOptions::default() };
+    let folder = temp_dir("slow-webhook");
+    write_image(&folder.join("a.png"), 1);
+    write_image(&folder.join("b.png"), 2);
+
+    let started = Instant::now();
+    let report = upload_photos(&db.pool, &FakeTagger, &options, folder.to_str().unwrap()).await.unwrap();
+    assert_eq!(report.added.len(), 2);
+    assert!(started.elapsed() < Duration::from_secs(2), "{:?}", started.elapsed());
+
+    options.hooks[0].finish().await;
+    for (_, photo_id) in report.added {
+        let deliveries = WebhookDelivery::list_for_photo(&db.pool, photo_id).await.unwrap();
+        assert_eq!(deliveries.iter().map(|delivery| delivery.status).collect::<Vec<_>>(), vec![Some(204)]);
+    }
+
+    std::fs::remove_dir_all(&folder).unwrap();
+    db.close().await;
+}
+
+#[tokio::test]
+async fn rescanning_with_keep_both_and_normalization_adds_no_copies() {
+    let Some(db) = TestDb::new().await else { return };
+    let folder = temp_dir("keep-both");
+    write_image(&folder.join("a.png"), 1);
+    write_image(&folder.join("copy/a.png"), 1);
+    let output_dir = temp_dir("keep-both-normalized");
+    let options = IngestOptions {
+        normalization: Some(Normalization {
+            format: NormalizedFormat::Jpeg { quality: 85 },
+            output_dir: output_dir.clone(),
+            keep_originals: true,
+            layout: NormalizedLayout::Named,
+        }),
+        duplicates: DuplicatePolicy::KeepBoth,
+        ..IngestOptions::default()
+    };
+
+    let first = upload_photos(&db.pool, &FakeTagger, &options, folder.to_str().unwrap()).await.unwrap();
+    assert_eq!((first.added.len(), first.skipped.len()), (2, 0));
+    let second = upload_photos(&db.pool, &FakeTagger, &options, folder.to_str().unwrap()).await.unwrap();
+    assert_eq!((second.added.len(), second.skipped.len()), (0, 2));
+
+    let photos: Vec<Photo> = futures::TryStreamExt::try_collect(Photo::stream_all(&db.pool)).await.unwrap();
+    assert_eq!(photos.len(), 2);
+    assert_eq!(photos[1].duplicate_of, Some(photos[0].photo_id));
+    assert_eq!(std::fs::read_dir(&output_dir).unwrap().count(), 2);
+
+    std::fs::remove_dir_all(&folder).unwrap();
+    std::fs::remove_dir_all(&output_dir).unwrap();
+    db.close().await;
+}
+
+// FakeTagger whose vision model is down
+struct DownTagger;
+
+#[async_trait]
+impl Tagger for DownTagger {
+    fn vision_model(&self) -> &str {
+        FakeTagger.vision_model()
+    }
+
+    fn text_model(&self) -> &str {
+        FakeTagger.text_model()
+    }
+
+    async fn tag_image(&self, _base64_image: &str) -> Result<Vec<String>, Box<dyn Error>> {
+        Err("vision model unavailable".into())
+    }
+
+    async fn tags_for_query(&self, query: &str) -> Result<Vec<String>, Box<dyn Error>> {
+        FakeTagger.tags_for_query(query).await
+    }
+
+    async fn related_tags(&self, tag: &str) -> Result<Vec<String>, Box<dyn Error>> {
+        FakeTagger.related_tags(tag).await
+    }
+}
+
+#[tokio::test]
+async fn watched_files_are_stored_only_once_indexed() {
+    let Some(db) = TestDb::new().await else { return };
+    let inbox = temp_dir("watch-inbox");
+    let storage_dir = temp_dir("watch-storage").canonicalize().unwrap();
+    let dropped = inbox.join("2024/a.png");
+    write_image(&dropped, 1);
+    let watch = WatchOptions {
+        dirs: vec![inbox.clone()],
+        settle: std::time::Duration::ZERO,
+        storage: Some(Storage { dir: storage_dir.clone(), mode: StoreMode::Move }),
+        duplicates: None,
+    };
+
+    let report = watch.ingest(&db.pool, &DownTagger, &IngestOptions::default(), vec![dropped.clone()]).await;
+    assert_eq!((report.added.len(), report.failed.len()), (0, 1));
+    assert!(dropped.exists());
+    assert_eq!(std::fs::read_dir(&storage_dir).unwrap().count(), 0);
+
+    // picked up again on the next start
+    let report = watch.ingest(&db.pool, &FakeTagger, &IngestOptions::default(), vec![dropped.clone()]).await;
+    let (stored, photo_id) = report.added[0].clone();
+    assert_eq!(stored, storage_dir.join("2024/a.png"));
+    assert!(!dropped.exists());
+    let photo = Photo::find_by_id(&db.pool, photo_id).await.unwrap().unwrap();
+    assert_eq!(photo.file_path, stored.to_str().unwrap());
+    assert_eq!(photo.original_path.as_deref(), stored.to_str());
+
+    std::fs::remove_dir_all(&inbox).unwrap();
+    std::fs::remove_dir_all(&storage_dir).unwrap();
+    db.close().await;
+}
+
+#[tokio::test]
+async fn failed_images_leave_no_transcoded_file() {
+    let Some(db) = TestDb::new().await else { return };
+    let (folder, output_dir) = (temp_dir("normalize-failed"), temp_dir("normalize-failed-output"));
+    write_image(&folder.join("a.png"), 1);
+    let options = IngestOptions {
+        normalization: Some(Normalization {
+            format: NormalizedFormat::Png,
+            output_dir: output_dir.clone(),
+            keep_originals: true,
+            layout: NormalizedLayout::Named,
+        }),
+        ..IngestOptions::default()
+    };
+
+    let report = upload_photos(&db.pool, &DownTagger, &options, folder.to_str().unwrap()).await.unwrap();
+    assert_eq!((report.added.len(), report.failed.len()), (0, 1));
+    assert_eq!(std::fs::read_dir(&output_dir).unwrap().count(), 0);
+
+    std::fs::remove_dir_all(&folder).unwrap();
+    std::fs::remove_dir_all(&output_dir).unwrap();
+    db.close().await;
+}
+
+#[tokio::test]
+async fn mismatched_content_is_a_failure() {
+    let Some(db) = TestDb::new().await else { return };
+    let folder = temp_dir("mismatched");
+    std::fs::write(folder.join("a.png"), b"<script>alert(1)</script>").unwrap();
+
+    let report = upload_photos(&db.pool, &FakeTagger, &IngestOptions::default(), folder.to_str().unwrap()).await.unwrap();
+    assert_eq!((report.added.len(), report.skipped.len(), report.failed.len()), (0, 0, 1));
+    assert!(report.failed[0].1.contains("not a supported image"));
+
+    std::fs::remove_dir_all(&folder).unwrap();
+    db.close().await;
+}
+
+#[tokio::test]
+async fn rejecting_duplicates_skips_the_files_already_indexed() {
+    let Some(db) = TestDb::new().await else { return };
+    let (folder, other) = (temp_dir("reject"), temp_dir("reject-other"));
+    write_image(&folder.join("a.png"), 1);
+    write_image(&other.join("a.png"), 1);
+    let options = IngestOptions { duplicates: DuplicatePolicy::Reject, ..IngestOptions::default() };
+
+    let first = upload_photos(&db.pool, &FakeTagger, &options, folder.to_str().unwrap()).await.unwrap();
+    assert_eq!(first.added.len(), 1);
+    let again = upload_photos(&db.pool, &FakeTagger, &options, folder.to_str().unwrap()).await.unwrap();
+    assert_eq!((again.added.len(), again.skipped.len(), again.failed.len()), (0, 1, 0));
+    let copy = upload_photos(&db.pool, &FakeTagger, &options, other.to_str().unwrap()).await.unwrap();
+    assert_eq!((copy.added.len(), copy.skipped.len(), copy.failed.len()), (0, 0, 1));
+    assert!(copy.failed[0].1.contains(&format!("photo #{}", first.added[0].1)));
+
+    std::fs::remove_dir_all(&folder).unwrap();
+    std::fs::remove_dir_all(&other).unwrap();
+    db.close().await;
+}
+
+#[tokio::test]
+async fn replacing_keeps_the_photo_id_links_and_events() {
+    let Some(db) = TestDb::new().await else { return };
+    let (folder, other) = (temp_dir("replace"), temp_dir("replace-other"));
+    write_image(&folder.join("a.png"), 1);
+    write_image(&other.join("moved.png"), 1);
+    let first = upload_photos(&db.pool, &FakeTagger, &IngestOptions::default(), folder.to_str().unwrap()).await.unwrap();
+    let (_, photo_id) = first.added[0];
+    let crop = add(&db.pool, "a-crop.jpg", &["beach"], 100, "hash-crop", None).await.unwrap();
+    PhotoLink::create(&db.pool, crop, LinkKind::CropOf, photo_id).await.unwrap().unwrap();
+    let options = IngestOptions { duplicates: DuplicatePolicy::Replace, ..IngestOptions::default() };
+
+    let replaced = upload_photos(&db.pool, &FakeTagger, &options, other.to_str().unwrap()).await.unwrap();
+    assert_eq!(replaced.added.iter().map(|(_, photo_id)| *photo_id).collect::<Vec<_>>(), vec![photo_id]);
+    let photo = Photo::find_by_id(&db.pool, photo_id).await.unwrap().unwrap();
+    let moved = other.join("moved.png").canonicalize().unwrap();
+    assert_eq!(photo.file_path, moved.to_str().unwrap());
+    assert_eq!(photo.original_file_name.as_deref(), Some("moved.png"));
+    assert_eq!(PhotoLink::group(&db.pool, photo_id).await.unwrap(), vec![photo_id, crop]);
+    let events = PhotoEvent::list_for_photo(&db.pool, photo_id).await.unwrap();
+    assert_eq!(events.iter().filter(|event| event.event == "uploaded").count(), 2);
+
+    // the same folder again changes nothing
+    let again = upload_photos(&db.pool, &FakeTagger, &options, other.to_str().unwrap()).await.unwrap();
+    assert_eq!((again.added.len(), again.skipped.len()), (0, 1));
+    assert_eq!(TagProvenance::list_for_photo(&db.pool, photo_id).await.unwrap().len(), 2);
+
+    std::fs::remove_dir_all(&folder).unwrap();
+    std::fs::remove_dir_all(&other).unwrap();
+    db.close().await;
+}